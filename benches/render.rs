@@ -0,0 +1,26 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use raytracer::raytracer::raytracer::RayTracer;
+use std::hint::black_box;
+
+/// Scenes under `benches/scenes`, each a few thousand pixels so that the whole suite stays quick.
+/// Flat shaded spheres, a textured triangle mesh, and reflective spheres traced through several bounces.
+const SCENES: [&str; 3] = ["spheres", "mesh", "reflections"];
+
+fn render(c: &mut Criterion) {
+    let mut group = c.benchmark_group("render");
+    group.sample_size(20);
+
+    for name in SCENES {
+        let path: String = format!("{}/benches/scenes/{}.txt", env!("CARGO_MANIFEST_DIR"), name);
+        // Only the rendering is measured, the scene is parsed once
+        let (mut raytracer, _) = RayTracer::from_file(&path);
+        group.bench_function(name, |b| b.iter(|| {
+            black_box(raytracer.render_to_buffer());
+        }));
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, render);
+criterion_main!(benches);
@@ -1,15 +1,54 @@
-mod raytracer;
-use raytracer::raytracer::RayTracer;
+use raytracer::raytracer::raytracer::RayTracer;
+use raytracer::raytracer::log;
+use std::io::Write;
+use std::sync::Mutex;
 fn main() {
     
     let args: Vec<String> = std::env::args().collect();
 
-    if args.len() != 2 {
-        eprintln!("Usage: {} <input_file>", args[0]);
+    let usage = || {
+        eprintln!("Usage: {} [--threads N] [--log FILE] <input_file>", args[0]);
         std::process::exit(1);
+    };
+
+    let mut threads: Option<usize> = None;
+    let mut input_file: Option<&String> = None;
+
+    let mut i = 1;
+    while i < args.len() {
+        if args[i] == "--threads" {
+            i += 1;
+            match args.get(i).and_then(|n| n.parse().ok()) {
+                Some(n) => threads = Some(n),
+                None => usage(),
+            }
+        } else if args[i] == "--log" {
+            i += 1;
+            match args.get(i).map(std::fs::File::create) {
+                Some(Ok(file)) => {
+                    // Messages come from the render threads too, so each line is written under the lock
+                    let file: Mutex<std::fs::File> = Mutex::new(file);
+                    log::set_sink(move |level, message| {
+                        let _ = writeln!(file.lock().unwrap(), "[{:?}] {}", level, message);
+                    });
+                },
+                Some(Err(error)) => {
+                    eprintln!("Failed to open the log file: {}", error);
+                    std::process::exit(1);
+                },
+                None => usage(),
+            }
+        } else if input_file.is_none() {
+            input_file = Some(&args[i]);
+        } else {
+            usage();
+        }
+        i += 1;
     }
 
-    let input_file = &args[1];
-    RayTracer::render_from_file(input_file);
+    match input_file {
+        Some(input_file) => RayTracer::render_from_file(input_file, threads),
+        None => usage(),
+    }
 
 }
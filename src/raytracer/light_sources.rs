@@ -1,53 +1,112 @@
 use crate::raytracer::{MatVec, Color, InputState};
 use crate::raytracer::scene::LightSource;
 use crate::raytracer::ray::Ray;
+use rand::Rng;
+use rand::rngs::StdRng;
 
 /// Sun is a light source that is infinitely far away and shines in a single direction.
+/// With a non zero angular diameter, shadow rays are jittered within the cone the sun covers in the sky,
+/// which produces soft shadows whose penumbra grows with the distance from the occluder.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Sun {
     pub direction: MatVec<3>,
     pub color: Color,
+    pub angle: f32, // Angular diameter in radians
+    pub intensity: f32,
 }
 
 impl Sun {
-    pub fn new(direction: MatVec<3>, context: &InputState) -> Sun {
+    /// `angle` is the angular diameter of the sun in degrees.
+    pub fn new(direction: MatVec<3>, angle: f32, intensity: f32, context: &InputState) -> Sun {
         Sun {
             direction: direction.normalize(),
             color: context.color.clone(),
+            angle: angle.to_radians(),
+            intensity,
         }
     }
 }
 
 impl LightSource for Sun {
+
+    fn clone_box(&self) -> Box<dyn LightSource> {
+        Box::new(self.clone())
+    }
+    #[cfg(feature = "serde")]
+    fn serialized(&self) -> SerializedLight {
+        SerializedLight::SUN(self.clone())
+    }
     fn light_color(&self) -> Color {
         self.color.clone()
     }
     fn intensity(&self, _ray: &Ray) -> f32 {
-        1.0f32
+        self.intensity
     }
 
     fn compute_direction(&self, _origin: &MatVec<3>) -> MatVec<3> {
         self.direction.clone()
     }
 
+    /// Samples a direction uniformly within the cone of the sun's disk.
+    fn sample_direction(&self, _origin: &MatVec<3>, rng: &mut StdRng) -> MatVec<3> {
+        if self.angle <= 0.0 {
+            return self.direction;
+        }
+
+        let cos_max: f32 = (0.5 * self.angle).cos();
+        let cos_theta: f32 = 1.0 - rng.gen::<f32>() * (1.0 - cos_max);
+        let sin_theta: f32 = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi: f32 = 2.0 * std::f32::consts::PI * rng.gen::<f32>();
+
+        // Orthonormal basis around the direction of the sun
+        let w: MatVec<3> = self.direction;
+        let helper: MatVec<3> = if w[0].abs() > 0.9 {
+            MatVec::from_array([0.0, 1.0, 0.0])
+        } else {
+            MatVec::from_array([1.0, 0.0, 0.0])
+        };
+        let u: MatVec<3> = helper.cross(&w).normalize();
+        let v: MatVec<3> = w.cross(&u);
+
+        (sin_theta * phi.cos() * u + sin_theta * phi.sin() * v + cos_theta * w).normalize()
+    }
+
+    fn is_area_light(&self) -> bool {
+        self.angle > 0.0
+    }
+
 }
 
 /// Bulb is a light source that is located at a specific position and shines in all directions.
-/// The intensity of the light decreases with the square of the distance.
+/// The intensity of the light decreases with the square of the distance, from `intensity` at a distance of 1.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Bulb {
     pub position: MatVec<3>,
     pub color: Color,
+    pub intensity: f32,
 }
 
 impl Bulb {
-    pub fn new(position: MatVec<3>, context: &InputState) -> Bulb {
+    pub fn new(position: MatVec<3>, intensity: f32, context: &InputState) -> Bulb {
         Bulb {
             position,
             color: context.color.clone(),
+            intensity,
         }
     }
 }
 
 impl LightSource for Bulb {
+
+    fn clone_box(&self) -> Box<dyn LightSource> {
+        Box::new(self.clone())
+    }
+    #[cfg(feature = "serde")]
+    fn serialized(&self) -> SerializedLight {
+        SerializedLight::BULB(self.clone())
+    }
     fn light_color(&self) -> Color {
         self.color.clone()
     }
@@ -55,7 +114,7 @@ impl LightSource for Bulb {
     fn intensity(&self, ray: &Ray) -> f32 {
         let distance: f32 = (self.position.clone() - ray.origin.clone()).magnitude();
         let epsilon: f32 = 1e-6;
-        let intensity = 1.0f32 / f32::powi(distance + epsilon, 2);
+        let intensity = self.intensity / f32::powi(distance + epsilon, 2);
         intensity
 
     }
@@ -63,4 +122,110 @@ impl LightSource for Bulb {
     fn compute_direction(&self, origin: &MatVec<3>) -> MatVec<3> {
         (self.position.clone() - origin.clone()).normalize()
     }
-}
\ No newline at end of file
+
+    fn distance(&self, ray: &Ray) -> f32 {
+        (self.position - ray.origin).magnitude()
+    }
+}
+
+/// RectLight is an area light spanning the parallelogram `corner + s * edge_u + t * edge_v`
+/// for `s, t` in `[0, 1]`. Shadow rays are cast towards random points on the rectangle,
+/// which produces soft shadows. Like `Bulb`, the intensity falls off with the square of the distance.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RectLight {
+    pub corner: MatVec<3>,
+    pub edge_u: MatVec<3>,
+    pub edge_v: MatVec<3>,
+    pub color: Color,
+}
+
+impl RectLight {
+    pub fn new(corner: MatVec<3>, edge_u: MatVec<3>, edge_v: MatVec<3>, context: &InputState) -> RectLight {
+        RectLight {
+            corner,
+            edge_u,
+            edge_v,
+            color: context.color,
+        }
+    }
+
+    fn center(&self) -> MatVec<3> {
+        self.corner + 0.5 * self.edge_u + 0.5 * self.edge_v
+    }
+
+    /// Distance along the ray to the plane of the rectangle.
+    fn _distance_to_plane(&self, ray: &Ray) -> f32 {
+        let normal: MatVec<3> = self.edge_u.cross(&self.edge_v).normalize();
+        let denom: f32 = normal.dot(ray.direction);
+        if denom.abs() < 1e-6 {
+            (self.center() - ray.origin).magnitude()
+        } else {
+            (normal.dot(self.corner - ray.origin) / denom).abs() * ray.direction.magnitude()
+        }
+    }
+}
+
+impl LightSource for RectLight {
+
+    fn clone_box(&self) -> Box<dyn LightSource> {
+        Box::new(self.clone())
+    }
+    #[cfg(feature = "serde")]
+    fn serialized(&self) -> SerializedLight {
+        SerializedLight::RECT(self.clone())
+    }
+    fn light_color(&self) -> Color {
+        self.color
+    }
+
+    /// Computes the falloff using the distance along the ray to the plane of the rectangle,
+    /// so that each sampled shadow ray uses the distance to its own sample point.
+    fn intensity(&self, ray: &Ray) -> f32 {
+        let distance: f32 = self._distance_to_plane(ray);
+        let epsilon: f32 = 1e-6;
+        1.0f32 / f32::powi(distance + epsilon, 2)
+    }
+
+    fn compute_direction(&self, origin: &MatVec<3>) -> MatVec<3> {
+        (self.center() - *origin).normalize()
+    }
+
+    fn sample_direction(&self, origin: &MatVec<3>, rng: &mut StdRng) -> MatVec<3> {
+        let s: f32 = rng.gen();
+        let t: f32 = rng.gen();
+        let point: MatVec<3> = self.corner + s * self.edge_u + t * self.edge_v;
+        (point - *origin).normalize()
+    }
+
+    fn is_area_light(&self) -> bool {
+        true
+    }
+
+    fn distance(&self, ray: &Ray) -> f32 {
+        self._distance_to_plane(ray)
+    }
+}
+
+/// Every kind of light, tagged with its name, which is how serde writes and reads a `Box<dyn LightSource>`.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SerializedLight {
+    SUN(Sun),
+    BULB(Bulb),
+    RECT(RectLight),
+}
+
+#[cfg(feature = "serde")]
+impl SerializedLight {
+
+    pub fn into_boxed(self) -> Box<dyn LightSource> {
+        match self {
+            SerializedLight::SUN(inner) => Box::new(inner),
+            SerializedLight::BULB(inner) => Box::new(inner),
+            SerializedLight::RECT(inner) => Box::new(inner),
+        }
+    }
+
+}
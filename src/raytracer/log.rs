@@ -0,0 +1,88 @@
+use std::fmt::Arguments;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How important a message is, from always shown errors to the most detailed tracing.
+/// A message is shown when its level is at most the current level, see `set_level`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+
+    ERROR,
+    WARN,
+    INFO,
+    TRACE,
+
+}
+
+impl LogLevel {
+
+    pub fn from_name(name: &str) -> Option<LogLevel> {
+        match name {
+            "error" => Some(LogLevel::ERROR),
+            "warn" => Some(LogLevel::WARN),
+            "info" => Some(LogLevel::INFO),
+            "trace" => Some(LogLevel::TRACE),
+            _ => None,
+        }
+    }
+
+    fn from_u8(value: u8) -> LogLevel {
+        match value {
+            0 => LogLevel::ERROR,
+            1 => LogLevel::WARN,
+            2 => LogLevel::INFO,
+            _ => LogLevel::TRACE,
+        }
+    }
+
+}
+
+type Sink = Box<dyn Fn(LogLevel, &str) + Send + Sync>;
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::WARN as u8);
+static SINK: RwLock<Option<Sink>> = RwLock::new(None);
+
+/// Sets the most detailed level that is shown, messages above it are dropped without being formatted.
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> LogLevel {
+    LogLevel::from_u8(LEVEL.load(Ordering::Relaxed))
+}
+
+/// Routes every shown message to `sink` instead of stderr.
+pub fn set_sink(sink: impl Fn(LogLevel, &str) + Send + Sync + 'static) {
+    *SINK.write().unwrap() = Some(Box::new(sink));
+}
+
+/// Formats and hands the message to the sink if its level is shown, used through the `log_*` macros.
+/// Nothing is ever written to stdout, which may hold the image.
+pub fn log(level: LogLevel, message: Arguments) {
+    if level > self::level() {
+        return;
+    }
+    let message: String = message.to_string();
+    match *SINK.read().unwrap() {
+        Some(ref sink) => sink(level, &message),
+        None => eprintln!("{}", message),
+    }
+}
+
+macro_rules! log_error {
+    ($($arg:tt)*) => { $crate::raytracer::log::log($crate::raytracer::log::LogLevel::ERROR, format_args!($($arg)*)) };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)*) => { $crate::raytracer::log::log($crate::raytracer::log::LogLevel::WARN, format_args!($($arg)*)) };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)*) => { $crate::raytracer::log::log($crate::raytracer::log::LogLevel::INFO, format_args!($($arg)*)) };
+}
+
+macro_rules! log_trace {
+    ($($arg:tt)*) => { $crate::raytracer::log::log($crate::raytracer::log::LogLevel::TRACE, format_args!($($arg)*)) };
+}
+
+pub(crate) use {log_error, log_warn, log_info, log_trace};
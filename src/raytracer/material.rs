@@ -1,14 +1,228 @@
-pub struct Material {
-    pub shininess: Vec<f32>,
+use crate::raytracer::{Color, InputState, LightResidual, MatVec, RGBA};
+use crate::raytracer::utils;
+
+/// Trait that defines how the surface of an object is shaded.
+/// Notable methods are `shininess` and `transparency`, which decide whether reflection and
+/// refraction rays are cast at all, and `shade`, which combines the color of the surface,
+/// the colors gathered by those secondary rays and the light reaching the surface into the final color.
+/// Materials are owned by objects, which are shared between the render threads, so they must be `Send + Sync`.
+pub trait Material: Send + Sync {
+    /// Copies the material behind a trait object, see `Clone for Box<dyn Material>`.
+    fn clone_box(&self) -> Box<dyn Material>;
+    /// Copies the material into the enum of materials that serde can write, see `Serialize for Box<dyn Material>`.
+    #[cfg(feature = "serde")]
+    fn serialized(&self) -> SerializedMaterial;
+    fn shininess(&self) -> Option<Vec<f32>> { None }
+    fn transparency(&self) -> Option<Vec<f32>> { None }
+    /// `view` is the unit direction from the surface towards the viewer, and `fresnel` is the
+    /// reflectance of the surface at that angle, see `utils::schlick`.
+    fn shade(&self, base_color: &Color, reflection_color: &Color, refraction_color: &Color, ilumination_sources: &[LightResidual], view: &MatVec<3>, fresnel: f32) -> RGBA;
+}
+
+impl Clone for Box<dyn Material> {
+    fn clone(&self) -> Box<dyn Material> {
+        self.clone_box()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Box<dyn Material> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serde::Serialize::serialize(&self.serialized(), serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Box<dyn Material> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Box<dyn Material>, D::Error> {
+        <SerializedMaterial as serde::Deserialize>::deserialize(deserializer).map(SerializedMaterial::into_boxed)
+    }
+}
+
+/// The default material, blends the reflected and refracted colors with the surface color
+/// using the per channel shininess and transparency, and applies lambertian shading to the result.
+/// The transparent part is split between reflection and refraction by the Fresnel reflectance,
+/// so transparent surfaces become mirror-like at grazing angles.
+/// When a specular color is set, a Blinn-Phong highlight is added on top of the diffuse shading.
+#[derive(Default, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PhongMaterial {
+    pub shininess: Option<Vec<f32>>,
+    pub transparency: Option<Vec<f32>>,
+    pub specular_exponent: f32,
+    pub specular: Option<Color>,
+}
+
+impl PhongMaterial {
+
+    pub fn new(context: &InputState) -> PhongMaterial {
+        PhongMaterial {
+            shininess: PhongMaterial::_per_channel(&context.shininess),
+            transparency: PhongMaterial::_per_channel(&context.transparency),
+            specular_exponent: context.specular_exponent,
+            specular: context.specular,
+        }
+    }
+
+    /// Expands a coefficient given either for all channels at once, or per channel, to all 3 channels.
+    fn _per_channel(values: &[f32]) -> Option<Vec<f32>> {
+        match values.len() {
+            1 => Some(vec![values[0]; 3]),
+            3 => Some(values.to_vec()),
+            _ => None,
+        }
+    }
+
 }
 
+impl Material for PhongMaterial {
+
+    fn clone_box(&self) -> Box<dyn Material> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "serde")]
+    fn serialized(&self) -> SerializedMaterial {
+        SerializedMaterial::PHONG(self.clone())
+    }
+
+    fn shininess(&self) -> Option<Vec<f32>> {
+        self.shininess.clone()
+    }
+
+    fn transparency(&self) -> Option<Vec<f32>> {
+        self.transparency.clone()
+    }
+
+    fn shade(&self, base_color: &Color, reflection_color: &Color, refraction_color: &Color, ilumination_sources: &[LightResidual], view: &MatVec<3>, fresnel: f32) -> RGBA {
 
-impl Material {
+        let shininess = self.shininess.clone().unwrap_or(vec![0.0, 0.0, 0.0]);
+        let transparency = self.transparency.clone().unwrap_or(vec![0.0, 0.0, 0.0]);
 
-    pub fn new(shininess: Vec<f32>) -> Material {
-        Material {
-            shininess,
+        let mut color: Color = *base_color;
+
+        for i in 0..3 {
+            let color_val: f32 = shininess[i] * reflection_color[i]
+                                    + (1_f32 - shininess[i]) * transparency[i] * fresnel * reflection_color[i]
+                                    + (1_f32 - shininess[i]) * transparency[i] * (1_f32 - fresnel) * refraction_color[i]
+                                    + (1_f32 - shininess[i]) * (1_f32 - transparency[i]) * base_color[i];
+            color.set(i, color_val);
+        }
+
+        match self.specular {
+            // Apply Lambertian shading
+            None => utils::lambert(&color, ilumination_sources),
+            Some(ref specular) => utils::phong(&color, ilumination_sources, view, self.specular_exponent, specular),
         }
+
     }
 
-}
\ No newline at end of file
+}
+
+/// Refractive index of the clear layer of a `ClearcoatMaterial`, typical of lacquers and varnishes.
+const CLEARCOAT_IOR: f32 = 1.5;
+
+/// A varnished surface, ie a `PhongMaterial` base under a thin clear layer.
+/// The layer adds a second, sharp specular highlight on top of the base shading, weighted by
+/// its Fresnel reflectance, so it is faint when seen head on and strong at grazing angles.
+/// The coating only adds light, the diffuse color of the base is left untouched.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ClearcoatMaterial {
+    pub base: PhongMaterial,
+    pub strength: f32,
+    pub roughness: f32,
+}
+
+impl ClearcoatMaterial {
+
+    pub fn new(context: &InputState, strength: f32, roughness: f32) -> ClearcoatMaterial {
+        ClearcoatMaterial {
+            base: PhongMaterial::new(context),
+            strength,
+            roughness,
+        }
+    }
+
+    /// Blinn-Phong exponent that gives a highlight about as wide as a microfacet lobe of the given roughness.
+    fn _exponent(&self) -> f32 {
+        let roughness: f32 = self.roughness.clamp(0.01, 1.0);
+        2.0 / (roughness * roughness) - 2.0
+    }
+
+}
+
+impl Material for ClearcoatMaterial {
+
+    fn clone_box(&self) -> Box<dyn Material> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "serde")]
+    fn serialized(&self) -> SerializedMaterial {
+        SerializedMaterial::CLEARCOAT(self.clone())
+    }
+
+    fn shininess(&self) -> Option<Vec<f32>> {
+        self.base.shininess()
+    }
+
+    fn transparency(&self) -> Option<Vec<f32>> {
+        self.base.transparency()
+    }
+
+    fn shade(&self, base_color: &Color, reflection_color: &Color, refraction_color: &Color, ilumination_sources: &[LightResidual], view: &MatVec<3>, fresnel: f32) -> RGBA {
+
+        let base: RGBA = self.base.shade(base_color, reflection_color, refraction_color, ilumination_sources, view, fresnel);
+        let mut coat: Color = MatVec::zero();
+
+        for source in ilumination_sources.iter().filter(|source| source.source_id.is_some()) {
+
+            if source.normal.dot(source.direction) <= 0.0 {
+                continue;
+            }
+
+            // Normalized lobe, so a smoother coat concentrates its reflection into a brighter highlight instead of losing it
+            let exponent: f32 = self._exponent();
+            let half_vector: MatVec<3> = (source.direction + *view).normalize();
+            let specular: f32 = (exponent + 8.0) / (8.0 * std::f32::consts::PI) * f32::max(source.normal.dot(half_vector), 0.0).powf(exponent);
+            let reflectance: f32 = utils::schlick(half_vector.dot(*view), CLEARCOAT_IOR);
+
+            coat = coat + (self.strength * reflectance * source.intensity * specular) * source.color;
+
+        }
+
+        base + utils::color_to_rgba(coat, 0.0)
+
+    }
+
+}
+
+/// Builds the material described by the current input state.
+pub fn from_context(context: &InputState) -> Box<dyn Material> {
+    match context.clearcoat {
+        None => Box::new(PhongMaterial::new(context)),
+        Some((strength, roughness)) => Box::new(ClearcoatMaterial::new(context, strength, roughness)),
+    }
+}
+
+/// Every kind of material, tagged with its name, which is how serde writes and reads a `Box<dyn Material>`.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SerializedMaterial {
+    PHONG(PhongMaterial),
+    CLEARCOAT(ClearcoatMaterial),
+}
+
+#[cfg(feature = "serde")]
+impl SerializedMaterial {
+
+    pub fn into_boxed(self) -> Box<dyn Material> {
+        match self {
+            SerializedMaterial::PHONG(inner) => Box::new(inner),
+            SerializedMaterial::CLEARCOAT(inner) => Box::new(inner),
+        }
+    }
+
+}
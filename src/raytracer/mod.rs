@@ -1,5 +1,11 @@
 use std::ops::{Add, Sub, Mul, Index};
-use std::fmt::Debug;
+use std::fmt::{Debug, Display};
+use std::collections::HashMap;
+use std::sync::Arc;
+use rand::Rng;
+use transform::Transform;
+use texture::{Texture2d, TextureError, UvTransform, WrapMode};
+use shapes::{Mesh, Triangle};
 
 // For now MatVec represents a 'Mathematical Vector'
 // In needed, I will change this to be a 'Matrix Vector',
@@ -15,17 +21,48 @@ pub struct MatVec<const N: usize> {
 // Method Implementations for MatVec
 impl<const N: usize> MatVec<N> {
 
+    /// Constructs a `MatVec` from a vector whose length is only known at runtime.
+    /// Panics if the length does not match `N`, prefer `from_array` when it is known statically.
     pub fn new(data: Vec<f32>) -> MatVec<N> {
         assert_eq!(data.len(), N, "MatVec::new, Size missmatch");
-        let mut new_data = [0.0f32; N];
-        for i in 0..N {
-            new_data[i] = data[i];
-        }
+        MatVec::from_slice(&data)
+    }
+
+    /// Constructs a `MatVec` from a fixed size array.
+    /// The length is known at compile time, so no allocation or size check is needed.
+    pub fn from_array(data: [f32; N]) -> MatVec<N> {
         MatVec::<N> {
-            data: new_data,
+            data,
         }
     }
 
+    /// Constructs a `MatVec` with every component set to `value`.
+    pub fn splat(value: f32) -> MatVec<N> {
+        MatVec::from_array([value; N])
+    }
+
+    /// The vector with every component 0, black for colors.
+    pub fn zero() -> MatVec<N> {
+        MatVec::splat(0.0)
+    }
+
+    /// The vector with every component 1, white for colors.
+    pub fn one() -> MatVec<N> {
+        MatVec::splat(1.0)
+    }
+
+    /// Consumes the vector and returns its components.
+    pub fn into_array(self) -> [f32; N] {
+        self.data
+    }
+
+    /// Constructs a `MatVec` by copying a slice, without allocating.
+    /// Panics if the slice length does not match `N`, see `TryFrom<&[f32]>`
+    /// for the fallible version.
+    pub fn from_slice(data: &[f32]) -> MatVec<N> {
+        MatVec::try_from(data).expect("MatVec::from_slice, Size missmatch")
+    }
+
     pub fn get(&self, i: usize) -> &f32 {
         assert!(i < N, "MatVec::get, Index out of bounds");
         &self.data[i]
@@ -41,50 +78,52 @@ impl<const N: usize> MatVec<N> {
     }
 
     pub fn dot(&self, other: MatVec::<N>) -> f32 {
-        let mut sum = 0.0;
-        for i in 0..N {
-            sum += self.get(i) * other.get(i);
-        }
-        sum
+        self.iter().zip(other.iter()).map(|(a, b)| a * b).sum()
+    }
+
+    /// Iterates over the components of the vector.
+    pub fn iter(&self) -> std::slice::Iter<'_, f32> {
+        self.data.iter()
+    }
+
+    /// Iterates mutably over the components of the vector.
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, f32> {
+        self.data.iter_mut()
     }
 
+    // The element-wise operations work on the arrays directly, without allocating or bounds checks,
+    // so the compiler can vectorize them for the common 3 and 4 component vectors
     fn _add(self, other: MatVec<N>) -> MatVec<N> {
-        // assert_eq!(self.len(), other.len(), "Vectors must be of the same length");
-        let mut new_data = Vec::new();
-        for i in 0..N {
-            new_data.push(self.get(i) + other.get(i));
-        }
-        MatVec::new(new_data)
+        MatVec::from_array(std::array::from_fn(|i| self.data[i] + other.data[i]))
     }
 
     fn _sub(&self, other: MatVec<N>) -> MatVec<N> {
-        // assert_eq!(self.len(), other.len(), "Vectors must be of the same length");
-        let mut new_data = Vec::new();
-        for i in 0..N {
-            new_data.push(self.get(i) - other.get(i));
-        }
-        MatVec::new(new_data)
+        MatVec::from_array(std::array::from_fn(|i| self.data[i] - other.data[i]))
     }
 
     pub fn scale(&self, scalar: f32) -> MatVec<N> {
-        let mut new_data = Vec::new();
-        for i in 0..N {
-            new_data.push(self.get(i) * scalar);
-        }
-        MatVec::new(new_data)
+        MatVec::from_array(std::array::from_fn(|i| self.data[i] * scalar))
     }
 
     pub fn normalize(&self) -> MatVec<N> {
-        let mut new_data = Vec::new();
         let mut sum = 0.0;
         for i in 0..N {
-            sum += self.get(i) * self.get(i);
+            sum += self.data[i] * self.data[i];
         }
         let mag = sum.sqrt();
-        for i in 0..N {
-            new_data.push(self.get(i) / mag);
-        }
-        MatVec::new(new_data)
+        MatVec::from_array(std::array::from_fn(|i| self.data[i] / mag))
+    }
+
+    /// Component-wise product, as used to modulate one color by another.
+    pub fn hadamard(&self, other: &MatVec<N>) -> MatVec<N> {
+        MatVec::from_array(std::array::from_fn(|i| self.data[i] * other.data[i]))
+    }
+
+    /// Normalizes the vector like `normalize`, or returns `None` when it is too short to have a direction,
+    /// ie when dividing by its magnitude would not give finite components. Also `None` for non-finite vectors.
+    pub fn try_normalize(&self) -> Option<MatVec<N>> {
+        let normalized: MatVec<N> = self.normalize();
+        if normalized.iter().all(|c| c.is_finite()) { Some(normalized) } else { None }
     }
 
     pub fn magnitude(&self) -> f32 {
@@ -95,6 +134,12 @@ impl<const N: usize> MatVec<N> {
         sum.sqrt()
     }
 
+    /// Angle between two vectors in radians, in `[0, pi]`.
+    /// The cosine is clamped before `acos`, so rounding can not turn nearly parallel vectors into NaN.
+    pub fn angle_between(&self, other: &MatVec<N>) -> f32 {
+        self.normalize().dot(other.normalize()).clamp(-1.0, 1.0).acos()
+    }
+
     pub fn cross(&self, other: &MatVec<N>) -> MatVec<N> {
         if N == 3 {
             return self._cross3x3(other);
@@ -111,13 +156,28 @@ impl<const N: usize> MatVec<N> {
         MatVec::new(vec![x, y, z])
     }
 
+    /// Linearly interpolates between `self` (at `t = 0`) and `other` (at `t = 1`).
+    pub fn lerp(&self, other: &MatVec<N>, t: f32) -> MatVec<N> {
+        MatVec::from_array(std::array::from_fn(|i| self.data[i] + t * (other.data[i] - self.data[i])))
+    }
+
+    /// Component-wise minimum of two vectors.
+    pub fn component_min(&self, other: &MatVec<N>) -> MatVec<N> {
+        MatVec::from_array(std::array::from_fn(|i| self.data[i].min(other.data[i])))
+    }
+
+    /// Component-wise maximum of two vectors.
+    pub fn component_max(&self, other: &MatVec<N>) -> MatVec<N> {
+        MatVec::from_array(std::array::from_fn(|i| self.data[i].max(other.data[i])))
+    }
+
+    /// Clamps every component to the range `[lo, hi]`.
+    pub fn clamp(&self, lo: f32, hi: f32) -> MatVec<N> {
+        MatVec::from_array(std::array::from_fn(|i| self.data[i].clamp(lo, hi)))
+    }
+
     pub fn clip_to_u8(&self) -> Vec<u8> {
-        let mut clipped: Vec<u8> = Vec::new();
-        for i in 0..N {
-            let val = self.get(i).max(0.0).min(255.0);
-            clipped.push(val as u8);
-        }
-        clipped
+        self.clamp(0.0, 255.0).iter().map(|&value| value as u8).collect()
     }
 
     // TODO: Find a new home for this funcion since it is only valid for 4d vectors
@@ -140,14 +200,16 @@ impl<const N: usize> MatVec<N> {
         }
     }
 
-    pub fn perturb(&self, delta: f32, std_dev: f32) -> MatVec<N> {
-        let mut new_data: MatVec<N> = MatVec::new(vec![0.0; N]);
-        for i in 0..N {
-            let perturbation = utils::gaussian_sample(std_dev);
-            // println!{"Perturbation for element {}: {}", i, perturbation};
-            new_data.set(i, perturbation);
+    /// Randomly perturbs the vector, by adding `delta` times gaussian noise with standard deviation
+    /// `std_dev` to every component. Used to roughen normals, where `std_dev` is the roughness.
+    /// The result is not renormalized, and with a `std_dev` of 0 the vector is returned unchanged.
+    /// All randomness is drawn from `rng`, so the result is reproducible for a seeded generator.
+    pub fn perturb<R: Rng + ?Sized>(&self, delta: f32, std_dev: f32, rng: &mut R) -> MatVec<N> {
+        if std_dev == 0.0 {
+            return *self;
         }
-        *self + (delta * new_data)
+        let noise: MatVec<N> = MatVec::from_array(std::array::from_fn(|_| utils::gaussian_sample(std_dev, rng)));
+        *self + (delta * noise)
     }
 
     pub fn eq(&self, other: MatVec<N>) -> bool {
@@ -159,16 +221,83 @@ impl<const N: usize> MatVec<N> {
         true
     }
 
+    /// Whether every component is within `eps` of the corresponding component of `other`.
+    pub fn approx_eq(&self, other: &MatVec<N>, eps: f32) -> bool {
+        self.data.iter().zip(other.data.iter()).all(|(a, b)| (a - b).abs() <= eps)
+    }
+
+    /// Whether every component is within `eps` of zero.
+    pub fn approx_zero(&self, eps: f32) -> bool {
+        self.data.iter().all(|a| a.abs() <= eps)
+    }
+
+}
+
+// Methods that are only meaningful for 2D vectors
+impl MatVec<2> {
+
+    /// Rotates the vector counterclockwise by `angle` radians about the origin.
+    pub fn rotate(&self, angle: f32) -> MatVec<2> {
+        let (s, c) = angle.sin_cos();
+        MatVec::from_array([c * self.data[0] - s * self.data[1],
+                            s * self.data[0] + c * self.data[1]])
+    }
+
+}
+
+// Methods that are only meaningful for 3D vectors
+impl MatVec<3> {
+
+    /// Reflects the vector about the plane with the given unit `normal`.
+    pub fn reflect(&self, normal: &MatVec<3>) -> MatVec<3> {
+        *self - 2.0 * self.dot(*normal) * *normal
+    }
+
+    /// Component of the vector along `axis`, which does not need to be unit length.
+    pub fn project_onto(&self, axis: &MatVec<3>) -> MatVec<3> {
+        (self.dot(*axis) / axis.dot(*axis)) * *axis
+    }
+
+    /// Component of the vector perpendicular to `axis`, so that `project_onto` and `reject_from` sum to the vector.
+    pub fn reject_from(&self, axis: &MatVec<3>) -> MatVec<3> {
+        *self - self.project_onto(axis)
+    }
+
+    /// Refracts the unit vector through a surface with unit `normal` facing against it,
+    /// where `eta` is the ratio of the indices of refraction, incident over transmitted.
+    /// Returns `None` on total internal reflection.
+    pub fn refract(&self, normal: &MatVec<3>, eta: f32) -> Option<MatVec<3>> {
+        // Snell's law scales the part along the surface, and the part along the normal keeps the result unit length
+        let tangential: MatVec<3> = eta * self.reject_from(normal);
+        let sin_t2: f32 = tangential.dot(tangential);
+        if sin_t2 > 1.0 {
+            return None;
+        }
+        Some(tangential - (1.0 - sin_t2).sqrt() * *normal)
+    }
+
 }
 
 // Implementations for standard traits and operators on MatVec types
 
 impl<const N: usize> Debug for MatVec<N> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for (i, value) in self.data.iter().enumerate() {
-            writeln!(f, "data[{}]: {}", i, value)?;
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// Formats the components on one line as `(x, y, z)`. Precision and width apply to every component,
+/// so `{:.3}` prints `(1.000, 0.500, 0.250)`.
+impl<const N: usize> Display for MatVec<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "(")?;
+        for (i, value) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            Display::fmt(value, f)?;
         }
-        Ok(())
+        write!(f, ")")
     }
 }
 
@@ -186,6 +315,53 @@ impl<const N: usize> Clone for MatVec<N> {
 
 impl<const N: usize> Copy for MatVec<N> {}
 
+impl<const N: usize> From<[f32; N]> for MatVec<N> {
+    fn from(data: [f32; N]) -> MatVec<N> {
+        MatVec::from_array(data)
+    }
+}
+
+impl<const N: usize> From<MatVec<N>> for [f32; N] {
+    fn from(vec: MatVec<N>) -> [f32; N] {
+        vec.into_array()
+    }
+}
+
+impl<const N: usize> AsRef<[f32]> for MatVec<N> {
+    fn as_ref(&self) -> &[f32] {
+        &self.data
+    }
+}
+
+/// Fallible conversion from a slice, the length of the slice must be exactly `N`.
+impl<const N: usize> TryFrom<&[f32]> for MatVec<N> {
+    type Error = String;
+
+    fn try_from(data: &[f32]) -> Result<MatVec<N>, String> {
+        let array: [f32; N] = data.try_into()
+            .map_err(|_| format!("MatVec::try_from, expected {} elements, got {}", N, data.len()))?;
+        Ok(MatVec::from_array(array))
+    }
+}
+
+impl<'a, const N: usize> IntoIterator for &'a MatVec<N> {
+    type Item = &'a f32;
+    type IntoIter = std::slice::Iter<'a, f32>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, const N: usize> IntoIterator for &'a mut MatVec<N> {
+    type Item = &'a mut f32;
+    type IntoIter = std::slice::IterMut<'a, f32>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
 impl<const N: usize> Add for MatVec<N> {
     type Output = MatVec<N>;
 
@@ -242,30 +418,188 @@ impl<const N: usize> Index<usize> for MatVec<N> {
     }
 }
 
+/// Row major `R` by `C` matrix, the matrix counterpart of `MatVec`.
+/// Used for linear maps between vectors of fixed sizes, such as color space conversions,
+/// without hardcoding the dimensions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Matrix<const R: usize, const C: usize> {
+    data: [[f32; C]; R],
+}
+
+impl<const R: usize, const C: usize> Matrix<R, C> {
+
+    /// Constructs a matrix from its rows.
+    pub fn new(rows: [[f32; C]; R]) -> Matrix<R, C> {
+        Matrix {
+            data: rows,
+        }
+    }
+
+    pub fn row(&self, r: usize) -> MatVec<C> {
+        MatVec::from_array(self.data[r])
+    }
+
+    pub fn column(&self, c: usize) -> MatVec<R> {
+        MatVec::from_array(std::array::from_fn(|r| self.data[r][c]))
+    }
+
+    pub fn transpose(&self) -> Matrix<C, R> {
+        Matrix::new(std::array::from_fn(|c| std::array::from_fn(|r| self.data[r][c])))
+    }
+
+    /// Multiplies the matrix with a column vector.
+    pub fn mul(&self, v: &MatVec<C>) -> MatVec<R> {
+        MatVec::from_array(std::array::from_fn(|r| self.row(r).dot(*v)))
+    }
+
+    /// Multiplies the matrix with another one, `self * other`.
+    pub fn mul_matrix<const K: usize>(&self, other: &Matrix<C, K>) -> Matrix<R, K> {
+        Matrix::new(std::array::from_fn(|r| std::array::from_fn(|k| self.row(r).dot(other.column(k)))))
+    }
+
+}
+
+impl<const N: usize> Matrix<N, N> {
+
+    pub fn identity() -> Matrix<N, N> {
+        Matrix::new(std::array::from_fn(|r| std::array::from_fn(|c| if r == c { 1.0 } else { 0.0 })))
+    }
+
+}
+
+impl<const R: usize, const C: usize> From<[[f32; C]; R]> for Matrix<R, C> {
+    fn from(rows: [[f32; C]; R]) -> Self {
+        Matrix::new(rows)
+    }
+}
+
+impl<const R: usize, const C: usize> Index<(usize, usize)> for Matrix<R, C> {
+    type Output = f32;
+
+    fn index(&self, (r, c): (usize, usize)) -> &f32 {
+        &self.data[r][c]
+    }
+}
+
+impl<const R: usize, const C: usize> Mul<MatVec<C>> for Matrix<R, C> {
+    type Output = MatVec<R>;
+
+    fn mul(self, v: MatVec<C>) -> MatVec<R> {
+        Matrix::mul(&self, &v)
+    }
+}
+
+// Serde only implements arrays of up to 32 elements, and none whose length is a const generic,
+// so vectors and matrices are written as tuples by hand. A vector is a tuple of its elements,
+// and a matrix a tuple of its rows, eg `[1.0, 2.0, 3.0]` and `[[1.0, 0.0], [0.0, 1.0]]` in JSON.
+
+#[cfg(feature = "serde")]
+fn serialize_array<T: serde::Serialize, S: serde::Serializer>(elements: &[T], serializer: S) -> Result<S::Ok, S::Error> {
+    use serde::ser::SerializeTuple;
+    let mut tuple = serializer.serialize_tuple(elements.len())?;
+    for element in elements {
+        tuple.serialize_element(element)?;
+    }
+    tuple.end()
+}
+
+/// Reads a tuple of exactly `N` elements into an array.
+#[cfg(feature = "serde")]
+struct ArrayVisitor<T, const N: usize>(std::marker::PhantomData<T>);
+
+#[cfg(feature = "serde")]
+impl<'de, T: serde::Deserialize<'de>, const N: usize> serde::de::Visitor<'de> for ArrayVisitor<T, N> {
+    type Value = [T; N];
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(formatter, "a sequence of {} elements", N)
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<[T; N], A::Error> {
+        let mut elements: Vec<T> = Vec::with_capacity(N);
+        for i in 0..N {
+            match seq.next_element()? {
+                Some(element) => elements.push(element),
+                None => return Err(serde::de::Error::invalid_length(i, &self)),
+            }
+        }
+        Ok(elements.try_into().unwrap_or_else(|_| unreachable!("exactly N elements were read")))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<const N: usize> serde::Serialize for MatVec<N> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_array(&self.data, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, const N: usize> serde::Deserialize<'de> for MatVec<N> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<MatVec<N>, D::Error> {
+        deserializer.deserialize_tuple(N, ArrayVisitor::<f32, N>(std::marker::PhantomData)).map(MatVec::from_array)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<const R: usize, const C: usize> serde::Serialize for Matrix<R, C> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let rows: [MatVec<C>; R] = std::array::from_fn(|r| self.row(r));
+        serialize_array(&rows, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, const R: usize, const C: usize> serde::Deserialize<'de> for Matrix<R, C> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Matrix<R, C>, D::Error> {
+        let rows: [MatVec<C>; R] = deserializer.deserialize_tuple(R, ArrayVisitor::<MatVec<C>, R>(std::marker::PhantomData))?;
+        Ok(Matrix::new(rows.map(MatVec::into_array)))
+    }
+}
+
 // Other Commonly used types and constructors
 
 #[derive(Debug, Clone)]
 pub struct Intersection {
 
-    pub shape_id: Option<usize>, // Index of the shape in the scene
+    pub shape_id: Option<ShapeId>, // ID of the shape in the scene, see `Scene::add_shape`
     pub point: MatVec<3>,     // Intersection point in the world coordinate frame
     pub normal: MatVec<3>,    // Normals with respect to the object in the world coordinate frame
     pub distance: f32,          // Distance from the ray origin to the intersection point
     pub residual: bool,         // Indicates whether or not the intersection will result in a residual
+    pub primitive: Option<usize>, // Index of the face that was hit, for shapes made of several, see `Mesh::intersect`
 
 }
 
 pub struct InputState {
 
     color: Color,
-    texture: String,
+    texture: Option<Arc<Texture2d>>,
+    normal_map: Option<Arc<Texture2d>>, // Tangent space normals for the surfaces of new spheres
+    bump_map: Option<(Arc<Texture2d>, f32)>, // Heights and their scale for the surfaces of new spheres
+    texture_cache: HashMap<String, Arc<Texture2d>>, // Decoded texture files, keyed by path
+    wrap_mode: WrapMode,
+    uv_transform: UvTransform, // Applied to the UV coordinates of new textures before sampling
     verticies: Vec<MatVec<3>>,
     texcoords: Vec<MatVec<2>>,
+    vertcolors: Vec<Color>, // Colors of the vertices, indexed like `verticies`
     // texture: String,
     roughness: f32,
+    velocity: MatVec<3>,
     shininess: Vec<f32>,
     transparency: Vec<f32>,
     index_of_refraction: f32,
+    specular_exponent: f32,
+    specular: Option<Color>,
+    clearcoat: Option<(f32, f32)>, // Strength and roughness of a clear layer over the material
+    emission: Option<Color>, // Light given off by the surfaces of new shapes
+    backface_cull: bool, // Whether new triangles ignore rays hitting their back side
+    alpha_cutoff: Option<f32>, // New textured triangles are not hit where the texture alpha is below this
+    alpha_blend: bool, // Whether new textured triangles are blended over what is behind them by the texture alpha
+    transform: Transform,
+    transform_stack: Vec<Transform>,
+    meshes: HashMap<String, Arc<Mesh>>, // Meshes defined so far, which `instance` places by name
+    mesh_definition: Option<(String, Vec<Triangle>)>, // Name and triangles of the mesh being defined, if any
 
 }
 
@@ -273,29 +607,159 @@ impl InputState {
 
     pub fn new() -> InputState {
         InputState {
-            color: MatVec::new(vec![1.0, 1.0, 1.0]),
-            texture: "none".to_string(),
+            color: MatVec::one(),
+            texture: None,
+            normal_map: None,
+            bump_map: None,
+            texture_cache: HashMap::new(),
+            wrap_mode: WrapMode::CLAMP,
+            uv_transform: UvTransform::identity(),
             verticies: Vec::new(),
             texcoords: Vec::new(),
+            vertcolors: Vec::new(),
             roughness: 0.0_f32,
+            velocity: MatVec::zero(),
             shininess: Vec::new(),
             transparency: Vec::new(),
             index_of_refraction: 1.458_f32,
+            specular_exponent: 0.0_f32,
+            specular: None,
+            clearcoat: None,
+            emission: None,
+            backface_cull: false,
+            alpha_cutoff: None,
+            alpha_blend: false,
+            transform: Transform::new(),
+            transform_stack: Vec::new(),
+            meshes: HashMap::new(),
+            mesh_definition: None,
+        }
+    }
+
+    /// Restores the defaults of `new`, for the `reset` directive. What the file has built up and refers to
+    /// later is kept: the decoded textures, the defined meshes, the mesh being defined and the pushed transforms.
+    pub fn reset(&mut self) {
+        *self = InputState {
+            texture_cache: std::mem::take(&mut self.texture_cache),
+            meshes: std::mem::take(&mut self.meshes),
+            mesh_definition: self.mesh_definition.take(),
+            transform_stack: std::mem::take(&mut self.transform_stack),
+            ..InputState::new()
+        };
+    }
+
+    /// Returns the texture decoded from `path`.
+    /// Each file is only decoded once, and shared by every shape that uses it.
+    /// Files that fail to load are not cached.
+    pub fn load_texture(&mut self, path: &String) -> Result<Arc<Texture2d>, TextureError> {
+        if let Some(texture) = self.texture_cache.get(path) {
+            return Ok(Arc::clone(texture));
+        }
+        log::log_info!("Loading texture from file: {}", path);
+        let texture: Arc<Texture2d> = Arc::new(Texture2d::new(path)?);
+        self.texture_cache.insert(path.clone(), Arc::clone(&texture));
+        Ok(texture)
+    }
+
+    /// The transform that newly created shapes should use, `None` if it is the identity.
+    pub fn current_transform(&self) -> Option<Transform> {
+        if self.transform.is_identity() {
+            None
+        } else {
+            Some(self.transform)
         }
     }
 
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(rename_all = "lowercase"))]
 pub enum ProjectionType {
 
     FLAT,
     FISHEYE,
     PANORAMIC,
+    ORTHOGRAPHIC,
     // DOF,
 
 }
 
+/// Tone mapping operator applied to the linear color before gamma correction.
+/// `EXPOSURE` uses the `expose` value, and is a no-op when no exposure was given.
+#[derive(Debug)]
+pub enum ToneMapping {
+
+    EXPOSURE,
+    REINHARD,
+    ACES,
+
+}
+
+/// How the alpha channel of the written image relates to its color.
+/// Samples are averaged with their alpha, so a traced pixel is premultiplied, and it is unpremultiplied
+/// before tone mapping either way. `STRAIGHT` writes that color as it is, and `PREMULTIPLIED` multiplies
+/// it by the alpha again after tone mapping and gamma correction, for compositors that expect it.
+#[derive(Debug)]
+pub enum AlphaMode {
+
+    STRAIGHT,
+    PREMULTIPLIED,
+
+}
+
+/// Color space of the written image.
+/// `LINEAR` skips the sRGB gamma correction. Textures are always converted to linear
+/// when sampled (see `Texture2d::sample`), so a linear render round-trips its textures unchanged.
+#[derive(Debug)]
+pub enum ColorSpace {
+
+    SRGB,
+    LINEAR,
+
+}
+
+/// How the anti-aliasing samples are placed within a pixel.
+/// `RANDOM` places each one uniformly at random, `GRID` at the centers of a regular grid,
+/// and `STRATIFIED` at a random point within each cell of that grid (jittered sampling),
+/// which avoids both the clumping of `RANDOM` and the aliasing of `GRID`.
+#[derive(Debug, Clone, Copy)]
+pub enum SampleMode {
+
+    RANDOM,
+    GRID,
+    STRATIFIED,
+
+}
+
+/// Statistic of the rendered luminance that `autoexpose` maps to its target.
+/// `AVERAGE` uses the log-average, which is robust to a few very bright pixels,
+/// and `MAX` the brightest pixel, so nothing gets saturated.
+#[derive(Debug, Clone, Copy)]
+pub enum AutoExposure {
+
+    AVERAGE,
+    MAX,
+
+}
+
+/// What `render` writes into the image.
+/// `DEPTH` is the distance to the first hit, normalized by the largest distance in the image,
+/// and `NORMAL` encodes the world space normal of the first hit from `[-1, 1]` into `[0, 1]`.
+/// `RAYS` renders like `BEAUTY`, but shows the number of rays cast for every pixel on a heat ramp,
+/// normalized by the largest count in the image.
+/// `WIREFRAME` renders like `BEAUTY`, but draws the edges of triangles over it, see `Scene::wireframe`.
+#[derive(Debug)]
+pub enum OutputPass {
+
+    BEAUTY,
+    DEPTH,
+    NORMAL,
+    RAYS,
+    WIREFRAME,
+
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CameraState {
 
     pub width: u32,
@@ -305,7 +769,13 @@ pub struct CameraState {
     pub eye: MatVec<3>,
     pub exposure: Option<f32>,
     pub projection: ProjectionType,
+    pub orthographic_width: f32, // World space extent of the longer image side, for the orthographic projection
     pub dof: Option<DofParams>,
+    pub dof_samples: u32,
+    pub focus_point: Option<MatVec<3>>, // World point kept in focus, overrides the focal length of `dof`
+    pub look_at: Option<MatVec<3>>,     // World point the camera is aimed at, overrides the direction of `forward`
+    pub fov: Option<f32>,               // Horizontal field of view of the flat projection in radians, see `screen_scale`
+    pub clip: (f32, f32),               // Near and far distances along camera rays, hits outside are not seen
 
 }
 
@@ -315,19 +785,126 @@ impl CameraState {
         CameraState {
             width,
             height,
-            forward: MatVec::new(vec![0.0, 0.0, -1.0]),
-            up: MatVec::new(vec![0.0, 1.0, 0.0]),
-            eye: MatVec::new(vec![0.0, 0.0, 0.0]),
+            forward: MatVec::from_array([0.0, 0.0, -1.0]),
+            up: MatVec::from_array([0.0, 1.0, 0.0]),
+            eye: MatVec::zero(),
             exposure: None,
             projection: ProjectionType::FLAT,
+            orthographic_width: 2.0,
             dof: None,
+            dof_samples: 1,
+            focus_point: None,
+            look_at: None,
+            fov: None,
+            clip: (0.0, f32::INFINITY),
+        }
+    }
+
+    /// Distance from the eye to the plane in focus, measured along `forward`.
+    /// A focus point overrides the focal length given to `dof`, and is resolved here rather than when it is parsed,
+    /// so it follows the final eye and forward.
+    pub fn focal_length(&self, dof: &DofParams) -> f32 {
+        match self.focus_point {
+            Some(point) => (point - self.eye).dot(self.forward.normalize()),
+            None => dof[0],
+        }
+    }
+
+    /// Angle in radians that a pixel covers at the center of the image, 0 for the orthographic projection,
+    /// whose rays are parallel.
+    pub fn pixel_spread(&self) -> f32 {
+        let longer: f32 = u32::max(self.width, self.height) as f32;
+        match self.projection {
+            ProjectionType::FLAT => 2.0 * self.screen_scale() / (longer * self.forward.magnitude()),
+            ProjectionType::PANORAMIC => 2.0 * std::f32::consts::PI / self.width as f32,
+            ProjectionType::ORTHOGRAPHIC => 0.0,
+            ProjectionType::FISHEYE => 2.0 / longer,
         }
     }
 
+    /// Factor on the offsets of pixels from the center of the image plane of the flat projection.
+    /// The plane spans 2 units across the longer side of the image at the tip of `forward`, so without `fov` the
+    /// field of view narrows as `forward` gets longer. With it, the offsets are scaled so the width of the image
+    /// spans the angle whatever the length of `forward`.
+    pub fn screen_scale(&self) -> f32 {
+        match self.fov {
+            None => 1.0,
+            Some(fov) => {
+                let longer: f32 = u32::max(self.width, self.height) as f32;
+                (0.5 * fov).tan() * self.forward.magnitude() * longer / self.width as f32
+            },
+        }
+    }
+
+    /// Right and up directions of the image plane of the flat projection, orthonormal to `forward`.
+    pub fn flat_basis(&self) -> (MatVec<3>, MatVec<3>) {
+        let arbitrary_up = if *self.up.get(1) < 0.0 {
+            MatVec::from_array([0.0, -1.0, 0.0])
+        } else {
+            MatVec::from_array([0.0, 1.0, 0.0])
+        };
+        let right: MatVec<3> = utils::camera_right(&self.forward.normalize(), &arbitrary_up);
+        let up: MatVec<3> = right.cross(&self.forward.normalize()).normalize();
+        (right, up)
+    }
+
+    /// The volume seen by the flat projection, widened by a pixel on every side for the anti-aliasing samples.
+    /// Other projections, and depth of field, whose rays start away from the eye, have no frustum.
+    pub fn frustum(&self) -> Option<Frustum> {
+
+        if !matches!(self.projection, ProjectionType::FLAT) || self.dof.is_some() {
+            return None;
+        }
+
+        let (right, up) = self.flat_basis();
+        let longer: f32 = u32::max(self.width, self.height) as f32;
+        let half_x: f32 = self.screen_scale() * (self.width + 1) as f32 / longer;
+        let half_y: f32 = self.screen_scale() * (self.height + 1) as f32 / longer;
+        let corner = |sx: f32, sy: f32| self.forward + (sx * half_x) * right + (sy * half_y) * up;
+        let corners: [MatVec<3>; 4] = [corner(-1.0, -1.0), corner(1.0, -1.0), corner(1.0, 1.0), corner(-1.0, 1.0)];
+
+        // Every side plane goes through the eye and two neighbouring corner rays, with the normal turned inwards
+        let mut planes: Vec<(MatVec<3>, f32)> = (0..4).map(|i| {
+            let normal: MatVec<3> = corners[i].cross(&corners[(i + 1) % 4]).normalize();
+            let normal: MatVec<3> = if normal.dot(self.forward) < 0.0 { -1.0 * normal } else { normal };
+            (normal, -normal.dot(self.eye))
+        }).collect();
+        let forward: MatVec<3> = self.forward.normalize();
+        planes.push((forward, -forward.dot(self.eye)));
+
+        // Everything past the far plane is also farther than the far clip distance from the eye
+        if self.clip.1.is_finite() {
+            planes.push((-1.0 * forward, forward.dot(self.eye) + self.clip.1));
+        }
+
+        Some(Frustum { planes })
+
+    }
+
+}
+
+/// A convex volume bounded by planes, given as inward normals `n` and offsets `d`, so that a point `p` is inside
+/// when `n . p + d >= 0` for every plane.
+pub struct Frustum {
+    planes: Vec<(MatVec<3>, f32)>,
+}
+
+impl Frustum {
+
+    /// Whether an axis aligned box may overlap the frustum. Boxes near the edges may be kept even when they are
+    /// outside, but a box is only rejected when all of it is behind one of the planes.
+    pub fn overlaps_box(&self, min: &MatVec<3>, max: &MatVec<3>) -> bool {
+        self.planes.iter().all(|(normal, offset)| {
+            // The corner of the box farthest along the normal
+            let corner: MatVec<3> = MatVec::from_array(std::array::from_fn(|i| if normal[i] >= 0.0 { max[i] } else { min[i] }));
+            normal.dot(corner) + offset >= 0.0
+        })
+    }
+
 }
 
 pub struct LightResidual {
-    pub source_id: Option<usize>, // TODO: Will be used in reflections
+    pub source_id: Option<usize>, // Index of the light source in the scene, None for indirect light
     pub color: Color,
     pub intensity: f32,
     pub direction: MatVec<3>,
@@ -339,17 +916,18 @@ impl LightResidual {
     pub fn new() -> LightResidual {
         LightResidual {
             source_id: None,
-            color: MatVec::new(vec![0.0, 0.0, 0.0]),
+            color: MatVec::zero(),
             intensity: 0.0,
             // direction: Ray::new(MatVec::new(vec![0.0, 0.0, 0.0]), MatVec::new(vec![0.0, 0.0, 0.0])),
-            direction: MatVec::new(vec![0.0, 0.0, 0.0]),
-            normal: MatVec::new(vec![0.0,0.0,0.0]),
+            direction: MatVec::zero(),
+            normal: MatVec::zero(),
         }
     }
 }
 
 // Type aliases
 pub type IntersectionPayload = Option<Intersection>;
+pub type ShapeId = u64;
 pub type RGBA = MatVec<4>;
 pub type Color = MatVec<3>;
 pub type Light = MatVec<3>;
@@ -357,10 +935,85 @@ pub type DofParams = MatVec<2>;
 
 // Export internal modules
 pub mod raytracer;
+pub mod log;
 mod ray;
 mod scene;
 mod shapes;
 mod light_sources;
 mod utils;
 mod texture;
-mod material;
\ No newline at end of file
+mod material;
+mod transform;
+#[cfg(feature = "serde")]
+mod shared;
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn assert_close(actual: &MatVec<3>, expected: [f32; 3]) {
+        assert!(actual.approx_eq(&MatVec::from_array(expected), 1e-4), "expected {:?}, got {}", expected, actual);
+    }
+
+    #[test]
+    fn try_normalize_without_a_direction() {
+        assert!(MatVec::<3>::zero().try_normalize().is_none());
+        assert!(MatVec::from_array([1e-30f32, 0.0, 0.0]).try_normalize().is_none());
+        assert!(MatVec::from_array([f32::NAN, 1.0, 0.0]).try_normalize().is_none());
+        assert_close(&MatVec::from_array([0.0, 3.0, 4.0]).try_normalize().unwrap(), [0.0, 0.6, 0.8]);
+    }
+
+    #[test]
+    fn reflect_at_45_degrees() {
+        let direction = MatVec::from_array([1.0, -1.0, 0.0]).normalize();
+        let normal = MatVec::from_array([0.0, 1.0, 0.0]);
+        let half: f32 = 0.5f32.sqrt();
+        assert_close(&direction.reflect(&normal), [half, half, 0.0]);
+    }
+
+    #[test]
+    fn refract_from_air_into_glass() {
+        let direction = MatVec::from_array([1.0, -1.0, 0.0]).normalize();
+        let normal = MatVec::from_array([0.0, 1.0, 0.0]);
+        let refracted = direction.refract(&normal, 1.0 / 1.5).unwrap();
+        // Snell's law, sin 45° = 1.5 sin t
+        let sin_t: f32 = 0.5f32.sqrt() / 1.5;
+        assert_close(&refracted, [sin_t, -(1.0 - sin_t * sin_t).sqrt(), 0.0]);
+    }
+
+    #[test]
+    fn refract_past_the_critical_angle_reflects_totally() {
+        let direction = MatVec::from_array([1.0, -1.0, 0.0]).normalize();
+        let normal = MatVec::from_array([0.0, 1.0, 0.0]);
+        // The critical angle from glass into air is about 42°
+        assert!(direction.refract(&normal, 1.5).is_none());
+        assert!(direction.refract(&normal, 1.4).is_some());
+    }
+
+    #[test]
+    fn perturb_without_roughness_is_the_identity() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let normal = MatVec::from_array([0.3, 0.4, 0.5]);
+        assert!(normal.perturb(0.5, 0.0, &mut rng).eq(normal));
+    }
+
+    #[test]
+    fn perturb_with_full_roughness_covers_the_hemisphere() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let normal = MatVec::from_array([0.0, 0.0, 1.0]);
+        // Near the horizon, every eighth of the way around the normal is reached
+        let mut reached = [false; 8];
+        for _ in 0..10000 {
+            let perturbed: MatVec<3> = normal.perturb(0.5, 1.0, &mut rng).normalize();
+            if perturbed[2] > 0.0 && perturbed[2] < 0.2 {
+                let angle: f32 = perturbed[1].atan2(perturbed[0]) + std::f32::consts::PI;
+                reached[((angle / std::f32::consts::FRAC_PI_4) as usize).min(7)] = true;
+            }
+        }
+        assert!(reached.iter().all(|reached| *reached), "reached {:?}", reached);
+    }
+
+}
@@ -1,17 +1,26 @@
 use crate::raytracer::{CameraState, Intersection, MatVec, ProjectionType};
 use crate::raytracer::scene::LightSource;
+use crate::raytracer::utils;
 use rand::Rng;
+use rand::rngs::StdRng;
 
 use super::scene::SceneObject;
 
 /// Represents a ray in 3D space.
 /// The actual creating of rays is done through the
 /// static methods implemented on this struct.
+/// `time` is the moment within the shutter interval the ray samples, which moving objects
+/// use to find their position. Secondary rays inherit it from the ray that spawned them.
 #[derive(Debug, Clone)]
 pub struct Ray {
 
     pub origin: MatVec<3>,
     pub direction: MatVec<3>,
+    pub time: f32,
+    pub near: f32, // Hits closer than `near` or farther than `far` are ignored, only camera rays are clipped
+    pub far: f32,
+    pub spread: f32, // Angle in radians of the cone of directions the ray stands for, 0 for a single direction
+    pub pdf: f32, // Density the direction was sampled with by a GI bounce, 0 for rays that were aimed
 
 }
 
@@ -21,61 +30,99 @@ impl Ray {
         Ray {
             origin,
             direction,
+            time: 0.0,
+            near: 0.0,
+            far: f32::INFINITY,
+            spread: 0.0,
+            pdf: 0.0,
+        }
+    }
+
+    /// Returns the same ray at a different time.
+    pub fn at_time(self, time: f32) -> Ray {
+        Ray {
+            time,
+            ..self
+        }
+    }
+
+    /// Returns the same ray, only seeing what lies between `near` and `far` along it.
+    pub fn clipped(self, near: f32, far: f32) -> Ray {
+        Ray {
+            near,
+            far,
+            ..self
+        }
+    }
+
+    /// Returns the same ray, standing for a cone of directions `spread` radians wide.
+    pub fn with_spread(self, spread: f32) -> Ray {
+        Ray {
+            spread,
+            ..self
+        }
+    }
+
+    /// Returns the same ray, marked as sampled with solid angle density `pdf`, see `Scene::_emission_weight`.
+    pub fn with_pdf(self, pdf: f32) -> Ray {
+        Ray {
+            pdf,
+            ..self
         }
     }
 
     /// Generates a ray that passes through the pixel at the given coordinates.
     /// Origin of the ray is the camera's eye, and the direction is computed based on the projection type.
-    /// Currently supportes, Flat and Fisheye projections.
+    /// Currently supportes, Flat, Fisheye, Panoramic and Orthographic projections.
+    /// Orthographic rays all point along `forward`, starting from points spread over the plane of the eye.
+    /// Returns `None` when no ray passes through the pixel, ie outside the lens circle of a fisheye projection.
+    /// The ray is clipped to the `clip` distances of the camera, and spreads over the angle of a pixel.
     /// NOTE: Fisheye does not work as of now.
+    pub fn generate_primary_ray(through_pixel: MatVec<2>, context: &CameraState, rng: &mut StdRng) -> Option<Ray> {
+        Ray::_unclipped_primary_ray(through_pixel, context, rng)
+            .map(|ray| ray.clipped(context.clip.0, context.clip.1).with_spread(context.pixel_spread()))
+    }
+
     #[allow(unreachable_patterns)]
-    pub fn generate_primary_ray(through_pixel: MatVec<2>, context: &CameraState) -> Ray {
+    fn _unclipped_primary_ray(through_pixel: MatVec<2>, context: &CameraState, rng: &mut StdRng) -> Option<Ray> {
         
         match context.projection {
             
             ProjectionType::FLAT => {
                 
-                let s_x: f32 = ((2.0 * through_pixel[0]) - (context.width as f32)) / (u32::max(context.width, context.height) as f32);
-                let s_y: f32 = ((context.height as f32) - (2.0 * through_pixel[1])) / (u32::max(context.width, context.height) as f32);
+                let scale: f32 = context.screen_scale();
+                let s_x: f32 = scale * ((2.0 * through_pixel[0]) - (context.width as f32)) / (u32::max(context.width, context.height) as f32);
+                let s_y: f32 = scale * ((context.height as f32) - (2.0 * through_pixel[1])) / (u32::max(context.width, context.height) as f32);
 
                 let eye: MatVec<3> = context.eye.clone();
                 let forward: MatVec<3> = context.forward.clone();
 
-                let arbitrary_up = if *context.up.get(1) < 0.0 {
-                    MatVec::new(vec![0.0, -1.0, 0.0])
-                } else {
-                    MatVec::new(vec![0.0, 1.0, 0.0])
-                };
-
-                // Compute the right vector
-                let right: MatVec<3> = (forward.normalize()).cross(&arbitrary_up).normalize();
+                // Right and up vectors of the image plane, orthonormal to forward
+                let (right, up) = context.flat_basis();
 
-                // Compute the proper up vector to ensure orthonormality
-                let up: MatVec<3> = right.cross(&forward.normalize()).normalize();
-
-                // Construct the ray direction
-                let mut ray = Ray::new(eye.clone(), (forward + (s_x * right) + (s_y * up)).normalize());
+                // Construct the ray direction, a camera without a forward direction has none through the center
+                let mut ray = Ray::new(eye, (forward + (s_x * right) + (s_y * up)).try_normalize()?);
 
                 // Apply depth of field if enabled
                 if let Some(dof_params) = &context.dof {
-                    let focus = dof_params[0];
+                    let focus = context.focal_length(dof_params);
                     let lens_radius = dof_params[1];
 
-                    // Randomly perturb the ray's origin and direction
-                    let mut rng = rand::thread_rng();
-                    let rand_x: f32 = rng.gen_range(-1.0..1.0);
-                    let rand_y: f32 = rng.gen_range(-1.0..1.0);
+                    // Randomly perturb the ray's origin and direction, with the origin sampled uniformly on the lens disk
+                    let lens_sample: MatVec<2> = utils::concentric_disk_sample(rng.gen(), rng.gen());
+                    let rand_x: f32 = lens_sample[0];
+                    let rand_y: f32 = lens_sample[1];
 
                     let lens_offset = lens_radius * (rand_x * right + rand_y * up);
                     let new_origin = eye + lens_offset;
 
                     let focal_point = ray.origin + focus * ray.direction;
-                    let new_direction = (focal_point - new_origin).normalize();
+                    let new_direction = (focal_point - new_origin).try_normalize()?;
 
                     ray = Ray::new(new_origin, new_direction);
                 }
 
-                ray
+                Some(ray)
 
             },
 
@@ -85,32 +132,59 @@ impl Ray {
                 let s_y: f32 = ((context.height as f32) - (2.0 * through_pixel[1])) / (u32::max(context.width, context.height) as f32);
 
                 if s_x.powi(2) + s_y.powi(2) > 1.0 {
-                    return Ray::new(MatVec::new(vec![0.0, 0.0, 0.0]), MatVec::new(vec![0.0, 0.0, 0.0])); // No ray
+                    return None;
                 }
 
                 let eye: MatVec<3> = context.eye.clone();
                 let forward: MatVec<3> = context.forward.clone();
                 let arbitrary_up = context.up.clone().normalize();
-                let right: MatVec<3> = forward.cross(&arbitrary_up).normalize();
+                let right: MatVec<3> = utils::camera_right(&forward, &arbitrary_up);
                 let up: MatVec<3> = right.cross(&forward).normalize();
                 let direction = (f32::sqrt(1.0 - s_x.powi(2) - s_y.powi(2)) * forward + s_x * right + s_y * up).normalize();
 
-                Ray::new(eye, direction)
+                Some(Ray::new(eye, direction))
 
             }
 
-            ProjectionType::PANORAMIC => {
-                let theta: f32 = (through_pixel[0] / context.width as f32) * 2.0 * std::f32::consts::PI - std::f32::consts::PI;
-                let phi: f32 = ((context.height as f32 - through_pixel[1]) / context.height as f32) * std::f32::consts::PI - (std::f32::consts::PI / 2.0);
-            
-                let eye: MatVec<3> = context.eye.clone();
-                let forward: MatVec<3> = context.forward.clone();
-                let arbitrary_up = context.up.clone().normalize();
-                let right: MatVec<3> = forward.cross(&arbitrary_up).normalize();
+            ProjectionType::ORTHOGRAPHIC => {
+
+                let s_x: f32 = ((2.0 * through_pixel[0]) - (context.width as f32)) / (u32::max(context.width, context.height) as f32);
+                let s_y: f32 = ((context.height as f32) - (2.0 * through_pixel[1])) / (u32::max(context.width, context.height) as f32);
+
+                let forward: MatVec<3> = context.forward.normalize();
+
+                // Same frame as the flat projection, so both frame the scene the same way from the eye
+                let arbitrary_up = if *context.up.get(1) < 0.0 {
+                    MatVec::from_array([0.0, -1.0, 0.0])
+                } else {
+                    MatVec::from_array([0.0, 1.0, 0.0])
+                };
+                let right: MatVec<3> = utils::camera_right(&forward, &arbitrary_up);
                 let up: MatVec<3> = right.cross(&forward).normalize();
+
+                // The screen coordinates span [-1, 1] along the longer side, which covers the orthographic width
+                let half_width: f32 = 0.5 * context.orthographic_width;
+                let origin: MatVec<3> = context.eye + half_width * (s_x * right + s_y * up);
+
+                Some(Ray::new(origin, forward))
+
+            },
+
+            ProjectionType::PANORAMIC => {
+
+                // Angles of the pixel center, so the columns tile [-pi, pi) and the rows tile [pi/2, -pi/2],
+                // with the seam on the image edges and no row sitting on a pole
+                let theta: f32 = ((through_pixel[0] + 0.5) / context.width as f32) * 2.0 * std::f32::consts::PI - std::f32::consts::PI;
+                let phi: f32 = (std::f32::consts::PI / 2.0) - ((through_pixel[1] + 0.5) / context.height as f32) * std::f32::consts::PI;
+
+                // Orthonormal basis, `forward` is not necessarily unit length, nor `up` perpendicular to it
+                let eye: MatVec<3> = context.eye;
+                let forward: MatVec<3> = context.forward.normalize();
+                let right: MatVec<3> = utils::camera_right(&forward, &context.up);
+                let up: MatVec<3> = right.cross(&forward);
                 let direction = (theta.cos() * phi.cos() * forward + theta.sin() * phi.cos() * right + phi.sin() * up).normalize();
             
-                Ray::new(eye, direction)
+                Some(Ray::new(eye, direction))
             }
             
 
@@ -120,9 +194,10 @@ impl Ray {
 
 
     /// Generates a ray that starts at the intersection point and points towards the light source.
-    pub fn generate_light_ray(intersection: &Intersection, light_source: &Box<dyn LightSource>) -> Ray {
+    /// For area lights, the ray points towards a random point on the light.
+    pub fn generate_light_ray(intersection: &Intersection, light_source: &Box<dyn LightSource>, rng: &mut StdRng) -> Ray {
         let origin = intersection.point.clone();
-        let dir = light_source.compute_direction(&origin);
+        let dir = light_source.sample_direction(&origin, rng);
 
         Ray::new(origin, dir)
     }
@@ -139,57 +214,48 @@ impl Ray {
         let direction = incoming_ray.direction.normalize();
 
         // Calculate reflection direction
-        let dot = direction.dot(normal).clamp(-1.0, 1.0); // Clamp for numerical stability
-        let reflection = (direction - 2.0 * dot * normal).normalize();
+        let reflection = direction.reflect(&normal).normalize();
 
-        Ray::new(origin, reflection)
+        Ray::new(origin, reflection).at_time(incoming_ray.time)
 
     }
 
 
-    pub fn generate_refraction_ray(intersection: &Intersection, incoming_ray: &Ray, shape: &Box<dyn SceneObject>) -> Ray {
-        let mut normal = intersection.normal.clone();
+    /// Generates the ray refracted into `shape` at `intersection`, and out through its far side.
+    /// Returns it with the hit it leaves from, so that the scene offsets its origin by the bias,
+    /// see `Scene::find_minimum_intersection_with_point`. The far side is searched from `bias` inside the surface,
+    /// so that the entry point is not found again. Open surfaces such as planes and triangles have no far side,
+    /// and the ray continues from the entry point. On total internal reflection at the entry the ray is reflected,
+    /// and at the exit it goes on undeflected.
+    pub fn generate_refraction_ray(intersection: &Intersection, incoming_ray: &Ray, shape: &dyn SceneObject, bias: f32) -> (Ray, Intersection) {
+        let direction: MatVec<3> = incoming_ray.direction.normalize();
+        let mut normal = intersection.normal.normalize();
         let mut eta = 1.0 / shape.ior(); // Assume ray is entering the material
 
         // Check if the ray is exiting the material
-        if incoming_ray.direction.dot(normal) > 0.0 {
+        if direction.dot(normal) > 0.0 {
             normal = -1.0f32 * normal;
             eta = shape.ior();
         }
 
-        let cos_i = -normal.dot(incoming_ray.direction.clone()).clamp(-1.0, 1.0);
-        let sin_t2 = eta * eta * (1.0 - cos_i * cos_i);
-
         // Total internal reflection
-        if sin_t2 > 1.0 {
-            return Ray::generate_reflection_ray(intersection, incoming_ray);
-        }
-
-        let cos_t = (1.0 - sin_t2).sqrt();
-        let refraction_direction = eta * incoming_ray.direction + (eta * cos_i - cos_t) * normal;
-
-        // Find the exit point of the ray through the object
-        let exit_intersection = shape.intersect(&Ray::new(intersection.point.clone(), refraction_direction.clone().normalize())).unwrap();
-        let exit_point = exit_intersection.point;
-        let exit_normal = exit_intersection.normal;
-
-        // At the exit point, invert eta for material-to-air transition
-        let exit_eta = 1.0 / shape.ior();
-        let exit_cos_i = -exit_normal.dot(refraction_direction).clamp(-1.0, 1.0);
-        let exit_sin_t2 = exit_eta * exit_eta * (1.0 - exit_cos_i * exit_cos_i);
-
-        // Total internal reflection check at the exit point
-        if exit_sin_t2 <= 1.0 {
-            let exit_cos_t = (1.0 - exit_sin_t2).sqrt();
-            let exit_refraction_direction = exit_eta * refraction_direction + (exit_eta * exit_cos_i - exit_cos_t) * exit_normal;
-            return Ray::new(exit_point, exit_refraction_direction);
-        } else {
-            return Ray::new(exit_point, refraction_direction.normalize());
-        }
-
-
-        // Ray::new(exit_point, refraction_direction.normalize())
-
-        // Ray::new(exit_point, refraction_direction.normalize())
+        let refraction_direction = match direction.refract(&normal, eta) {
+            Some(refraction_direction) => refraction_direction,
+            None => return (Ray::generate_reflection_ray(intersection, incoming_ray), intersection.clone()),
+        };
+
+        // Find the exit point of the ray through the object,
+        // open surfaces such as planes and triangles have none, so the ray continues from the entry point
+        let inside = Ray::new(intersection.point - bias * normal, refraction_direction).at_time(incoming_ray.time);
+        let exit_intersection = match shape.intersect(&inside) {
+            Some(exit_intersection) => exit_intersection,
+            None => return (Ray::new(intersection.point, refraction_direction).at_time(incoming_ray.time), intersection.clone()),
+        };
+
+        // At the exit point the ray goes from the material back into air, against the inward normal
+        let exit_normal: MatVec<3> = exit_intersection.normal.normalize();
+        let exit_direction: MatVec<3> = refraction_direction.refract(&(-1.0f32 * exit_normal), shape.ior()).unwrap_or(refraction_direction);
+
+        (Ray::new(exit_intersection.point, exit_direction).at_time(incoming_ray.time), exit_intersection)
     }
 }
\ No newline at end of file
@@ -1,11 +1,47 @@
-use super::{scene, utils, CameraState, InputState, MatVec, RGBA, ProjectionType};
+use super::{scene, utils, AlphaMode, AutoExposure, CameraState, Color, ColorSpace, InputState, MatVec, OutputPass, RGBA, ProjectionType, SampleMode, ShapeId, ToneMapping};
 use super::ray::Ray;
+use super::log::{self, LogLevel, log_error, log_info, log_trace, log_warn};
 use std::fs::File;
-use std::io::{BufRead, BufReader};
-use image::{ImageBuffer, RgbaImage};
-use rand::Rng;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use image::{DynamicImage, ImageBuffer, Rgba32FImage, RgbaImage};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 use super::shapes::{*};
 use super::light_sources::{*};
+use super::texture::{Texture2d, UvTransform, WrapMode};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use rayon::prelude::*;
+#[cfg(feature = "serde")]
+use super::shared;
+
+/// Deepest chain of `include` directives that is followed, in case the files cannot be told apart to find a cycle.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Work done by a render, returned by `RayTracer::render` and logged at info level when it finishes.
+/// The counts cover every pass over the pixels, adaptive anti-aliasing included.
+#[derive(Debug, Clone, Copy)]
+pub struct RenderStats {
+    pub camera_rays: u64,        // Samples traced from the camera, several per pixel with anti-aliasing
+    pub rays: u64,               // Rays of any kind, camera, reflected, indirect, shadow and ambient occlusion
+    pub bounces: u64,            // Reflected and indirect rays
+    pub intersection_tests: u64, // Tests of a ray against a shape, an instanced mesh counting as one shape
+    pub time: Duration,          // Wall clock time spent tracing and filtering the pixels
+}
+
+impl RenderStats {
+
+    /// Reflected and indirect rays per camera ray, 0 when no camera rays were traced.
+    pub fn average_bounces(&self) -> f64 {
+        if self.camera_rays == 0 {
+            return 0.0;
+        }
+        self.bounces as f64 / self.camera_rays as f64
+    }
+
+}
 
 pub struct RayTracer {
 
@@ -14,6 +50,19 @@ pub struct RayTracer {
     width: u32,
     bounce_limit: u32,
     anti_aliasing: u32,
+    aa_mode: SampleMode, // Placement of the anti-aliasing samples within a pixel
+    aa_threshold: Option<f32>, // Adaptive anti-aliasing only samples pixels whose neighbours differ by more than this
+    tone_mapping: ToneMapping,
+    auto_exposure: Option<(AutoExposure, f32)>, // Derives the exposure from the render, unless one was given
+    bloom: Option<(f32, f32, f32)>, // Luminance threshold, intensity and radius in pixels of the glow around bright pixels
+    color_space: ColorSpace,
+    alpha_mode: AlphaMode,
+    output: OutputPass,
+    threads: usize, // Number of render threads, 0 uses all cores
+    tile_size: u32, // Width and height of the square tiles handed to the render threads
+    crop: Option<(u32, u32, u32, u32)>, // Only pixels with x0 <= x < x1 and y0 <= y < y1 are rendered
+    seed: Option<u64>, // Seed for all random sampling, a random one is picked for every render when not set
+    shutter: (f32, f32), // Times at which the shutter opens and closes, samples are spread over the interval
     // other porperties
     input_state: InputState,
     image: RgbaImage,
@@ -24,7 +73,7 @@ pub struct RayTracer {
 
 impl RayTracer {
 
-    pub fn new(height: u32, width: u32) -> RayTracer {
+    pub fn new(width: u32, height: u32) -> RayTracer {
         let scene = scene::Scene::new();
         let default_bounce_limit = 4;
         let default_aa_limit = 0;
@@ -34,43 +83,217 @@ impl RayTracer {
             width,
             bounce_limit : default_bounce_limit,
             anti_aliasing: default_aa_limit,
+            aa_mode: SampleMode::RANDOM,
+            aa_threshold: None,
+            tone_mapping: ToneMapping::EXPOSURE,
+            auto_exposure: None,
+            color_space: ColorSpace::SRGB,
+            alpha_mode: AlphaMode::STRAIGHT,
+            output: OutputPass::BEAUTY,
+            threads: 0,
+            tile_size: 32,
+            crop: None,
+            bloom: None,
+            seed: None,
+            shutter: (0.0, 0.0),
             input_state : InputState::new(),
             image: ImageBuffer::new(width, height),
             camera : CameraState::new(width, height),
         }
     }
 
-    #[allow(unreachable_code)]
-    pub fn render_from_file(file_path: &str) {
-        println!("Rendering from file: {}", file_path);
+    /// Parses and renders a scene file, and saves the image to the file named in its header.
+    /// An output file of `-` writes the image to stdout as a binary PPM instead, diagnostics always go to the log.
+    /// `threads` overrides the `threads` directive of the file when given.
+    pub fn render_from_file(file_path: &str, threads: Option<usize>) {
+
+        let (mut raytracer, out_file) = RayTracer::from_file(file_path);
+
+        if let Some(threads) = threads {
+            raytracer.threads = threads;
+        }
+
+        // Wires are as wide as the cone of a camera ray, and the parallel rays of the orthographic projection have none
+        if raytracer.scene.wireframe && raytracer.camera.pixel_spread() <= 0.0 {
+            log_warn!("The wireframe output draws no edges with this projection");
+        }
+
+        log_info!("Rendering from file: {}", file_path);
+
+        // Float formats keep the unclamped linear colors, everything else goes through the 8-bit path
+        if out_file == "-" {
+            raytracer.render();
+            let stdout = std::io::stdout();
+            if let Err(error) = raytracer.write_ppm(&mut stdout.lock()) {
+                log_error!("Failed to write the image to stdout: {}", error);
+                std::process::exit(1);
+            }
+        } else if out_file.ends_with(".exr") || out_file.ends_with(".hdr") {
+            let buffer: Vec<RGBA> = raytracer.render_to_f32_buffer();
+            raytracer.save_f32_image(&buffer, out_file);
+        } else {
+            raytracer.render();
+            raytracer.save_image(out_file);
+        }
+
+    }
+
+    /// Joins the lines of a scene file into directives.
+    /// Everything after a `#` is a comment, and a trailing `\` continues the directive on the next line.
+    /// Lines left empty are dropped, even between the lines of a continued directive.
+    /// Every directive comes with the 1-based number of the line it starts on, for error messages.
+    fn _logical_lines(lines: impl Iterator<Item = String>) -> Vec<(usize, String)> {
+
+        let mut directives: Vec<(usize, String)> = Vec::new();
+        let mut pending: String = String::new();
+        let mut pending_start: Option<usize> = None;
+
+        for (i, line) in lines.enumerate() {
+
+            let code: &str = match line.find('#') {
+                Some(comment) => &line[..comment],
+                None => &line,
+            };
+            let code: &str = code.trim_end();
+
+            if code.trim_start().is_empty() {
+                continue;
+            }
+
+            let start: usize = *pending_start.get_or_insert(i + 1);
+
+            match code.strip_suffix('\\') {
+                Some(continued) => {
+                    pending.push_str(continued);
+                    pending.push(' ');
+                },
+                None => {
+                    pending.push_str(code);
+                    directives.push((start, pending.trim().to_string()));
+                    pending.clear();
+                    pending_start = None;
+                },
+            }
+
+        }
+
+        // A continuation on the last line has nothing to continue onto
+        if let Some(start) = pending_start {
+            if !pending.trim().is_empty() {
+                directives.push((start, pending.trim().to_string()));
+            }
+        }
+
+        directives
+
+    }
+
+    /// Replaces every `include path` directive with the directives of the named file, recursively.
+    /// The path is relative to the directory of the file that includes it, and the included file
+    /// has no header. Its directives are parsed as if they were written in place of the `include`,
+    /// so they see and change the same input state. `chain` holds the files being included from,
+    /// starting with the scene file, and a file that includes one of them is a cycle.
+    fn _expand_includes(directives: Vec<(usize, String)>, file_path: &Path, chain: &mut Vec<PathBuf>) -> Vec<(usize, String)> {
+
+        let mut expanded: Vec<(usize, String)> = Vec::new();
+
+        for (line_number, line) in directives {
+
+            let path: &str = match line.strip_prefix("include") {
+                Some(rest) if rest.starts_with(char::is_whitespace) => rest.trim(),
+                _ => {
+                    expanded.push((line_number, line));
+                    continue;
+                },
+            };
+
+            let include_path: PathBuf = file_path.parent().unwrap_or(Path::new("")).join(path);
+            let file = match File::open(&include_path) {
+                Ok(file) => file,
+                Err(error) => {
+                    log_error!("line {} of {}: Failed to include {}: {}", line_number, file_path.display(), include_path.display(), error);
+                    std::process::exit(1);
+                },
+            };
+            let canonical: PathBuf = include_path.canonicalize().unwrap_or(include_path.clone());
+            if chain.contains(&canonical) {
+                log_error!("line {} of {}: Including {} again would never end", line_number, file_path.display(), include_path.display());
+                std::process::exit(1);
+            }
+            if chain.len() > MAX_INCLUDE_DEPTH {
+                log_error!("line {} of {}: Includes are nested more than {} deep", line_number, file_path.display(), MAX_INCLUDE_DEPTH);
+                std::process::exit(1);
+            }
+
+            log_info!("Including file: {}", include_path.display());
+            chain.push(canonical);
+            let included: Vec<(usize, String)> = RayTracer::_logical_lines(BufReader::new(file).lines().map_while(Result::ok));
+            expanded.extend(RayTracer::_expand_includes(included, &include_path, chain));
+            chain.pop();
+
+        }
+
+        expanded
+
+    }
+
+    /// Loads a texture named by the directive on the given line, and exits with a message naming it if that fails.
+    fn _load_texture(&mut self, path: &String, line_number: usize) -> Arc<Texture2d> {
+        match self.input_state.load_texture(path) {
+            Ok(texture) => texture,
+            Err(error) => {
+                log_error!("line {}: {}", line_number, error);
+                std::process::exit(1);
+            },
+        }
+    }
+
+    /// Adds a triangle to the scene, or to the mesh being defined, whose triangles only enter the scene through its instances.
+    fn _add_triangle(&mut self, triangle: Triangle) {
+        match self.input_state.mesh_definition {
+            Some((_, ref mut triangles)) => triangles.push(triangle),
+            None => {
+                self.scene.add_shape(Box::new(triangle));
+            },
+        }
+    }
+
+    /// Parses a scene file without rendering it.
+    /// Returns the configured raytracer, and the output file named in the header.
+    /// Exits after reporting every problem found by `Scene::validate` if the scene has any, like it does for parse errors.
+    /// The shapes outside of the view are culled from the camera rays, see `Scene::cull_to_frustum`.
+    pub fn from_file(file_path: &str) -> (RayTracer, String) {
         let file = File::open(file_path).expect("File not found");
         let reader = BufReader::new(file);
 
-        // Read the lines into a vector of strings
-        let lines: Vec<String> = reader.lines().filter_map(Result::ok).collect();
+        // Read the lines into a vector of strings, one per directive, with the included files spliced in after the header
+        let mut lines: Vec<(usize, String)> = RayTracer::_logical_lines(reader.lines().map_while(Result::ok));
+        if lines.len() > 1 {
+            let body: Vec<(usize, String)> = lines.split_off(1);
+            let mut chain: Vec<PathBuf> = vec![Path::new(file_path).canonicalize().unwrap_or(PathBuf::from(file_path))];
+            lines.extend(RayTracer::_expand_includes(body, Path::new(file_path), &mut chain));
+        }
 
         // // Parse the header line
-        let header: &String = &lines[0];
+        let header: &String = &lines[0].1;
         let header_parts: Vec<String> = header.split_whitespace().map(|s| s.to_string()).collect();
 
         if header_parts.len() != 4 {
-            println!("Invalid file format.");
+            log_error!("Invalid file format.");
             std::process::exit(1);
         }
 
         let _magic_num: String = header_parts[0].clone();
-        let height: u32 = header_parts[1].parse().unwrap();
-        let width: u32 = header_parts[2].parse().unwrap();
+        // The header is `png width height output_file`
+        let width: u32 = header_parts[1].parse().unwrap();
+        let height: u32 = header_parts[2].parse().unwrap();
         let out_file: String = header_parts[3].clone();
 
         let mut raytracer = RayTracer::new(width, height);        
 
         for i in 1..lines.len() {
 
-            let line: &String = &lines[i];
-            if line.is_empty() || line.starts_with("#") {
-                continue;
-            }
+            let (line_number, ref line) = lines[i];
     
             let delimitted: Vec<String> = line.split_whitespace().map(|s| s.to_string()).collect();
             let action = delimitted[0].clone();
@@ -79,25 +302,68 @@ impl RayTracer {
             match action.as_str() {
 
                 "sphere" => {
-                    let center = MatVec::new(vec![elements[0].parse().unwrap(),
-                                                  elements[1].parse().unwrap(),
-                                                  elements[2].parse().unwrap()]);
+                    let center = MatVec::from_array([elements[0].parse().unwrap(),
+                                                     elements[1].parse().unwrap(),
+                                                     elements[2].parse().unwrap()]);
 
                     let radius = elements[3].parse().unwrap();
+                    log_trace!("Making sphere with center: {}, radius: {}, color: {}", center, radius, raytracer.input_state.color);
                     let obj = Sphere::new(center, radius, &raytracer.input_state);
                     raytracer.scene.add_shape(Box::new(obj));
                 },
 
+                "verbose" => {
+                    // Without a level everything is shown, down to every shape made
+                    let level: LogLevel = match elements.first() {
+                        None => LogLevel::TRACE,
+                        Some(name) => match LogLevel::from_name(name) {
+                            Some(level) => level,
+                            None => {
+                                log_error!("Invalid verbose level: {}", name);
+                                std::process::exit(1);
+                            },
+                        },
+                    };
+                    log::set_level(level);
+                },
+
                 "sun" => {
-                    let direction = MatVec::new(vec![elements[0].parse().unwrap(),
+                    let direction = MatVec::from_array([elements[0].parse().unwrap(),
                                                   elements[1].parse().unwrap(),
                                                   elements[2].parse().unwrap()]);
-                    let obj = Sun::new(direction, &raytracer.input_state);
+                    // `sun dx dy dz [angle [intensity]]`, with the angular diameter in degrees, a point-like sun casts
+                    // hard shadows, and the intensity scaling the color of the light. The intensity can also be named,
+                    // as in `sun dx dy dz [angle] intensity 2`, which is the only way to give one without an angle
+                    let mut optional: Vec<String> = elements[3..].to_vec();
+                    let named_intensity: Option<f32> = match optional.iter().position(|e| e == "intensity") {
+                        None => None,
+                        Some(index) => {
+                            let value: f32 = match optional.get(index + 1).map(|i| i.parse()) {
+                                Some(Ok(value)) => value,
+                                _ => {
+                                    log_error!("line {}: sun expects a number after intensity", line_number);
+                                    std::process::exit(1);
+                                },
+                            };
+                            optional.truncate(index);
+                            Some(value)
+                        },
+                    };
+                    if named_intensity.is_some() && optional.len() > 1 {
+                        log_error!("line {}: sun is given an intensity twice", line_number);
+                        std::process::exit(1);
+                    }
+                    if named_intensity.is_none() && optional.len() == 1 {
+                        log_warn!("line {}: the 4th argument of sun is its angle, {} degrees, write `intensity {}` for an intensity instead", line_number, optional[0], optional[0]);
+                    }
+                    let angle: f32 = optional.first().map_or(0.0, |a| a.parse().unwrap());
+                    let intensity: f32 = named_intensity.unwrap_or_else(|| optional.get(1).map_or(1.0, |i| i.parse().unwrap()));
+                    let obj = Sun::new(direction, angle, intensity, &raytracer.input_state);
                     raytracer.scene.add_light_source(Box::new(obj));
                 },
 
                 "color" => {
-                    let color = MatVec::new(vec![elements[0].parse().unwrap(),
+                    let color = MatVec::from_array([elements[0].parse().unwrap(),
                                                   elements[1].parse().unwrap(),
                                                   elements[2].parse().unwrap()]);
                     raytracer.input_state.color = color;
@@ -108,27 +374,101 @@ impl RayTracer {
                     raytracer.camera.exposure = Some(exposure);
                 },
 
+                "autoexpose" => {
+                    let mode: AutoExposure = match elements.first().map(String::as_str) {
+                        None | Some("average") => AutoExposure::AVERAGE,
+                        Some("max") => AutoExposure::MAX,
+                        Some(other) => {
+                            log_error!("Invalid auto exposure mode: {}", other);
+                            std::process::exit(1);
+                        },
+                    };
+                    // Middle grey for the average, and just short of white for the maximum
+                    let target: f32 = match elements.get(1) {
+                        Some(target) => target.parse().unwrap(),
+                        None => match mode {
+                            AutoExposure::AVERAGE => 0.18,
+                            AutoExposure::MAX => 0.9,
+                        },
+                    };
+                    if target <= 0.0 || target >= 1.0 {
+                        log_error!("Auto exposure target must be between 0 and 1, got {}", target);
+                        std::process::exit(1);
+                    }
+                    raytracer.auto_exposure = Some((mode, target));
+                },
+
+                "tonemap" => {
+                    raytracer.tone_mapping = match elements[0].as_str() {
+                        "exposure" => ToneMapping::EXPOSURE,
+                        "reinhard" => ToneMapping::REINHARD,
+                        "aces" => ToneMapping::ACES,
+                        other => {
+                            log_error!("Invalid tone mapping: {}", other);
+                            std::process::exit(1);
+                        },
+                    };
+                },
+
+                "colorspace" => {
+                    raytracer.color_space = match elements[0].as_str() {
+                        "srgb" => ColorSpace::SRGB,
+                        "linear" => ColorSpace::LINEAR,
+                        other => {
+                            log_error!("Invalid color space: {}", other);
+                            std::process::exit(1);
+                        },
+                    };
+                },
+
+                "alpha" => {
+                    raytracer.alpha_mode = match elements[0].as_str() {
+                        "straight" => AlphaMode::STRAIGHT,
+                        "premultiplied" => AlphaMode::PREMULTIPLIED,
+                        other => {
+                            log_error!("Invalid alpha mode: {}", other);
+                            std::process::exit(1);
+                        },
+                    };
+                },
+
                 "up" => {
-                    let up:MatVec<3> = MatVec::new(vec![elements[0].parse().unwrap(),
+                    let up:MatVec<3> = MatVec::from_array([elements[0].parse().unwrap(),
                                                   elements[1].parse().unwrap(),
                                                   elements[2].parse().unwrap()]);
                     raytracer.camera.up = up;
                 },
 
                 "eye" => {
-                    let eye: MatVec<3> = MatVec::new(vec![elements[0].parse().unwrap(),
+                    let eye: MatVec<3> = MatVec::from_array([elements[0].parse().unwrap(),
                                                   elements[1].parse().unwrap(),
                                                   elements[2].parse().unwrap()]);
                     raytracer.camera.eye = eye;
                 },
 
                 "forward" => {
-                    let forward: MatVec<3> = MatVec::new(vec![elements[0].parse().unwrap(),
+                    let forward: MatVec<3> = MatVec::from_array([elements[0].parse().unwrap(),
                                                   elements[1].parse().unwrap(),
                                                   elements[2].parse().unwrap()]);
                     raytracer.camera.forward = forward;
                 },
 
+                "lookat" => {
+                    let target: MatVec<3> = MatVec::from_array([elements[0].parse().unwrap(),
+                                                  elements[1].parse().unwrap(),
+                                                  elements[2].parse().unwrap()]);
+                    raytracer.camera.look_at = Some(target);
+                },
+
+                "fov" => {
+                    let fov: f32 = elements[0].parse().unwrap();
+                    if !(fov > 0.0 && fov < 180.0) {
+                        log_error!("fov must be between 0 and 180 degrees, got {}", fov);
+                        std::process::exit(1);
+                    }
+                    raytracer.camera.fov = Some(fov.to_radians());
+                },
+
                 "fisheye" => {
                     // todo!("Fisheye doesnt work rn");
                     raytracer.camera.projection = ProjectionType::FISHEYE;
@@ -139,43 +479,469 @@ impl RayTracer {
                     raytracer.camera.projection = ProjectionType::PANORAMIC;
                 },
 
+                "orthographic" => {
+                    let width: f32 = elements[0].parse().unwrap();
+                    if width <= 0.0 {
+                        log_error!("orthographic width must be positive, got {}", width);
+                        std::process::exit(1);
+                    }
+                    raytracer.camera.projection = ProjectionType::ORTHOGRAPHIC;
+                    raytracer.camera.orthographic_width = width;
+                },
+
                 "plane" => {
-                    let coeffs = MatVec::<4>::new(vec![elements[0].parse().unwrap(),
+                    let coeffs = MatVec::<4>::from_array([elements[0].parse().unwrap(),
                                                   elements[1].parse().unwrap(),
                                                   elements[2].parse().unwrap(),
                                                   elements[3].parse().unwrap()]);
+                    log_trace!("Making plane with coeffs: {}, color: {}", coeffs, raytracer.input_state.color);
                     let obj = Plane::new(coeffs, &raytracer.input_state);
                     raytracer.scene.add_shape(Box::new(obj));
                 },
 
+                "checkerplane" => {
+                    let values: Vec<f32> = elements.iter().map(|e| e.parse().unwrap()).collect();
+                    if values.len() != 11 {
+                        log_error!("checkerplane expects a b c d r1 g1 b1 r2 g2 b2 size, got {} arguments", values.len());
+                        std::process::exit(1);
+                    }
+                    let coeffs: MatVec<4> = MatVec::from_slice(&values[0..4]);
+                    let color_a: Color = MatVec::from_slice(&values[4..7]);
+                    let color_b: Color = MatVec::from_slice(&values[7..10]);
+                    log_trace!("Making checker plane with coeffs: {}, colors: {} {}, size: {}", coeffs, color_a, color_b, values[10]);
+                    let obj = Plane::checker(coeffs, color_a, color_b, values[10], &raytracer.input_state);
+                    raytracer.scene.add_shape(Box::new(obj));
+                },
+
+                "disk" => {
+                    let center = MatVec::from_array([elements[0].parse().unwrap(),
+                                                     elements[1].parse().unwrap(),
+                                                     elements[2].parse().unwrap()]);
+                    let normal = MatVec::from_array([elements[3].parse().unwrap(),
+                                                     elements[4].parse().unwrap(),
+                                                     elements[5].parse().unwrap()]);
+                    let radius: f32 = elements[6].parse().unwrap();
+                    log_trace!("Making disk with center: {}, radius: {}, color: {}", center, radius, raytracer.input_state.color);
+                    let obj = Disk::new(center, normal, radius, &raytracer.input_state);
+                    raytracer.scene.add_shape(Box::new(obj));
+                },
+
+                "torus" => {
+                    let center = MatVec::from_array([elements[0].parse().unwrap(),
+                                                     elements[1].parse().unwrap(),
+                                                     elements[2].parse().unwrap()]);
+                    let axis = MatVec::from_array([elements[3].parse().unwrap(),
+                                                   elements[4].parse().unwrap(),
+                                                   elements[5].parse().unwrap()]);
+                    let major: f32 = elements[6].parse().unwrap();
+                    let minor: f32 = elements[7].parse().unwrap();
+                    log_trace!("Making torus with center: {}, radii: {} {}, color: {}", center, major, minor, raytracer.input_state.color);
+                    let obj = Torus::new(center, axis, major, minor, &raytracer.input_state);
+                    raytracer.scene.add_shape(Box::new(obj));
+                },
+
+                "cone" => {
+                    let apex = MatVec::from_array([elements[0].parse().unwrap(),
+                                                   elements[1].parse().unwrap(),
+                                                   elements[2].parse().unwrap()]);
+                    let axis = MatVec::from_array([elements[3].parse().unwrap(),
+                                                   elements[4].parse().unwrap(),
+                                                   elements[5].parse().unwrap()]);
+                    let angle: f32 = elements[6].parse().unwrap();
+                    let height: f32 = elements[7].parse().unwrap();
+                    // The base is closed unless the cone is asked to be open
+                    let capped: bool = match elements.get(8).map(String::as_str) {
+                        None | Some("capped") => true,
+                        Some("open") => false,
+                        Some(other) => {
+                            log_error!("Invalid cone base: {}", other);
+                            std::process::exit(1);
+                        },
+                    };
+                    log_trace!("Making cone with apex: {}, axis: {}, angle: {}, height: {}, color: {}", apex, axis, angle, height, raytracer.input_state.color);
+                    let obj = Cone::new(apex, axis, angle, height, capped, &raytracer.input_state);
+                    raytracer.scene.add_shape(Box::new(obj));
+                },
+
+                "quadric" => {
+                    if elements.len() != 10 {
+                        log_error!("quadric needs 10 coefficients, got {}", elements.len());
+                        std::process::exit(1);
+                    }
+                    let coefficients: [f32; 10] = std::array::from_fn(|i| elements[i].parse().unwrap());
+                    log_trace!("Making quadric with coefficients: {:?}, color: {}", coefficients, raytracer.input_state.color);
+                    let obj = Quadric::new(coefficients, &raytracer.input_state);
+                    raytracer.scene.add_shape(Box::new(obj));
+                },
+
+                "aabb" => {
+                    let corner = MatVec::from_array([elements[0].parse().unwrap(),
+                                                     elements[1].parse().unwrap(),
+                                                     elements[2].parse().unwrap()]);
+                    let opposite = MatVec::from_array([elements[3].parse().unwrap(),
+                                                       elements[4].parse().unwrap(),
+                                                       elements[5].parse().unwrap()]);
+                    log_trace!("Making box from {} to {}, color: {}", corner, opposite, raytracer.input_state.color);
+                    let obj = Aabb::new(corner, opposite, &raytracer.input_state);
+                    raytracer.scene.add_shape(Box::new(obj));
+                },
+
+                "csg" => {
+                    let operation: CsgOperation = match elements[0].as_str() {
+                        "union" => CsgOperation::UNION,
+                        "intersection" => CsgOperation::INTERSECTION,
+                        "difference" => CsgOperation::DIFFERENCE,
+                        _ => {
+                            log_error!("Invalid CSG operation: {}", elements[0]);
+                            std::process::exit(1);
+                        }
+                    };
+                    // Combines the two most recently added shapes, the earlier one being the first operand
+                    let operands: Vec<ShapeId> = raytracer.scene.shape_ids().rev().take(2).collect();
+                    if operands.len() < 2 {
+                        log_error!("csg needs two shapes to combine");
+                        std::process::exit(1);
+                    }
+                    let right = raytracer.scene.remove_shape(operands[0]).unwrap();
+                    let left = raytracer.scene.remove_shape(operands[1]).unwrap();
+                    log_trace!("Making CSG {:?} of the last two shapes", operation);
+                    let obj = Csg::new(left, right, operation);
+                    raytracer.scene.add_shape(Box::new(obj));
+                },
+
                 "xyz" => {
-                    let vertex = MatVec::new(vec![elements[0].parse().unwrap(),
-                                                  elements[1].parse().unwrap(),
-                                                  elements[2].parse().unwrap()]);
+                    let vertex = MatVec::from_array([elements[0].parse().unwrap(),
+                                                     elements[1].parse().unwrap(),
+                                                     elements[2].parse().unwrap()]);
                     raytracer.input_state.verticies.push(vertex);
                 },
 
                 "tri" => {
                     let indices: Vec<i32> = elements.iter().map(|e| e.parse().unwrap()).collect();
+                    if raytracer.input_state.texture.is_some() && raytracer.input_state.texcoords.is_empty() {
+                        log_warn!("Triangle {:?} has a texture but no texcoords are defined, it is drawn with the flat color", indices);
+                    }
+                    log_trace!("Making triangle with vertices: {:?}, color: {}", indices, raytracer.input_state.color);
                     let obj = Triangle::new(indices, &raytracer.input_state);
+                    raytracer._add_triangle(obj);
+                },
+
+                "polygon" => {
+                    let indices: Vec<i32> = elements.iter().map(|e| e.parse().unwrap()).collect();
+                    if indices.len() < 3 {
+                        log_error!("polygon needs at least 3 vertex indices, got {}", indices.len());
+                        std::process::exit(1);
+                    }
+                    if raytracer.input_state.texture.is_some() && raytracer.input_state.texcoords.is_empty() {
+                        log_warn!("Polygon {:?} has a texture but no texcoords are defined, it is drawn with the flat color", indices);
+                    }
+                    log_trace!("Making polygon with vertices: {:?}, color: {}", indices, raytracer.input_state.color);
+                    // Fanned out from the first vertex, which is exact for convex polygons
+                    for k in 1..indices.len() - 1 {
+                        let obj = Triangle::new(vec![indices[0], indices[k], indices[k + 1]], &raytracer.input_state);
+                        raytracer._add_triangle(obj);
+                    }
+                },
+
+                "mesh" => {
+                    if let Some((ref name, _)) = raytracer.input_state.mesh_definition {
+                        log_error!("mesh {} started inside of mesh {}", elements[0], name);
+                        std::process::exit(1);
+                    }
+                    raytracer.input_state.mesh_definition = Some((elements[0].clone(), Vec::new()));
+                },
+
+                "end_mesh" => {
+                    match raytracer.input_state.mesh_definition.take() {
+                        Some((name, triangles)) => {
+                            log_info!("Defined mesh {} with {} triangles", name, triangles.len());
+                            raytracer.input_state.meshes.insert(name.clone(), Arc::new(Mesh::new(name, triangles)));
+                        },
+                        None => {
+                            log_error!("end_mesh without a matching mesh");
+                            std::process::exit(1);
+                        },
+                    }
+                },
+
+                "instance" => {
+                    let mesh: Arc<Mesh> = match raytracer.input_state.meshes.get(&elements[0]) {
+                        Some(mesh) => mesh.clone(),
+                        None => {
+                            log_error!("instance of undefined mesh: {}", elements[0]);
+                            std::process::exit(1);
+                        },
+                    };
+                    log_trace!("Making instance of mesh {}", elements[0]);
+                    let obj = Instance::new(mesh, &raytracer.input_state);
                     raytracer.scene.add_shape(Box::new(obj));
                 },
 
                 "bulb" => {
-                    let position = MatVec::new(vec![elements[0].parse().unwrap(),
-                                                    elements[1].parse().unwrap(),
-                                                    elements[2].parse().unwrap()]);
-                    let obj = Bulb::new(position, &raytracer.input_state);
+                    let position = MatVec::from_array([elements[0].parse().unwrap(),
+                                                       elements[1].parse().unwrap(),
+                                                       elements[2].parse().unwrap()]);
+                    // Optional intensity, scaling the color of the light
+                    let intensity: f32 = elements.get(3).map_or(1.0, |i| i.parse().unwrap());
+                    let obj = Bulb::new(position, intensity, &raytracer.input_state);
                     raytracer.scene.add_light_source(Box::new(obj));
                 },
 
+                "rectlight" => {
+                    let corner = MatVec::from_array([elements[0].parse().unwrap(),
+                                                     elements[1].parse().unwrap(),
+                                                     elements[2].parse().unwrap()]);
+                    let edge_u = MatVec::from_array([elements[3].parse().unwrap(),
+                                                     elements[4].parse().unwrap(),
+                                                     elements[5].parse().unwrap()]);
+                    let edge_v = MatVec::from_array([elements[6].parse().unwrap(),
+                                                     elements[7].parse().unwrap(),
+                                                     elements[8].parse().unwrap()]);
+                    let obj = RectLight::new(corner, edge_u, edge_v, &raytracer.input_state);
+                    raytracer.scene.add_light_source(Box::new(obj));
+                },
+
+                "reset" => {
+                    // Vertex colors are indexed like the vertices, so they are cleared with them
+                    match elements.first().map(String::as_str) {
+                        None => raytracer.input_state.reset(),
+                        Some("verts") => {
+                            raytracer.input_state.verticies.clear();
+                            raytracer.input_state.vertcolors.clear();
+                        },
+                        Some("texcoords") => raytracer.input_state.texcoords.clear(),
+                        Some(other) => {
+                            log_error!("Invalid reset target: {}", other);
+                            std::process::exit(1);
+                        },
+                    }
+                },
+
+                "push_transform" => {
+                    let current = raytracer.input_state.transform;
+                    raytracer.input_state.transform_stack.push(current);
+                },
+
+                "pop_transform" => {
+                    match raytracer.input_state.transform_stack.pop() {
+                        Some(transform) => raytracer.input_state.transform = transform,
+                        None => {
+                            log_error!("pop_transform without a matching push_transform");
+                            std::process::exit(1);
+                        },
+                    }
+                },
+
+                "translate" => {
+                    let offset: MatVec<3> = MatVec::from_array([elements[0].parse().unwrap(),
+                                                  elements[1].parse().unwrap(),
+                                                  elements[2].parse().unwrap()]);
+                    raytracer.input_state.transform = raytracer.input_state.transform.translate(&offset);
+                },
+
+                "rotate" => {
+                    let axis: MatVec<3> = MatVec::from_array([elements[0].parse().unwrap(),
+                                                  elements[1].parse().unwrap(),
+                                                  elements[2].parse().unwrap()]);
+                    let angle: f32 = elements[3].parse().unwrap();
+                    raytracer.input_state.transform = raytracer.input_state.transform.rotate(&axis, angle);
+                },
+
+                "scale" => {
+                    let factors: MatVec<3> = MatVec::from_array([elements[0].parse().unwrap(),
+                                                  elements[1].parse().unwrap(),
+                                                  elements[2].parse().unwrap()]);
+                    raytracer.input_state.transform = raytracer.input_state.transform.scale(&factors);
+                },
+
                 "texture" => {
-                    let texture: String = elements[0].clone();
-                    raytracer.input_state.texture = texture;
+                    let path: String = elements[0].clone();
+                    raytracer.input_state.texture = match path.as_str() {
+                        "none" => None,
+                        _ => {
+                            let texture = raytracer._load_texture(&path, line_number);
+                            Some(Arc::new(texture.with_wrap_mode(raytracer.input_state.wrap_mode).with_uv_transform(raytracer.input_state.uv_transform)))
+                        },
+                    };
+                },
+
+                "normalmap" => {
+                    let path: String = elements[0].clone();
+                    raytracer.input_state.normal_map = match path.as_str() {
+                        "none" => None,
+                        _ => {
+                            let normal_map = raytracer._load_texture(&path, line_number);
+                            Some(Arc::new(normal_map.with_wrap_mode(raytracer.input_state.wrap_mode).with_uv_transform(raytracer.input_state.uv_transform)))
+                        },
+                    };
+                },
+
+                "bumpmap" => {
+                    let path: String = elements[0].clone();
+                    raytracer.input_state.bump_map = match path.as_str() {
+                        "none" => None,
+                        _ => {
+                            let scale: f32 = match elements.get(1) {
+                                Some(scale) => scale.parse().unwrap(),
+                                None => {
+                                    log_error!("bumpmap expects a path and a scale");
+                                    std::process::exit(1);
+                                },
+                            };
+                            let bump_map = raytracer._load_texture(&path, line_number);
+                            Some((Arc::new(bump_map.with_wrap_mode(raytracer.input_state.wrap_mode).with_uv_transform(raytracer.input_state.uv_transform)), scale))
+                        },
+                    };
+                },
+
+                "backface_cull" => {
+                    raytracer.input_state.backface_cull = match elements[0].as_str() {
+                        "on" => true,
+                        "off" => false,
+                        other => {
+                            log_error!("Invalid backface_cull value: {}", other);
+                            std::process::exit(1);
+                        },
+                    };
+                },
+
+                "alpha_blend" => {
+                    raytracer.input_state.alpha_blend = match elements[0].as_str() {
+                        "on" => true,
+                        "off" => false,
+                        other => {
+                            log_error!("Invalid alpha_blend value: {}", other);
+                            std::process::exit(1);
+                        },
+                    };
+                },
+
+                "alpha_cutoff" => {
+                    raytracer.input_state.alpha_cutoff = match elements[0].as_str() {
+                        "none" => None,
+                        value => {
+                            let cutoff: f32 = value.parse().unwrap();
+                            if !(0.0..=1.0).contains(&cutoff) {
+                                log_error!("alpha_cutoff must be between 0 and 1, got {}", cutoff);
+                                std::process::exit(1);
+                            }
+                            Some(cutoff)
+                        },
+                    };
+                },
+
+                "background" => {
+                    let background: Color = MatVec::from_array([elements[0].parse().unwrap(),
+                                                  elements[1].parse().unwrap(),
+                                                  elements[2].parse().unwrap()]);
+                    raytracer.scene.background = Some(background);
+                },
+
+                "fog" => {
+                    let fog_color: Color = MatVec::from_array([elements[0].parse().unwrap(),
+                                                               elements[1].parse().unwrap(),
+                                                               elements[2].parse().unwrap()]);
+                    let density: f32 = elements[3].parse().unwrap();
+                    if density < 0.0 {
+                        log_error!("fog density must not be negative, got {}", density);
+                        std::process::exit(1);
+                    }
+                    raytracer.scene.fog = Some((fog_color, density));
+                },
+
+                "background_secondary" => {
+                    raytracer.scene.background_secondary = match elements[0].as_str() {
+                        "on" => true,
+                        "off" => false,
+                        other => {
+                            log_error!("Invalid background_secondary value: {}", other);
+                            std::process::exit(1);
+                        },
+                    };
+                },
+
+                "environment" => {
+                    let path: String = elements[0].clone();
+                    raytracer.scene.environment = match path.as_str() {
+                        "none" => None,
+                        _ => Some(Arc::new(raytracer._load_texture(&path, line_number).with_mipmaps())),
+                    };
+                },
+
+                "checker" => {
+                    let scale = MatVec::from_array([elements[0].parse().unwrap(),
+                                                  elements[1].parse().unwrap()]);
+                    let color_a = MatVec::from_array([elements[2].parse().unwrap(),
+                                                  elements[3].parse().unwrap(),
+                                                  elements[4].parse().unwrap()]);
+                    let color_b = MatVec::from_array([elements[5].parse().unwrap(),
+                                                  elements[6].parse().unwrap(),
+                                                  elements[7].parse().unwrap()]);
+                    raytracer.input_state.texture = Some(Arc::new(Texture2d::checker(scale, color_a, color_b).with_wrap_mode(raytracer.input_state.wrap_mode).with_uv_transform(raytracer.input_state.uv_transform)));
+                },
+
+                "gradient" => {
+                    let color_a = MatVec::from_array([elements[0].parse().unwrap(),
+                                                  elements[1].parse().unwrap(),
+                                                  elements[2].parse().unwrap()]);
+                    let color_b = MatVec::from_array([elements[3].parse().unwrap(),
+                                                  elements[4].parse().unwrap(),
+                                                  elements[5].parse().unwrap()]);
+                    raytracer.input_state.texture = Some(Arc::new(Texture2d::gradient(color_a, color_b).with_wrap_mode(raytracer.input_state.wrap_mode).with_uv_transform(raytracer.input_state.uv_transform)));
+                },
+
+                "texture_wrap" => {
+                    let wrap_mode = match elements[0].as_str() {
+                        "repeat" => WrapMode::REPEAT,
+                        "clamp" => WrapMode::CLAMP,
+                        "mirror" => WrapMode::MIRROR,
+                        other => {
+                            log_error!("Invalid texture wrap mode: {}", other);
+                            std::process::exit(1);
+                        },
+                    };
+                    raytracer.input_state.wrap_mode = wrap_mode;
+                    // Also applies to the currently selected texture
+                    if let Some(ref texture) = raytracer.input_state.texture {
+                        raytracer.input_state.texture = Some(Arc::new(texture.with_wrap_mode(wrap_mode)));
+                    }
+                },
+
+                "texture_transform" => {
+                    let uv_transform = match elements[0].as_str() {
+                        "none" => UvTransform::identity(),
+                        _ => {
+                            if elements.len() != 3 && elements.len() != 5 {
+                                log_error!("texture_transform expects sx sy angle [ox oy], got {} arguments", elements.len());
+                                std::process::exit(1);
+                            }
+                            let offset: MatVec<2> = if elements.len() == 5 {
+                                MatVec::from_array([elements[3].parse().unwrap(), elements[4].parse().unwrap()])
+                            } else {
+                                MatVec::from_array([0.0, 0.0])
+                            };
+                            UvTransform {
+                                scale: MatVec::from_array([elements[0].parse().unwrap(), elements[1].parse().unwrap()]),
+                                rotation: elements[2].parse::<f32>().unwrap().to_radians(),
+                                offset,
+                            }
+                        },
+                    };
+                    raytracer.input_state.uv_transform = uv_transform;
+                    // Also applies to the currently selected texture
+                    if let Some(ref texture) = raytracer.input_state.texture {
+                        raytracer.input_state.texture = Some(Arc::new(texture.with_uv_transform(uv_transform)));
+                    }
                 },
 
+                "vertcolor" => {
+                    let vertcolor = MatVec::from_array([elements[0].parse().unwrap(),
+                                                        elements[1].parse().unwrap(),
+                                                        elements[2].parse().unwrap()]);
+                    raytracer.input_state.vertcolors.push(vertcolor);
+                },
                 "texcoord" => {
-                    let texcoord = MatVec::new(vec![elements[0].parse().unwrap(),
+                    let texcoord = MatVec::from_array([elements[0].parse().unwrap(),
                                                   elements[1].parse().unwrap()]);
                     raytracer.input_state.texcoords.push(texcoord);
                 },
@@ -183,6 +949,26 @@ impl RayTracer {
                     let shine: Vec<f32> = elements.iter().map(|e| e.parse().unwrap()).collect();
                     raytracer.input_state.shininess = shine;
                 },
+                "specular" => {
+                    let exponent: f32 = elements[0].parse().unwrap();
+                    let specular: Color = MatVec::from_array([elements[1].parse().unwrap(),
+                                                  elements[2].parse().unwrap(),
+                                                  elements[3].parse().unwrap()]);
+                    raytracer.input_state.specular_exponent = exponent;
+                    raytracer.input_state.specular = Some(specular);
+                },
+                "emission" => {
+                    let emission: Color = MatVec::from_array([elements[0].parse().unwrap(),
+                                                   elements[1].parse().unwrap(),
+                                                   elements[2].parse().unwrap()]);
+                    // No emission at all keeps shapes exactly as they were
+                    raytracer.input_state.emission = if emission.iter().any(|&e| e != 0.0) { Some(emission) } else { None };
+                },
+                "clearcoat" => {
+                    let strength: f32 = elements[0].parse().unwrap();
+                    let roughness: f32 = elements[1].parse().unwrap();
+                    raytracer.input_state.clearcoat = if strength > 0.0 { Some((strength, roughness)) } else { None };
+                },
                 "bounces" => {
                     let bounces: u32 = elements[0].parse().unwrap();
                     raytracer.bounce_limit = bounces;
@@ -192,10 +978,20 @@ impl RayTracer {
                     raytracer.input_state.index_of_refraction = ior;
                 }
                 "transparency" => {
-                    todo!("Transparency not suppoerted");
                     let transparency: Vec<f32> = elements.iter().map(|e| e.parse().unwrap()).collect();
                     raytracer.input_state.transparency = transparency;
                 },
+                "velocity" => {
+                    let velocity = MatVec::from_array([elements[0].parse().unwrap(),
+                                                       elements[1].parse().unwrap(),
+                                                       elements[2].parse().unwrap()]);
+                    raytracer.input_state.velocity = velocity;
+                },
+                "shutter" => {
+                    let open: f32 = elements[0].parse().unwrap();
+                    let close: f32 = elements[1].parse().unwrap();
+                    raytracer.shutter = (open, close);
+                },
                 "roughness" => {
                     let roughness: f32 = elements[0].parse().unwrap();
                     raytracer.input_state.roughness = roughness;
@@ -203,18 +999,155 @@ impl RayTracer {
                 "aa" => {
                     let aa: u32 = elements[0].parse().unwrap();
                     raytracer.anti_aliasing = aa;
+                    // Area lights reuse the anti-aliasing count for their shadow samples
+                    raytracer.scene.light_samples = aa;
+                },
+                "aa_mode" => {
+                    raytracer.aa_mode = match elements[0].as_str() {
+                        "random" => SampleMode::RANDOM,
+                        "grid" => SampleMode::GRID,
+                        "stratified" => SampleMode::STRATIFIED,
+                        other => {
+                            log_error!("Invalid anti-aliasing mode: {}", other);
+                            std::process::exit(1);
+                        },
+                    };
+                },
+                "aa_threshold" => {
+                    raytracer.aa_threshold = match elements[0].as_str() {
+                        "none" => None,
+                        value => {
+                            let threshold: f32 = value.parse().unwrap();
+                            if threshold < 0.0 {
+                                log_error!("aa_threshold must not be negative, got {}", threshold);
+                                std::process::exit(1);
+                            }
+                            Some(threshold)
+                        },
+                    };
                 },
                 "dof" => {
                     let focal_length: f32 = elements[0].parse().unwrap();
                     let aperture: f32 = elements[1].parse().unwrap();
-                    raytracer.camera.dof = Some(MatVec::new(vec![focal_length, aperture]));
+                    raytracer.camera.dof = Some(MatVec::from_array([focal_length, aperture]));
+                },
+                "ao" => {
+                    let samples: u32 = elements[0].parse().unwrap();
+                    let radius: f32 = elements[1].parse().unwrap();
+                    raytracer.scene.ao_samples = samples;
+                    raytracer.scene.ao_radius = radius;
+                },
+                "focus" => {
+                    let point: MatVec<3> = MatVec::from_array([elements[0].parse().unwrap(),
+                                                               elements[1].parse().unwrap(),
+                                                               elements[2].parse().unwrap()]);
+                    raytracer.camera.focus_point = Some(point);
+                },
+                "clip" => {
+                    let near: f32 = elements[0].parse().unwrap();
+                    let far: f32 = elements[1].parse().unwrap();
+                    if near < 0.0 || far <= near {
+                        log_error!("clip needs 0 <= near < far, got {} {}", near, far);
+                        std::process::exit(1);
+                    }
+                    raytracer.camera.clip = (near, far);
+                },
+                "dof_samples" => {
+                    let samples: u32 = elements[0].parse().unwrap();
+                    raytracer.camera.dof_samples = samples;
+                },
+                "crop" => {
+                    let bounds: Vec<u32> = elements.iter().map(|e| e.parse().unwrap()).collect();
+                    // Clamp the region to the image, so it can never index outside of it
+                    let x0 = bounds[0].min(raytracer.width);
+                    let y0 = bounds[1].min(raytracer.height);
+                    let x1 = bounds[2].clamp(x0, raytracer.width);
+                    let y1 = bounds[3].clamp(y0, raytracer.height);
+                    raytracer.crop = Some((x0, y0, x1, y1));
+                },
+                "bloom" => {
+                    let threshold: f32 = elements[0].parse().unwrap();
+                    let intensity: f32 = elements[1].parse().unwrap();
+                    let radius: f32 = elements[2].parse().unwrap();
+                    if intensity < 0.0 || radius <= 0.0 {
+                        log_error!("Bloom needs a non-negative intensity and a positive radius, got {} and {}", intensity, radius);
+                        std::process::exit(1);
+                    }
+                    raytracer.bloom = Some((threshold, intensity, radius));
+                },
+                "output" => {
+                    raytracer.output = match elements[0].as_str() {
+                        "beauty" => OutputPass::BEAUTY,
+                        "depth" => OutputPass::DEPTH,
+                        "normal" => OutputPass::NORMAL,
+                        "rays" => OutputPass::RAYS,
+                        "wireframe" => {
+                            raytracer.scene.wireframe = true;
+                            // The faces are shaded unless they are asked to be see-through
+                            raytracer.scene.wireframe_fill = match elements.get(1).map(String::as_str) {
+                                None | Some("fill") => true,
+                                Some("transparent") => false,
+                                Some(other) => {
+                                    log_error!("Invalid wireframe fill: {}", other);
+                                    std::process::exit(1);
+                                },
+                            };
+                            OutputPass::WIREFRAME
+                        },
+                        other => {
+                            log_error!("Invalid output pass: {}", other);
+                            std::process::exit(1);
+                        },
+                    };
+                },
+                "bias" => {
+                    let bias: f32 = elements[0].parse().unwrap();
+                    raytracer.scene.bias = Some(bias);
+                },
+                "seed" => {
+                    let seed: u64 = elements[0].parse().unwrap();
+                    raytracer.seed = Some(seed);
+                },
+                "threads" => {
+                    let threads: usize = elements[0].parse().unwrap();
+                    raytracer.threads = threads;
+                },
+                "tile_size" => {
+                    let tile_size: u32 = elements[0].parse().unwrap();
+                    if tile_size == 0 {
+                        log_error!("tile_size must be at least 1");
+                        std::process::exit(1);
+                    }
+                    raytracer.tile_size = tile_size;
                 },
                 "gi" => {
                     let gi: u32 = elements[0].parse().unwrap();
                     raytracer.scene.gi_depth = gi;
                 }
+                "gi_rr_depth" => {
+                    let rr_depth: u32 = elements[0].parse().unwrap();
+                    raytracer.scene.gi_rr_depth = rr_depth;
+                }
+                "gi_nee" => {
+                    raytracer.scene.gi_nee = match elements[0].as_str() {
+                        "on" => true,
+                        "off" => false,
+                        other => {
+                            log_error!("Invalid gi_nee value: {}", other);
+                            std::process::exit(1);
+                        },
+                    };
+                }
+                "gi_samples" => {
+                    let samples: u32 = elements[0].parse().unwrap();
+                    if samples == 0 {
+                        log_error!("gi_samples must be at least 1");
+                        std::process::exit(1);
+                    }
+                    raytracer.scene.gi_samples = samples;
+                }
                 _ => {
-                    println!("Invalid action: {}", action);
+                    log_error!("Invalid action: {}", action);
                     std::process::exit(1);
                 },
 
@@ -222,84 +1155,539 @@ impl RayTracer {
 
         }    
 
-        raytracer.render();
-        raytracer.save_image(out_file);
+        if let Some((ref name, _)) = raytracer.input_state.mesh_definition {
+            log_error!("mesh {} is missing its end_mesh", name);
+            std::process::exit(1);
+        }
+
+        // The camera is aimed once the whole file is read, so `lookat` follows the final eye wherever it is given.
+        // The length of `forward`, which sets the field of view of the flat projection, is kept
+        if let Some(target) = raytracer.camera.look_at {
+            match (target - raytracer.camera.eye).try_normalize() {
+                Some(direction) => raytracer.camera.forward = raytracer.camera.forward.magnitude() * direction,
+                None => {
+                    log_error!("lookat target {} is at the eye", target);
+                    std::process::exit(1);
+                },
+            }
+        }
+
+        if let Err(errors) = raytracer.scene.validate() {
+            for error in &errors {
+                log_error!("Invalid scene: {}", error);
+            }
+            std::process::exit(1);
+        }
+
+        // Camera rays skip the shapes that cannot be on screen, the camera is final once the whole file is read
+        if let Some(frustum) = raytracer.camera.frustum() {
+            let culled: usize = raytracer.scene.cull_to_frustum(&frustum);
+            log_info!("Culled {} shapes outside of the view", culled);
+        }
+
+        (raytracer, out_file)
 
     }
 
-    pub fn render(&mut self) -> bool {
-        for x in 0..self.width {
+    /// Renders the selected output pass into the image, and returns how much work it took.
+    /// With a crop region only the pixels inside of it are rendered, and the rest are left transparent.
+    pub fn render(&mut self) -> RenderStats {
 
-            for y in 0..self.height {
-                
-                // let ray = Ray::generate_primary_ray(MatVec::new(vec![x as f32, y as f32]), &self.camera);
-                // // print!("Ray: {:?}", ray);
-                // // It is ok to do this because a valid ray will always have a normalized direction
-                // if ray.direction.eq(MatVec::new(vec![0.0, 0.0, 0.0])) {
-                //     continue;
-                // }
+        let (x0, y0, rows, stats) = self._trace_pixels();
 
-                // let mut pixel_color: RGBA = self.scene.trace_ray(&ray, self.bounce_limit);
-                let pixel_color_optional: Option<RGBA> = self._compute_pixel_value(x, y, self.bounce_limit);
+        // An explicit exposure always wins over the automatic one
+        let exposure: Option<f32> = self.camera.exposure.or_else(|| self._auto_exposure(&rows));
 
-                if pixel_color_optional.is_none() {
-                    continue;
+        // Depth is normalized by the farthest hit, and ray counts by the largest count, so the whole range is visible
+        let max_value: f32 = rows.iter().flatten().flatten().map(|pixel_color| pixel_color[0]).fold(0.0, f32::max);
+
+        for (y, row) in rows.iter().enumerate() {
+            for (x, pixel_color) in row.iter().enumerate() {
+                // Pixels without a ray are left transparent
+                if let Some(pixel_color) = pixel_color {
+                    let output_color: RGBA = match self.output {
+                        OutputPass::BEAUTY | OutputPass::WIREFRAME => self._output_color(*pixel_color, exposure),
+                        OutputPass::DEPTH if max_value > 0.0 => utils::color_to_rgba((1.0 / max_value) * utils::rgba_to_color(*pixel_color), 1.0),
+                        OutputPass::DEPTH | OutputPass::NORMAL => pixel_color.clamp(0.0, 1.0),
+                        OutputPass::RAYS => utils::color_to_rgba(utils::heat_ramp(pixel_color[0] / max_value.max(1.0)), 1.0),
+                    };
+                    self.image.put_pixel(x0 + x as u32, y0 + y as u32, output_color.to_rgba());
+                }
+            }
+        }
+
+        stats
+
+    }
+
+    /// Renders the scene and returns the raw linear color of every pixel, row by row,
+    /// before tone mapping, gamma correction and quantization, so values outside of `[0, 1]` are kept.
+    /// For the depth pass the distances, and for the rays pass the counts, are not normalized.
+    /// Pixels without a ray, or outside the crop region, are transparent black.
+    /// Colors are traced premultiplied, and with straight alpha they are unpremultiplied, see `AlphaMode`.
+    pub fn render_to_f32_buffer(&self) -> Vec<RGBA> {
+
+        let mut buffer: Vec<RGBA> = vec![MatVec::zero(); (self.width * self.height) as usize];
+
+        let (x0, y0, rows, _) = self._trace_pixels();
+
+        for (y, row) in rows.iter().enumerate() {
+            for (x, pixel_color) in row.iter().enumerate() {
+                if let Some(pixel_color) = pixel_color {
+                    let straight: bool = matches!(self.alpha_mode, AlphaMode::STRAIGHT) && matches!(self.output, OutputPass::BEAUTY | OutputPass::WIREFRAME);
+                    buffer[((y0 as usize + y) * self.width as usize) + x0 as usize + x] = if straight { utils::unpremultiply(pixel_color) } else { *pixel_color };
                 }
+            }
+        }
+
+        buffer
 
-                let mut pixel_color = pixel_color_optional.unwrap();
+    }
+
+    /// Traces every pixel of the crop region, or of the whole image without one.
+    /// Returns the corner of the region, the linear colors of its rows, `None` for pixels without a ray,
+    /// and the work it took, which is also logged. The counters of the scene are reset first, so renders of the
+    /// same ray tracer must not overlap.
+    /// The region is split into square tiles of `tile_size` pixels, which are traced in parallel on a pool of
+    /// `threads` threads, so neighbouring rays, which tend to visit the same shapes, run close together in time.
+    /// Every pixel gets its own random number generator, seeded from `seed` and the pixel coordinates,
+    /// so a seeded render is reproducible regardless of the thread count, tile size or crop region.
+    fn _trace_pixels(&self) -> (u32, u32, Vec<Vec<Option<RGBA>>>, RenderStats) {
+
+        let start = Instant::now();
+        self.scene.counters.reset();
 
-                if self.camera.exposure.is_some() {
+        let (x0, y0, x1, y1) = self.crop.unwrap_or((0, 0, self.width, self.height));
+        let seed: u64 = self.seed.unwrap_or_else(|| rand::thread_rng().gen());
 
-                    pixel_color = utils::appy_exposure(&pixel_color, self.camera.exposure.unwrap());
+        // A thread count of 0 lets rayon use all cores
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()
+            .expect("Failed to build the render thread pool");
 
+        let tile_size: u32 = self.tile_size.max(1);
+        let tiles: Vec<(u32, u32)> = (y0..y1).step_by(tile_size as usize)
+            .flat_map(|tile_y| (x0..x1).step_by(tile_size as usize).map(move |tile_x| (tile_x, tile_y)))
+            .collect();
+        let done = AtomicUsize::new(0);
+
+        let traced: Vec<_> = pool.install(|| {
+            tiles.par_iter().map(|&(tile_x, tile_y)| {
+                let pixels: Vec<(Option<RGBA>, u32)> = (tile_y..(tile_y + tile_size).min(y1))
+                    .flat_map(|y| (tile_x..(tile_x + tile_size).min(x1)).map(move |x| (x, y)))
+                    .map(|(x, y)| {
+                        let mut rng = StdRng::seed_from_u64(seed ^ ((y as u64) << 32 | x as u64));
+                        let mut rays: u32 = 0;
+                        let pixel: Option<RGBA> = match self.output {
+                            OutputPass::BEAUTY | OutputPass::RAYS | OutputPass::WIREFRAME => self._compute_pixel_value(x, y, self.bounce_limit, &mut rays, &mut rng),
+                            OutputPass::DEPTH | OutputPass::NORMAL => self._compute_aov_value(x, y, &mut rng),
+                        };
+                        (pixel, rays)
+                    })
+                    .collect();
+
+                // Reports every tenth of the tiles, in whichever order they finish
+                let finished: usize = done.fetch_add(1, Ordering::Relaxed) + 1;
+                if finished * 10 / tiles.len() != (finished - 1) * 10 / tiles.len() {
+                    log_info!("Rendered {} of {} tiles", finished, tiles.len());
                 }
 
-                #[allow(non_snake_case)]
-                let sRGB: RGBA = utils::sRGB(&pixel_color);
-                // let sRGB = pixel_color;
+                ((tile_x, tile_y), pixels)
+            }).collect()
+        });
 
-                self.image.put_pixel(x, y, sRGB.to_rgba());
+        let mut rows: Vec<Vec<Option<RGBA>>> = vec![vec![None; (x1 - x0) as usize]; (y1 - y0) as usize];
+        let mut ray_counts: Vec<Vec<u32>> = vec![vec![0; (x1 - x0) as usize]; (y1 - y0) as usize];
+        for ((tile_x, tile_y), pixels) in traced {
+            let tile_width: usize = ((tile_x + tile_size).min(x1) - tile_x) as usize;
+            for (i, (pixel, rays)) in pixels.into_iter().enumerate() {
+                rows[(tile_y - y0) as usize + i / tile_width][(tile_x - x0) as usize + i % tile_width] = pixel;
+                ray_counts[(tile_y - y0) as usize + i / tile_width][(tile_x - x0) as usize + i % tile_width] = rays;
+            }
+        }
+
+        let rows: Vec<Vec<Option<RGBA>>> = if self._adaptive() {
+            pool.install(|| self._refine_pixels(x0, y0, &rows, &mut ray_counts, seed))
+        } else {
+            rows
+        };
 
+        let rows: Vec<Vec<Option<RGBA>>> = if !matches!(self.output, OutputPass::RAYS) {
+            match self.bloom {
+                Some(bloom) if matches!(self.output, OutputPass::BEAUTY) => RayTracer::_apply_bloom(rows, bloom),
+                _ => rows,
             }
+        } else {
+            // The rays pass keeps the count of every pixel in all three color channels, pixels without a ray stay empty
+            rows.iter().zip(ray_counts.iter())
+                .map(|(row, counts)| row.iter().zip(counts.iter())
+                    .map(|(pixel, &rays)| pixel.map(|_| MatVec::from_array([rays as f32, rays as f32, rays as f32, 1.0])))
+                    .collect())
+                .collect()
+        };
+
+        let counters = &self.scene.counters;
+        let stats = RenderStats {
+            camera_rays: counters.camera_rays.load(Ordering::Relaxed),
+            rays: counters.rays.load(Ordering::Relaxed),
+            bounces: counters.bounces.load(Ordering::Relaxed),
+            intersection_tests: counters.intersection_tests.load(Ordering::Relaxed),
+            time: start.elapsed(),
+        };
+        log_info!(
+            "Rendered in {:.2?}: {} camera rays, {} rays in total, {:.2} bounces per camera ray, {} intersection tests",
+            stats.time, stats.camera_rays, stats.rays, stats.average_bounces(), stats.intersection_tests
+        );
 
+        (x0, y0, rows, stats)
+
+    }
+
+    /// Applies tone mapping and the output color space to a traced color, and clamps it to `[0, 1]`.
+    /// Tone mapping works on the unpremultiplied color, see `AlphaMode`.
+    fn _output_color(&self, pixel_color: RGBA, exposure: Option<f32>) -> RGBA {
+
+        let pixel_color: RGBA = utils::unpremultiply(&pixel_color);
+
+        let pixel_color: RGBA = match self.tone_mapping {
+            ToneMapping::EXPOSURE => match exposure {
+                Some(exposure) => utils::appy_exposure(&pixel_color, exposure),
+                None => pixel_color,
+            },
+            ToneMapping::REINHARD => utils::apply_reinhard(&pixel_color),
+            ToneMapping::ACES => utils::apply_aces(&pixel_color),
+        };
+
+        // Linear output skips the gamma correction, but is still clamped and quantized
+        let output_color: RGBA = match self.color_space {
+            ColorSpace::SRGB => utils::sRGB(&pixel_color),
+            ColorSpace::LINEAR => pixel_color,
+        };
+
+        let output_color: RGBA = output_color.clamp(0.0, 1.0);
+
+        match self.alpha_mode {
+            AlphaMode::STRAIGHT => output_color,
+            AlphaMode::PREMULTIPLIED => utils::premultiply(&output_color),
         }
 
-        return true;
+    }
+
+    /// Adds the glow of the bright parts of the traced image, for the `bloom` directive.
+    /// The part of every color whose luminance lies above `threshold` is blurred by a Gaussian with a standard
+    /// deviation of `radius` pixels, separably along the rows and then the columns, and added back scaled by `intensity`.
+    /// Pixels without a ray neither glow nor receive any. The glow over a transparent background makes it as opaque
+    /// as the glow is bright, so that it is seen in the written image.
+    fn _apply_bloom(rows: Vec<Vec<Option<RGBA>>>, (threshold, intensity, radius): (f32, f32, f32)) -> Vec<Vec<Option<RGBA>>> {
+
+        let height: usize = rows.len();
+        let width: usize = rows.first().map_or(0, |row| row.len());
+
+        // The kernel reaches three standard deviations out, beyond which it is negligible
+        let reach: i64 = (3.0 * radius).ceil() as i64;
+        let kernel: Vec<f32> = (-reach..=reach).map(|offset| (-(offset * offset) as f32 / (2.0 * radius * radius)).exp()).collect();
+        let kernel_sum: f32 = kernel.iter().sum();
+        let kernel: Vec<f32> = kernel.iter().map(|weight| weight / kernel_sum).collect();
+
+        // Scaling the whole color keeps the hue of the bright part
+        let bright: Vec<Vec<Color>> = rows.iter().map(|row| row.iter().map(|pixel_color| match pixel_color {
+            Some(pixel_color) => {
+                let color: Color = utils::rgba_to_color(*pixel_color);
+                let luminance: f32 = utils::luminance(&color);
+                if luminance > threshold { ((luminance - threshold) / luminance) * color } else { MatVec::zero() }
+            },
+            None => MatVec::zero(),
+        }).collect()).collect();
+
+        // Pixels past the edges count as black
+        let blur = |source: &Vec<Vec<Color>>, horizontal: bool| -> Vec<Vec<Color>> {
+            (0..height).map(|y| (0..width).map(|x| {
+                kernel.iter().enumerate().fold(MatVec::zero(), |sum: Color, (i, weight)| {
+                    let offset: i64 = i as i64 - reach;
+                    let (sample_x, sample_y): (i64, i64) = if horizontal { (x as i64 + offset, y as i64) } else { (x as i64, y as i64 + offset) };
+                    if sample_x < 0 || sample_y < 0 || sample_x >= width as i64 || sample_y >= height as i64 {
+                        sum
+                    } else {
+                        sum + *weight * source[sample_y as usize][sample_x as usize]
+                    }
+                })
+            }).collect()).collect()
+        };
+        let glow: Vec<Vec<Color>> = blur(&blur(&bright, true), false);
+
+        rows.into_iter().zip(glow).map(|(row, glow_row)| row.into_iter().zip(glow_row).map(|(pixel_color, glow)| {
+            pixel_color.map(|pixel_color| {
+                let glow: Color = intensity * glow;
+                let alpha: f32 = pixel_color[3].max(glow.iter().cloned().fold(0.0, f32::max).min(1.0));
+                utils::color_to_rgba(utils::rgba_to_color(pixel_color) + glow, alpha)
+            })
+        }).collect()).collect()
 
     }
 
+    /// Picks the exposure that maps the luminance statistic chosen by `autoexpose` to its target,
+    /// through the `1 - exp(-exposure * luminance)` curve of `utils::appy_exposure`.
+    /// Pixels where nothing was hit are ignored, so an empty background does not brighten the image.
+    /// Returns `None` without `autoexpose`, or when the image is black.
+    fn _auto_exposure(&self, rows: &[Vec<Option<RGBA>>]) -> Option<f32> {
 
-    fn _compute_pixel_value(&self, pixel_x: u32, pixel_y: u32, bounce_limit: u32) -> Option<RGBA> {
+        let (mode, target) = self.auto_exposure?;
 
-        if self.anti_aliasing == 0 {
-            let ray = Ray::generate_primary_ray(MatVec::new(vec![pixel_x as f32, pixel_y as f32]), &self.camera);
-            
-            if ray.direction.eq(MatVec::new(vec![0.0, 0.0, 0.0])) {
-                return None;
-            }
+        let luminances: Vec<f32> = rows.iter()
+            .flatten()
+            .flatten()
+            .filter(|pixel_color| pixel_color[3] > 0.0)
+            .map(|pixel_color| utils::luminance(&utils::rgba_to_color(*pixel_color)))
+            .collect();
+
+        if luminances.is_empty() {
+            return None;
+        }
+
+        let key: f32 = match mode {
+            AutoExposure::AVERAGE => {
+                // Offset so that black pixels do not send the logarithm to minus infinity
+                let log_sum: f32 = luminances.iter().map(|luminance| (luminance + 1e-4).ln()).sum();
+                (log_sum / luminances.len() as f32).exp()
+            },
+            AutoExposure::MAX => luminances.iter().cloned().fold(0.0, f32::max),
+        };
 
-            return Some(self.scene.trace_ray(&ray, bounce_limit));
+        if key <= 1e-4 {
+            return None;
         }
 
-        let mut pixel_color: RGBA = MatVec::new(vec![0.0, 0.0, 0.0, 0.0]);
+        let exposure: f32 = -(1.0 - target).ln() / key;
+
+        log_info!("Auto exposure: {:?} luminance {} mapped to {} with exposure {}", mode, key, target, exposure);
+
+        Some(exposure)
+
+    }
+
+    /// Traces a single ray through the center of the pixel for the depth or normal pass.
+    /// Depth is stored in all three color channels, and normals are encoded into `[0, 1]`.
+    fn _compute_aov_value(&self, pixel_x: u32, pixel_y: u32, rng: &mut StdRng) -> Option<RGBA> {
+
+        let ray = Ray::generate_primary_ray(MatVec::from_array([pixel_x as f32, pixel_y as f32]), &self.camera, rng)?;
+
+        let value: Color = match self.output {
+            OutputPass::DEPTH => {
+                let depth: f32 = self.scene.trace_depth(&ray)?;
+                MatVec::from_array([depth, depth, depth])
+            },
+            _ => {
+                let normal: MatVec<3> = self.scene.trace_normal(&ray)?;
+                0.5 * normal + MatVec::from_array([0.5, 0.5, 0.5])
+            },
+        };
+
+        Some(utils::color_to_rgba(value, 1.0))
+
+    }
+
+    fn _compute_pixel_value(&self, pixel_x: u32, pixel_y: u32, bounce_limit: u32, rays: &mut u32, rng: &mut StdRng) -> Option<RGBA> {
+
+        // Adaptive anti-aliasing starts from a single sample, see `_refine_pixels`
+        if self.anti_aliasing == 0 || self._adaptive() {
+            let time: f32 = self._sample_time(rng);
+            return self._sample_lens(pixel_x as f32, pixel_y as f32, time, bounce_limit, rays, rng);
+        }
+
+        let (pixel_color, num_rays) = self._accumulate_samples(pixel_x, pixel_y, bounce_limit, rays, rng);
+
+        RayTracer::_average(pixel_color, num_rays)
+
+    }
+
+    /// Sums `anti_aliasing` samples spread over the pixel, and returns the sum and the number of samples that had a ray.
+    /// Every ray cast into the scene for the samples is added to `rays`.
+    fn _accumulate_samples(&self, pixel_x: u32, pixel_y: u32, bounce_limit: u32, rays: &mut u32, rng: &mut StdRng) -> (RGBA, u32) {
+
+        let mut pixel_color: RGBA = MatVec::zero();
         let mut num_rays: u32 = 0;
-        for _throw in 0..self.anti_aliasing {
+        for throw in 0..self.anti_aliasing {
+
+            let (offset_x, offset_y) = self._sample_offset(throw, rng);
+            let x = pixel_x as f32 + offset_x;
+            let y = pixel_y as f32 + offset_y;
 
-            let x = pixel_x as f32 + rand::thread_rng().gen_range(-0.5_f32..0.5_f32);
-            let y = pixel_y as f32 + rand::thread_rng().gen_range(-0.5_f32..0.5_f32);
+            let time: f32 = self._sample_time(rng);
 
-            let ray = Ray::generate_primary_ray(MatVec::new(vec![x, y]), &self.camera);
-            if ray.direction.eq(MatVec::new(vec![0.0, 0.0, 0.0])) {
+            let sample_color: Option<RGBA> = self._sample_lens(x, y, time, bounce_limit, rays, rng);
+            if sample_color.is_none() {
                 continue;
             }
             num_rays += 1;
-            pixel_color = pixel_color + self.scene.trace_ray(&ray, bounce_limit);
+            pixel_color = pixel_color + sample_color.unwrap();
         }
 
-        Some(MatVec::new(vec![pixel_color[0] / num_rays as f32,
-                        pixel_color[1] / num_rays as f32,
-                        pixel_color[2] / num_rays as f32,
-                        pixel_color[3] / num_rays as f32]))
+        (pixel_color, num_rays)
+
+    }
+
+    /// Divides a sum of samples by their count.
+    /// None of the sub-samples had a ray when the count is 0, so the pixel is left transparent.
+    fn _average(pixel_color: RGBA, num_rays: u32) -> Option<RGBA> {
+
+        if num_rays == 0 {
+            return None;
+        }
+
+        Some(MatVec::from_array(std::array::from_fn(|i| pixel_color[i] / num_rays as f32)))
+
+    }
+
+    /// Whether anti-aliasing is adaptive, which only applies to the beauty pass, and to the rays pass that profiles it.
+    fn _adaptive(&self) -> bool {
+        self.aa_threshold.is_some() && self.anti_aliasing > 0 && matches!(self.output, OutputPass::BEAUTY | OutputPass::RAYS | OutputPass::WIREFRAME)
+    }
+
+    /// Second pass of adaptive anti-aliasing, over the single sample per pixel of the first pass.
+    /// A pixel that differs from any of its neighbours by more than `aa_threshold` in any linear channel
+    /// gets `anti_aliasing` more samples, which are averaged together with the first one.
+    /// Pixels without a ray count as transparent black, so the edges of the image plane are refined too.
+    /// The rays cast for the extra samples are added to the counts of their pixels in `ray_counts`.
+    fn _refine_pixels(&self, x0: u32, y0: u32, rows: &[Vec<Option<RGBA>>], ray_counts: &mut [Vec<u32>], seed: u64) -> Vec<Vec<Option<RGBA>>> {
+
+        let threshold: f32 = self.aa_threshold.unwrap_or(0.0);
+        let value = |pixel: &Option<RGBA>| pixel.unwrap_or(MatVec::zero());
+
+        let needs_samples = |j: usize, i: usize| {
+            let center: RGBA = value(&rows[j][i]);
+            let neighbours = (j.saturating_sub(1)..(j + 2).min(rows.len()))
+                .flat_map(|n| (i.saturating_sub(1)..(i + 2).min(rows[n].len())).map(move |m| (n, m)));
+            let contrast: f32 = neighbours
+                .map(|(n, m)| (value(&rows[n][m]) - center).iter().fold(0.0, |max: f32, c| max.max(c.abs())))
+                .fold(0.0, f32::max);
+            contrast > threshold
+        };
+
+        // Counting is a full extra pass over the pixels, so it is skipped when the message is not shown
+        if log::level() >= LogLevel::INFO {
+            let total: usize = rows.iter().map(|row| row.len()).sum();
+            let count: usize = (0..rows.len()).map(|j| (0..rows[j].len()).filter(|&i| needs_samples(j, i)).count()).sum();
+            log_info!("Adaptive anti-aliasing refines {} of {} pixels", count, total);
+        }
+
+        let refined: Vec<Vec<(Option<RGBA>, u32)>> = (0..rows.len()).into_par_iter().map(|j| {
+            (0..rows[j].len()).map(|i| {
+
+                let first: Option<RGBA> = rows[j][i];
+
+                if !needs_samples(j, i) {
+                    return (first, 0);
+                }
+
+                // A separate stream from the first pass, the constant keeps it from matching the stream of another pixel
+                let (x, y) = (x0 + i as u32, y0 + j as u32);
+                let mut rng = StdRng::seed_from_u64(seed ^ 0x9E37_79B9_7F4A_7C15 ^ ((y as u64) << 32 | x as u64));
+                let mut rays: u32 = 0;
+                let (pixel_color, num_rays) = self._accumulate_samples(x, y, self.bounce_limit, &mut rays, &mut rng);
+
+                let pixel: Option<RGBA> = match first {
+                    Some(first) => RayTracer::_average(pixel_color + first, num_rays + 1),
+                    None => RayTracer::_average(pixel_color, num_rays),
+                };
+                (pixel, rays)
+
+            }).collect()
+        }).collect();
+
+        refined.into_iter().zip(ray_counts.iter_mut())
+            .map(|(row, counts)| row.into_iter().zip(counts.iter_mut())
+                .map(|((pixel, rays), count)| {
+                    *count += rays;
+                    pixel
+                })
+                .collect())
+            .collect()
+
+    }
+
+    /// Offset from the pixel center of the anti-aliasing sample with the given index, in `[-0.5, 0.5)`.
+    /// The grid modes use the largest square grid with at most `anti_aliasing` cells, one sample per cell,
+    /// and any samples left over after filling it are placed at random.
+    fn _sample_offset(&self, index: u32, rng: &mut StdRng) -> (f32, f32) {
+
+        let cells: u32 = (self.anti_aliasing as f32).sqrt() as u32;
+
+        if matches!(self.aa_mode, SampleMode::RANDOM) || index >= cells * cells {
+            return (rng.gen_range(-0.5_f32..0.5_f32), rng.gen_range(-0.5_f32..0.5_f32));
+        }
+
+        let jitter: (f32, f32) = match self.aa_mode {
+            SampleMode::STRATIFIED => (rng.gen(), rng.gen()),
+            _ => (0.5, 0.5),
+        };
+
+        let cell_x: f32 = (index % cells) as f32;
+        let cell_y: f32 = (index / cells) as f32;
+
+        ((cell_x + jitter.0) / cells as f32 - 0.5, (cell_y + jitter.1) / cells as f32 - 0.5)
+
+    }
+
+    /// Picks a random time within the shutter interval.
+    /// Without an open shutter no random number is drawn, so static renders are unaffected.
+    fn _sample_time(&self, rng: &mut StdRng) -> f32 {
+
+        let (open, close) = self.shutter;
+        if close > open {
+            rng.gen_range(open..close)
+        } else {
+            open
+        }
+
+    }
+
+    /// Averages the color of `dof_samples` rays through a single position on the image plane,
+    /// each starting from a different point on the lens. Without depth of field every ray
+    /// would be identical, so only a single ray is traced.
+    fn _sample_lens(&self, x: f32, y: f32, time: f32, bounce_limit: u32, rays: &mut u32, rng: &mut StdRng) -> Option<RGBA> {
+
+        let num_samples: u32 = if self.camera.dof.is_some() { self.camera.dof_samples.max(1) } else { 1 };
+
+        let mut color: RGBA = MatVec::zero();
+        for _sample in 0..num_samples {
+
+            let ray = Ray::generate_primary_ray(MatVec::from_array([x, y]), &self.camera, rng)?.at_time(time);
+
+            color = color + self.scene.trace_ray(&ray, bounce_limit, rays, rng);
+        }
+
+        Some((1.0 / num_samples as f32) * color)
+
+    }
+
+    /// Renders the scene and returns the image, without writing it to disk, along with the work it took.
+    /// Meant for comparing renders in memory, eg against reference images.
+    pub fn render_to_buffer(&mut self) -> (&RgbaImage, RenderStats) {
+
+        let stats: RenderStats = self.render();
+        (&self.image, stats)
+
+    }
+
+    /// Writes the scene, its shapes, lights and global settings, in any serde format, eg JSON or RON.
+    /// The camera and the render settings are not part of it. Image textures are written as the files they were
+    /// decoded from. Textures and meshes are written once, in tables ahead of the scene, and shapes refer to them by key.
+    #[cfg(feature = "serde")]
+    pub fn serialize_scene<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+
+        shared::serialize_scene(&self.scene, serializer)
+
+    }
+
+    /// Replaces the scene with one written by `serialize_scene`, keeping the camera and the render settings.
+    /// Texture files are decoded again, and one that fails to load fails the whole scene.
+    /// Shapes that shared a texture or mesh share it again.
+    #[cfg(feature = "serde")]
+    pub fn deserialize_scene<'de, D: serde::Deserializer<'de>>(&mut self, deserializer: D) -> Result<(), D::Error> {
+
+        self.scene = shared::deserialize_scene(deserializer)?;
+        Ok(())
 
     }
 
@@ -309,4 +1697,32 @@ impl RayTracer {
         
     }
 
+    /// Writes the image as a binary PPM (`P6`), dropping the alpha channel.
+    pub fn write_ppm(&self, writer: &mut impl Write) -> std::io::Result<()> {
+
+        write!(writer, "P6\n{} {}\n255\n", self.width, self.height)?;
+        let bytes: Vec<u8> = self.image.pixels().flat_map(|pixel| [pixel[0], pixel[1], pixel[2]]).collect();
+        writer.write_all(&bytes)?;
+        writer.flush()
+
+    }
+
+    /// Saves a buffer from `render_to_f32_buffer` as an OpenEXR (`.exr`) or Radiance (`.hdr`) image.
+    /// Radiance files have no alpha channel, so it is dropped for them.
+    pub fn save_f32_image(&self, buffer: &[RGBA], file_path: String) {
+
+        let image: Rgba32FImage = ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            image::Rgba(buffer[(y * self.width + x) as usize].into_array())
+        });
+
+        let image = if file_path.ends_with(".hdr") {
+            DynamicImage::ImageRgb32F(DynamicImage::ImageRgba32F(image).to_rgb32f())
+        } else {
+            DynamicImage::ImageRgba32F(image)
+        };
+
+        image.save(file_path).unwrap();
+
+    }
+
 }
@@ -1,72 +1,374 @@
-use crate::raytracer::{Intersection, IntersectionPayload, MatVec, RGBA, Color, LightResidual};
+use crate::raytracer::{Frustum, Intersection, IntersectionPayload, MatVec, RGBA, Color, LightResidual, ShapeId};
 use rand::Rng;
+use rand::rngs::StdRng;
 use crate::raytracer::ray::Ray;
 use crate::raytracer::utils;
+use crate::raytracer::material::{Material, PhongMaterial};
+use crate::raytracer::texture::Texture2d;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "serde")]
+use crate::raytracer::{shapes::SerializedShape, light_sources::SerializedLight, shared::SharedTables};
 
 /// Trait that defines the required behavior of any object in a scene.
 /// Notable methods are `intersect` and `color_at`, which are used to
 /// calculate a possible intersection with a given ray and the color (including textures)
-/// at any given point on the surface of the object.
-pub trait SceneObject {
+/// at the point of such an intersection.
+/// Objects are shared between the render threads, so they must be `Send + Sync`.
+pub trait SceneObject: Send + Sync {
     fn intersect(&self, ray: &Ray) -> IntersectionPayload;
-    fn color_at(&self, point: &MatVec<3>) -> Color;
+    fn color_at(&self, hit: &Intersection) -> Color;
+    /// Copies the object behind a trait object, see `Clone for Box<dyn SceneObject>`.
+    fn clone_box(&self) -> Box<dyn SceneObject>;
+    /// Copies the object into the enum of shapes that serde can write, see `Serialize for Box<dyn SceneObject>`.
+    #[cfg(feature = "serde")]
+    fn serialized(&self) -> SerializedShape;
+    /// Adds the textures and meshes the object shares with others to the tables of the scene, see `SharedTables`.
+    #[cfg(feature = "serde")]
+    fn add_shared(&self, _tables: &mut SharedTables) {}
 
     // TODO: Restructure these things to be propagated by the Objects themselves
     // IE I want the computations to be handled by each implementation of the SceneObject trait
 
     // add normal, and apply roughness to the normal
-    fn propagate(&self, incident: &Ray) -> Ray;
-    fn shininess(&self) -> Option<Vec<f32>> { None }
-    fn transparency(&self) -> Option<Vec<f32>> { None }
-    // fn roughness(&self) -> Option<f32> { None }
+    /// Moves a ray that entered the object to where it leaves it.
+    /// Only meaningful for closed volumes, so by default the ray is passed through unchanged.
+    fn propagate(&self, incident: &Ray) -> Ray { incident.clone() }
+    /// The material used to shade the object, objects without one use a plain `PhongMaterial`.
+    fn material(&self) -> Option<&dyn Material> { None }
+    /// Typical angle in radians between the shading normal from `perturb_normal` and the true normal.
+    /// Reflections off the object see the environment blurred by it, see `Scene::_miss_color`.
+    fn roughness(&self) -> f32 { 0.0 }
     fn ior(&self) -> f32 { 1.458 }
+    /// Roughens the shading normal at a hit, smooth objects return it unchanged.
+    fn perturb_normal(&self, normal: &MatVec<3>, _rng: &mut StdRng) -> MatVec<3> { *normal }
+    /// Fraction of the surface that is there at a hit. Below 1 the ray also continues past the surface,
+    /// and the shaded color is blended over what it sees behind, see `Scene::_recursive_raytrace`.
+    fn coverage(&self, _hit: &Intersection) -> f32 { 1.0 }
+    /// Light given off by the surface itself, added to its shaded color whether or not it is lit.
+    fn emission(&self) -> Option<Color> { None }
+    /// Closest point to the point of a hit on an edge of the face that was hit, for the wireframe output.
+    /// Objects without edges return `None`, see `Scene::_recursive_raytrace`.
+    fn nearest_edge(&self, _hit: &Intersection) -> Option<MatVec<3>> { None }
+    /// Samples a direction from `origin` towards the object, for the next event estimation of emissive objects.
+    /// Objects that cannot be sampled return `None`, and are only found by GI rays that happen to hit them.
+    fn sample_direction_from(&self, _origin: &MatVec<3>, _rng: &mut StdRng) -> Option<MatVec<3>> { None }
+    /// Solid angle density of `sample_direction_from` choosing `direction`, 0 when it never does.
+    fn direction_pdf_from(&self, _origin: &MatVec<3>, _direction: &MatVec<3>) -> f32 { 0.0 }
+    /// Every stretch of the ray's line that lies inside the object, as `(entry, exit)` hits in order,
+    /// with outward normals. Entries behind the ray origin are kept, with a negative distance.
+    /// Objects that do not enclose a volume return no intervals, and so cannot take part in CSG, see `is_solid`.
+    fn intersect_interval(&self, _ray: &Ray) -> Vec<(Intersection, Intersection)> { Vec::new() }
+    /// Whether `intersect_interval` gives the volume the object encloses, which CSG operands need.
+    fn is_solid(&self) -> bool { false }
+    /// Descriptions of the problems that would keep the object from rendering correctly, see `Scene::validate`.
+    fn validate(&self) -> Vec<String> { Vec::new() }
+    /// Corners of a world space box that contains the whole object, `None` for unbounded objects,
+    /// or when the bounds are not known. Objects without bounds are never culled, see `Scene::cull_to_frustum`.
+    fn bounds(&self) -> Option<(MatVec<3>, MatVec<3>)> { None }
     // fn apply_dir_transform(&self, dir: &MatVec<3>) -> MatVec<3>;
     // fn apply_light_transform(&self, light: &MatVec<3>) -> MatVec<3>;
 }
 
+impl Clone for Box<dyn SceneObject> {
+    fn clone(&self) -> Box<dyn SceneObject> {
+        self.clone_box()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Box<dyn SceneObject> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serde::Serialize::serialize(&self.serialized(), serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Box<dyn SceneObject> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Box<dyn SceneObject>, D::Error> {
+        <SerializedShape as serde::Deserialize>::deserialize(deserializer).map(SerializedShape::into_boxed)
+    }
+}
+
 /// Trait that defines the required behavior of any light source in a scene.
 /// Notable methods are `compute_direction`, `light_color` and `intensity`, which are used to
 /// calculate the direction of the light source, the color of the light and the intensity of the light.
-pub trait LightSource {
+/// Lights are shared between the render threads like objects, so they must be `Send + Sync` too.
+pub trait LightSource: Send + Sync {
     fn compute_direction(&self, origin: &MatVec<3>) -> MatVec<3>;
     fn light_color(&self) -> Color;
     fn intensity(&self, ray: &Ray) -> f32;
+    /// Copies the light behind a trait object, see `Clone for Box<dyn LightSource>`.
+    fn clone_box(&self) -> Box<dyn LightSource>;
+    /// Copies the light into the enum of lights that serde can write, see `Serialize for Box<dyn LightSource>`.
+    #[cfg(feature = "serde")]
+    fn serialized(&self) -> SerializedLight;
+    /// Direction towards a randomly sampled point on the light.
+    /// Lights without any extent always return the same direction.
+    fn sample_direction(&self, origin: &MatVec<3>, _rng: &mut StdRng) -> MatVec<3> { self.compute_direction(origin) }
+    /// Whether the light has an extent, and thus needs multiple shadow samples.
+    fn is_area_light(&self) -> bool { false }
+    /// Distance from the origin of a light ray to the light, only geometry closer than this casts a shadow.
+    /// Infinite by default, for lights that are infinitely far away.
+    fn distance(&self, _ray: &Ray) -> f32 { f32::INFINITY }
     // fn position(&self) -> MatVec;
     // fn color(&self) -> RGBA;
 }
 
-pub trait Material {
-    fn compute_shine(&self, point: &Intersection) -> f32;
-    fn compute_color(&self, point: &Intersection) -> Color;
-    fn compute_intensity(&self, point: &Intersection) -> f32;
+impl Clone for Box<dyn LightSource> {
+    fn clone(&self) -> Box<dyn LightSource> {
+        self.clone_box()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Box<dyn LightSource> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serde::Serialize::serialize(&self.serialized(), serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Box<dyn LightSource> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Box<dyn LightSource>, D::Error> {
+        <SerializedLight as serde::Deserialize>::deserialize(deserializer).map(SerializedLight::into_boxed)
+    }
+}
+
+/// A problem with a shape in the scene, found by `Scene::validate`.
+#[derive(Debug)]
+pub struct SceneError {
+    pub shape_id: ShapeId,
+    pub message: String,
+}
+
+impl std::fmt::Display for SceneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "shape {}: {}", self.shape_id, self.message)
+    }
+}
+
+/// Running totals of the work done while tracing, shared by the render threads and read after a render.
+/// A copy of a scene starts counting from zero.
+#[derive(Default)]
+pub struct RenderCounters {
+    pub camera_rays: AtomicU64,        // Calls of `Scene::trace_ray`
+    pub rays: AtomicU64,               // Rays of any kind cast by those calls
+    pub bounces: AtomicU64,            // Reflected and indirect rays, see `Scene::_recursive_raytrace`
+    pub intersection_tests: AtomicU64, // Shapes tested against a ray, see `Scene::_closest_hit`
+}
+
+impl RenderCounters {
+
+    pub fn reset(&self) {
+        for counter in [&self.camera_rays, &self.rays, &self.bounces, &self.intersection_tests] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+}
+
+impl Clone for RenderCounters {
+    fn clone(&self) -> RenderCounters {
+        RenderCounters::default()
+    }
 }
 
+/// Relative difference in distance below which two hits are considered to be on coincident surfaces.
+const COINCIDENT_TOLERANCE: f32 = 1e-4;
+
+/// Default bias as a fraction of the diagonal of the bounds of the scene, see `Scene::bias`.
+const RELATIVE_BIAS: f32 = 1e-4;
+
+/// Default bias of scenes with no bounded shapes, whose size is not known, see `Scene::bias`.
+const FALLBACK_BIAS: f32 = 1e-3;
+
 /// The `Scene` struct holds all the objects and light sources in the scene.
 /// It additionally provides methods to trace rays through the scene.
 /// The actual tracing of any given `Ray` is facilitated through the scene object,
 /// although many of the calculations are offloaded to the objects themselves or other
 /// utility functions that are provided my other modules.
+/// Cloning a scene copies every shape and light, so a parsed scene can be snapshotted and varied
+/// without parsing it again. Textures are shared between the copies.
+///
+/// A scene is only read while rendering, and is shared by reference between the render threads.
+/// The only state changed behind a shared reference is the atomic `counters`: every tracing method takes the
+/// random number generator of the pixel being traced and counts rays into a counter of the caller, and textures
+/// are immutable images behind an `Arc`. Shapes, lights and materials are `Send + Sync` for this reason,
+/// and anything added to the scene has to keep it that way.
+///
+/// With the `serde` feature, a scene can be written and read in any serde format, see `RayTracer::serialize_scene`.
+/// The shapes are kept with their IDs, and the culling of `cull_to_frustum` is left out. Textures and meshes are
+/// written once for all the shapes that share them, see `SharedTables`.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Scene {
-    pub shapes: Vec<Box<dyn SceneObject>>,
+    shapes: Vec<(ShapeId, Box<dyn SceneObject>)>, // Ordered by ID, which is the order they were added in
+    next_shape_id: ShapeId,
     // pub materials: Vec<Material>,
     pub light_sources: Vec<Box<dyn LightSource>>,
-    pub gi_depth: u32
+    pub gi_depth: u32,      // Number of indirect bounces that are always traced
+    pub gi_rr_depth: u32,   // Number of further indirect bounces, each continued by Russian roulette with the throughput of the path
+    pub gi_samples: u32,    // Number of indirect rays averaged at the first diffuse bounce of a path
+    pub gi_nee: bool,       // Whether GI bounces also sample emissive objects directly, see `_sample_emitters`
+    pub light_samples: u32, // Number of shadow rays cast towards area lights
+    pub ao_samples: u32,    // Number of ambient occlusion rays per primary hit, 0 disables it
+    pub ao_radius: f32,     // Maximum distance at which geometry occludes a point
+    #[cfg_attr(feature = "serde", serde(with = "crate::raytracer::shared::option"))]
+    pub environment: Option<Arc<Texture2d>>, // Equirectangular image seen by rays that miss every shape
+    pub background: Option<Color>,          // Solid color seen by rays that miss every shape, when there is no environment
+    pub background_secondary: bool,         // Whether reflected and indirect rays also see the background color
+    pub bias: Option<f32>,                  // Offset of secondary ray origins from the surface they start on, see `bias`
+    extent: Option<(MatVec<3>, MatVec<3>)>, // Union of the bounds of the shapes that have any, kept by `add_shape`
+    pub fog: Option<(Color, f32)>,          // Color and density of the fog every ray passes through
+    pub wireframe: bool,                    // Whether camera rays draw the edges of faces, see `_recursive_raytrace`
+    pub wireframe_fill: bool,               // Whether the faces between the edges are shaded, or seen through
+    #[cfg_attr(feature = "serde", serde(skip))]
+    primary_shapes: Option<Vec<usize>>,     // Indices of the shapes left for camera rays by `cull_to_frustum`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub counters: RenderCounters,           // Work done by the traces since the last reset, see `RayTracer::render`
 }
 
+// Fails to compile as soon as a field of the scene can no longer be shared between the render threads
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Scene>();
+};
+
 impl Scene {
 
     pub fn new() -> Scene {
         Scene {
             shapes: Vec::new(),
+            next_shape_id: 0,
             // materials: Vec::new(),
             light_sources: Vec::new(),
             gi_depth: 0,
+            gi_rr_depth: 0,
+            gi_samples: 1,
+            gi_nee: true,
+            light_samples: 1,
+            ao_samples: 0,
+            ao_radius: 1.0,
+            environment: None,
+            background: None,
+            background_secondary: true,
+            bias: None,
+            extent: None,
+            fog: None,
+            wireframe: false,
+            wireframe_fill: true,
+            primary_shapes: None,
+            counters: RenderCounters::default(),
+        }
+    }
+
+    /// Adds a shape to the scene and returns its ID.
+    /// IDs are never reused, so an ID keeps referring to the same shape as others are added or removed.
+    pub fn add_shape(&mut self, shape: Box<dyn SceneObject>) -> ShapeId {
+
+        let id: ShapeId = self.next_shape_id;
+        self.next_shape_id += 1;
+        if let Some((min, max)) = shape.bounds() {
+            self.extent = Some(match self.extent {
+                None => (min, max),
+                Some((ref extent_min, ref extent_max)) => (extent_min.component_min(&min), extent_max.component_max(&max)),
+            });
+        }
+        self.shapes.push((id, shape));
+        self.primary_shapes = None;
+        id
+
+    }
+
+    /// Removes a shape from the scene and returns it, `None` if there is no shape with the ID.
+    pub fn remove_shape(&mut self, id: ShapeId) -> Option<Box<dyn SceneObject>> {
+
+        let index: usize = self.shapes.binary_search_by_key(&id, |(shape_id, _)| *shape_id).ok()?;
+        self.primary_shapes = None;
+        let shape: Box<dyn SceneObject> = self.shapes.remove(index).1;
+        self.extent = self.shapes.iter()
+            .filter_map(|(_, shape)| shape.bounds())
+            .reduce(|(min_a, max_a), (min_b, max_b)| (min_a.component_min(&min_b), max_a.component_max(&max_b)));
+        Some(shape)
+
+    }
+
+    /// The shape with the given ID, if it is still in the scene.
+    pub fn shape(&self, id: ShapeId) -> Option<&dyn SceneObject> {
+
+        let index: usize = self.shapes.binary_search_by_key(&id, |(shape_id, _)| *shape_id).ok()?;
+        Some(self.shapes[index].1.as_ref())
+
+    }
+
+    /// Offset of secondary ray origins from the surface they start on, so that the surface does not hit the ray itself.
+    /// Unless set, it scales with the scene: too large a bias skips real geometry close to the surface and leaks light,
+    /// and too small a one lets rounding errors shadow a surface by itself in large scenes.
+    pub fn bias(&self) -> f32 {
+
+        self.bias.unwrap_or_else(|| match self.extent {
+            None => FALLBACK_BIAS,
+            Some((ref min, ref max)) => RELATIVE_BIAS * (*max - *min).magnitude(),
+        })
+
+    }
+
+    /// The textures and meshes shared by the shapes and the environment, which are written once, see `SharedTables`.
+    #[cfg(feature = "serde")]
+    pub fn shared_tables(&self) -> SharedTables {
+
+        let mut tables = SharedTables::default();
+        if let Some(ref environment) = self.environment {
+            tables.add(environment);
+        }
+        for (_, shape) in &self.shapes {
+            shape.add_shared(&mut tables);
+        }
+        tables
+
+    }
+
+    /// IDs of the shapes in the scene, in the order they were added.
+    pub fn shape_ids(&self) -> impl DoubleEndedIterator<Item = ShapeId> + '_ {
+
+        self.shapes.iter().map(|(id, _)| *id)
+
+    }
+
+    /// Checks every shape for problems that would keep it from rendering correctly, such as a nonpositive radius
+    /// or a triangle with bad vertex indices. Every problem is collected, rather than stopping at the first.
+    pub fn validate(&self) -> Result<(), Vec<SceneError>> {
+
+        let errors: Vec<SceneError> = self.shapes.iter()
+            .flat_map(|(id, shape)| shape.validate().into_iter().map(move |message| SceneError { shape_id: *id, message }))
+            .collect();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
         }
+
     }
 
-    pub fn add_shape(&mut self, shape: Box<dyn SceneObject>) {
+    /// Leaves out the shapes whose bounds are entirely outside of the frustum when tracing camera rays,
+    /// see `find_primary_intersection`. Every other ray still sees every shape, since off-screen shapes
+    /// still cast shadows and show up in reflections. Returns the number of shapes left out.
+    /// Adding or removing a shape undoes the culling.
+    pub fn cull_to_frustum(&mut self, frustum: &Frustum) -> usize {
+
+        let visible: Vec<usize> = self.shapes.iter().enumerate()
+            .filter(|(_, (_, shape))| match shape.bounds() {
+                None => true,
+                Some((min, max)) => frustum.overlaps_box(&min, &max),
+            })
+            .map(|(index, _)| index)
+            .collect();
 
-        self.shapes.push(shape);
+        let culled: usize = self.shapes.len() - visible.len();
+        self.primary_shapes = Some(visible);
+        culled
 
     }
 
@@ -86,211 +388,401 @@ impl Scene {
     //     None
     // }
 
+    /// Finds the closest intersection of the ray with any shape.
+    /// Hits within `COINCIDENT_TOLERANCE` of each other, relative to their distance, are treated as coincident,
+    /// and the shape with the lowest ID, ie the one added first, wins. Overlapping surfaces then resolve to
+    /// the same shape for every ray, instead of by rounding, which would speckle them between AA samples.
     pub fn find_minimum_intersection(&self, ray: &Ray) -> IntersectionPayload {
+        self._closest_hit(ray, self.shapes.iter())
+    }
 
-        let mut intersections: Vec<Intersection> = Vec::new();
-        // for i, shape in &self.shapes {
-        for (i, shape) in self.shapes.iter().enumerate() {
-
-            let mut intersection: IntersectionPayload = shape.intersect(&ray);
-
-            if intersection.is_some() {
-
-                intersection.as_mut().unwrap().shape_id = Some(i);
-                intersections.push(intersection.unwrap());
-
-            }
-
+    /// Finds the closest intersection of a ray cast from the camera, like `find_minimum_intersection`,
+    /// but only among the shapes left by `cull_to_frustum`.
+    pub fn find_primary_intersection(&self, ray: &Ray) -> IntersectionPayload {
+        match self.primary_shapes {
+            None => self.find_minimum_intersection(ray),
+            Some(ref indices) => self._closest_hit(ray, indices.iter().map(|&index| &self.shapes[index])),
         }
+    }
 
-        if intersections.is_empty() {
-
-            return None;
-
+    /// Closest hit among the given shapes, which must be in ID order, see `find_minimum_intersection`.
+    /// Only hits between the `near` and `far` distances of the ray count.
+    fn _closest_hit<'a>(&self, ray: &Ray, shapes: impl Iterator<Item = &'a (ShapeId, Box<dyn SceneObject>)>) -> IntersectionPayload {
+
+        // The ray starts at the near distance instead, so shapes cut by it show their far side
+        if ray.near > 0.0 {
+            let start: f32 = ray.near;
+            let shifted = Ray::new(ray.origin + start * ray.direction, ray.direction).at_time(ray.time).clipped(0.0, ray.far - start);
+            return self._closest_hit(&shifted, shapes).map(|intersection| Intersection {
+                distance: intersection.distance + start,
+                ..intersection
+            });
         }
 
         let mut minimum_intersection: IntersectionPayload = None;
+        let mut tests: u64 = 0;
+
+        // Shapes are visited in ID order, so a later hit must be clearly closer to replace an earlier one
+        for (id, shape) in shapes {
+
+            tests += 1;
+            if let Some(mut intersection) = shape.intersect(ray) {
 
-        for i in intersections {
+                intersection.shape_id = Some(*id);
 
-            if minimum_intersection.is_none() || i.distance < minimum_intersection.as_ref().unwrap().distance {
+                let closer: bool = match minimum_intersection {
+                    None => true,
+                    Some(ref minimum) => intersection.distance < minimum.distance - COINCIDENT_TOLERANCE * minimum.distance.abs().max(1.0),
+                };
 
-                minimum_intersection = Some(i);
+                if closer {
+                    minimum_intersection = Some(intersection);
+                }
 
             }
 
         }
 
+        // Counted once per ray rather than per shape, so the render threads rarely touch the shared counter
+        self.counters.intersection_tests.fetch_add(tests, Ordering::Relaxed);
+
         // println!("Minimum intersection: {:?}", minimum_intersection);
-        minimum_intersection
+        // Nothing behind the closest hit is seen either
+        minimum_intersection.filter(|intersection| intersection.distance <= ray.far)
 
     }
 
+    /// Finds the closest intersection of a ray that starts on the surface of `intersection`.
+    /// The origin is offset by the `bias` along the normal, to the side the ray leaves towards,
+    /// so that the surface does not intersect the ray itself.
     pub fn find_minimum_intersection_with_point(&self, ray: &Ray, intersection: &IntersectionPayload) -> IntersectionPayload {
-        
-        if intersection.is_none() {
-            return self.find_minimum_intersection(ray);
+
+        match intersection {
+            None => self.find_minimum_intersection(ray),
+            Some(ref origin) => {
+                let side: f32 = if ray.direction.dot(origin.normal) < 0.0 { -1.0 } else { 1.0 };
+                let biased_ray = Ray::new(ray.origin + (side * self.bias()) * origin.normal, ray.direction).at_time(ray.time);
+                self.find_minimum_intersection(&biased_ray)
+            },
         }
 
-        let intersection = intersection.clone().unwrap();
+    }
 
-        let mut intersections: Vec<Intersection> = Vec::new();
+    /// Traces a ray through the scene and returns the color at the intersection
+    /// of the primary ray and its collision in the scene.
+    /// Utilizes the `_recursive_raytrace` method to handle recursive raytracing.
+    /// All random sampling draws from `rng`, so the same seed reproduces the same color.
+    /// Every ray cast into the scene on the way, primary, secondary or shadow, is added to `rays`,
+    /// and to the totals in `counters`.
+    pub fn trace_ray(&self, ray: &Ray, bounce_limit: u32, rays: &mut u32, rng: &mut StdRng) -> RGBA {
+        let rays_before: u32 = *rays;
+        let color: RGBA = self._recursive_raytrace(ray,  &None, bounce_limit, self.gi_depth + self.gi_rr_depth, MatVec::from_array([1.0, 1.0, 1.0]), true, rays, rng);
+
+        self.counters.camera_rays.fetch_add(1, Ordering::Relaxed);
+        self.counters.rays.fetch_add((*rays - rays_before) as u64, Ordering::Relaxed);
+        color
+    }
 
-        let mut local_ray = ray.clone();
+    /// Distance from the origin of the ray to the first shape it hits.
+    pub fn trace_depth(&self, ray: &Ray) -> Option<f32> {
+        self.find_primary_intersection(ray).map(|colision| colision.distance)
+    }
 
-        for (i, shape) in self.shapes.iter().enumerate() {
+    /// World space normal of the first shape the ray hits.
+    pub fn trace_normal(&self, ray: &Ray) -> Option<MatVec<3>> {
+        self.find_primary_intersection(ray).map(|colision| colision.normal)
+    }
 
-            // Bias the origin if the intesection belongs to this shape
-            if intersection.shape_id.unwrap() == i {
-                local_ray.origin = local_ray.origin.clone() + 0.065f32 * local_ray.direction.clone();
-            }
+    /// Computes the fraction of the hemisphere around the intersection that is not occluded,
+    /// by casting `ao_samples` short rays around the normal. Only geometry closer than
+    /// `ao_radius` counts as an occluder.
+    pub fn compute_ao(&self, intersection: &Intersection, time: f32, rays: &mut u32, rng: &mut StdRng) -> f32 {
 
-            let mut intersection: IntersectionPayload = shape.intersect(&local_ray);
+        let mut occluded: u32 = 0;
 
-            if intersection.is_some() {
+        for _sample in 0..self.ao_samples {
 
-                intersection.as_mut().unwrap().shape_id = Some(i);
-                intersections.push(intersection.unwrap());
+            let direction = self.generate_random_direction_in_hemisphere(&intersection.normal, rng);
+            let ao_ray = Ray::new(intersection.point, direction).at_time(time);
+            *rays += 1;
 
+            // Biased like shadow rays, so that the surface does not occlude itself
+            if let Some(hit) = self.find_minimum_intersection_with_point(&ao_ray, &Some(intersection.clone())) {
+                if hit.distance < self.ao_radius {
+                    occluded += 1;
+                }
             }
-        }
-        
-        if intersections.is_empty() {
-
-            return None;
 
         }
 
-        let mut minimum_intersection: IntersectionPayload = None;
-
-        for i in intersections {
-
-            if minimum_intersection.is_none() || i.distance < minimum_intersection.as_ref().unwrap().distance {
-                
-                minimum_intersection = Some(i);
-
-            }
-
-        }
-        // println!("Minimum intersection: {:?}", minimum_intersection);
-        minimum_intersection
-    }
+        1.0 - occluded as f32 / self.ao_samples as f32
 
-    /// Traces a ray through the scene and returns the color at the intersection
-    /// of the primary ray and its collision in the scene.
-    /// Utilizes the `_recursive_raytrace` method to handle recursive raytracing.
-    pub fn trace_ray(&self, ray: &Ray, bounce_limit: u32) -> RGBA {
-        self._recursive_raytrace(ray,  &None, bounce_limit, self.gi_depth)
     }
 
     /// Recursive implementation of raytracing, with support for reflections and transparency.
-    fn _recursive_raytrace(&self, ray: &Ray, optional_intersection: &IntersectionPayload, bounce_limit: u32, gi_depth: u32) -> RGBA {
+    /// `throughput` is the fraction of the light seen along the ray that reaches the camera, per channel,
+    /// the product of the albedos of the surfaces the GI rays of the path bounced off.
+    /// `primary` is true only for rays cast from the camera.
+    /// With fog, the color fades into the fog color with the distance the ray travelled, see `_apply_fog`.
+    /// A partially covered surface is blended over what the ray sees when it continues past it, each such
+    /// surface spending a bounce, and with no bounces left it is blended over transparent black.
+    /// With `wireframe`, camera rays that hit less than half a pixel from an edge see the wire color,
+    /// so an edge shared by two faces is a pixel wide. Without `wireframe_fill` they see through the faces.
+    #[allow(clippy::too_many_arguments)]
+    fn _recursive_raytrace(&self, ray: &Ray, optional_intersection: &IntersectionPayload, bounce_limit: u32, gi_depth: u32, throughput: Color, primary: bool, rays: &mut u32, rng: &mut StdRng) -> RGBA {
         // cast primary ray
+        *rays += 1;
+        if !primary {
+            self.counters.bounces.fetch_add(1, Ordering::Relaxed);
+        }
 
-        let primary_colision: IntersectionPayload = self.find_minimum_intersection_with_point(ray, &optional_intersection);
-
-        if primary_colision.is_none() {
-
-            return MatVec::new(vec![0.0, 0.0, 0.0, 0.0]);
+        let primary_colision: IntersectionPayload = if primary {
+            self.find_primary_intersection(ray)
+        } else {
+            self.find_minimum_intersection_with_point(ray, optional_intersection)
+        };
 
+        match primary_colision {
+            None => self._apply_fog(self._miss_color(ray, primary), f32::INFINITY),
+            Some(colision) => {
+                let distance: f32 = colision.distance;
+
+                if primary && self.wireframe {
+                    // The ray stands for a cone a pixel wide, which is `spread * distance` across at the hit
+                    let shape: Option<&dyn SceneObject> = self.shape(colision.shape_id.unwrap());
+                    let on_edge: bool = shape.and_then(|shape| shape.nearest_edge(&colision))
+                        .is_some_and(|edge| (edge - colision.point).magnitude() < 0.5 * ray.spread * distance);
+                    if on_edge {
+                        return MatVec::from_array([0.0, 1.0, 0.0, 1.0]);
+                    }
+                    if !self.wireframe_fill {
+                        // The same ray from the camera, so the wires behind keep their width
+                        let continued = ray.clone().clipped(distance + self.bias(), ray.far);
+                        return self._recursive_raytrace(&continued, &None, bounce_limit, gi_depth, throughput, primary, rays, rng);
+                    }
+                }
+
+                let coverage: f32 = self.shape(colision.shape_id.unwrap()).map_or(1.0, |shape| shape.coverage(&colision));
+                if coverage >= 1.0 {
+                    let color: RGBA = self._shade_intersection(ray, colision, bounce_limit, gi_depth, throughput, primary, rays, rng);
+                    return self._apply_fog(color, distance);
+                }
+
+                let behind: RGBA = if bounce_limit > 1 {
+                    // The same ray, continuing from just past the surface, offset like secondary rays
+                    let side: f32 = if ray.direction.dot(colision.normal) < 0.0 { -1.0 } else { 1.0 };
+                    let continued = Ray::new(colision.point + (side * self.bias()) * colision.normal, ray.direction)
+                        .at_time(ray.time)
+                        .clipped(0.0, ray.far - distance)
+                        .with_spread(ray.spread);
+                    self._recursive_raytrace(&continued, &None, bounce_limit - 1, gi_depth, throughput, primary, rays, rng)
+                } else {
+                    MatVec::zero()
+                };
+
+                let color: RGBA = if coverage > 0.0 {
+                    coverage * self._shade_intersection(ray, colision, bounce_limit, gi_depth, throughput, primary, rays, rng) + (1.0 - coverage) * behind
+                } else {
+                    behind
+                };
+                self._apply_fog(color, distance)
+            },
         }
 
-        let colision: Intersection = primary_colision.unwrap(); // consume the payload
+    }
 
-        let shape_id: usize = colision.shape_id.unwrap();
-        let mut color: Color = self.shapes[shape_id].color_at(&colision.point);
+    /// Blends a color seen at `distance` along a ray into the fog color, by the fraction `1 - exp(-density * distance)`
+    /// of the light the fog scatters on the way. Rays that escape the scene only see the fog.
+    fn _apply_fog(&self, color: RGBA, distance: f32) -> RGBA {
+        match self.fog {
+            None => color,
+            Some((fog_color, _)) if distance.is_infinite() => utils::color_to_rgba(fog_color, 1.0),
+            Some((fog_color, density)) => color.lerp(&utils::color_to_rgba(fog_color, 1.0), 1.0 - (-density * distance.max(0.0)).exp()),
+        }
+    }
 
-        let mut ilumination_sources: Vec<LightResidual> = self._find_light_sources(&colision);
+    /// Color of the surface hit by a ray, lit by the light sources, global illumination and reflections.
+    /// `throughput` and `primary` are those of the ray, see `_recursive_raytrace`.
+    /// When ambient occlusion is enabled, the surface color of hits of camera rays is darkened by `compute_ao`,
+    /// which leaves what the surface reflects, lets through or emits as it is.
+    #[allow(clippy::too_many_arguments)]
+    fn _shade_intersection(&self, ray: &Ray, mut colision: Intersection, bounce_limit: u32, gi_depth: u32, throughput: Color, primary: bool, rays: &mut u32, rng: &mut StdRng) -> RGBA {
+
+        let shape: &dyn SceneObject = self.shape(colision.shape_id.unwrap()).expect("Intersected a shape that is not in the scene");
+        colision.normal = shape.perturb_normal(&colision.normal, rng);
+        let color: Color = shape.color_at(&colision);
+        // Emissive surfaces glow even in a shadow, and light their surroundings through GI rays that hit them
+        let emission: Color = shape.emission().map_or(MatVec::zero(), |emission| self._emission_weight(ray, shape) * emission);
+        let emission: RGBA = utils::color_to_rgba(emission, 0.0);
+
+        let mut ilumination_sources: Vec<LightResidual> = self._find_light_sources(&colision, ray.time, rays, rng);
         
         // Apply global illumination
-        // In _recursive_raytrace method
-        if gi_depth > 0 {
-            let random_direction = self.generate_random_direction_in_hemisphere(&colision.normal);
-            let gi_ray = Ray::new(
-            colision.point + colision.normal * 0.001, // Offset to avoid self-intersection
-            random_direction,
-            );
-
-            let gi_color = utils::rgba_to_color(self._recursive_raytrace(&gi_ray, &None, bounce_limit, gi_depth - 1));
-            // let weight = random_direction.dot(colision.normal).max(0.0); // Importance weight
-            // for i in 0..3 {
-            // color.set(i, color[i] + weight * gi_color[i] * 0.5); // Scaled blending
-            ilumination_sources.push(LightResidual {
-                source_id: None,
-                color: gi_color,
-                intensity: 1.0_f32,
-                direction: random_direction,
-                normal: random_direction.cross(&colision.normal).normalize(),
-            });
+        // `gi_depth` counts the indirect bounces left, the last `gi_rr_depth` of which are played by Russian roulette:
+        // the path survives with a probability given by its throughput past this surface, ie the fraction of the
+        // indirect light found here that would reach the camera, and survivors are weighted up by it, so the
+        // estimate stays unbiased. A path that has bounced off dark surfaces is then soon ended
+        let gi_throughput: Color = throughput.hadamard(&color);
+        let survival: f32 = if gi_depth > self.gi_rr_depth {
+            1.0
+        } else if gi_depth > 0 {
+            gi_throughput.iter().cloned().fold(0.0, f32::max).clamp(0.05, 1.0)
+        } else {
+            0.0
+        };
+
+        // Only the first diffuse bounce of a path is split into `gi_samples` rays, splitting every bounce
+        // would multiply the rays by `gi_samples` per bounce. Reflections keep the depth, so they split too
+        let gi_samples: u32 = if gi_depth == self.gi_depth + self.gi_rr_depth { self.gi_samples.max(1) } else { 1 };
+        // How many GI rays leave here on average, which scales the density of their directions
+        let gi_density: f32 = survival * gi_samples as f32;
+
+        if survival >= 1.0 || (survival > 0.0 && rng.gen::<f32>() < survival) {
+            for _sample in 0..gi_samples {
+                let random_direction = self.generate_random_direction_in_hemisphere(&colision.normal, rng);
+                // The direction is cosine-weighted, so the cosine term of the rendering equation cancels
+                // against the pdf (cos / pi, with the pi cancelling the one in the lambertian brdf).
+                // `lambert` multiplies by the cosine again, so it is divided out of the intensity here.
+                // Every sample carries an equal share, so their sum is the average.
+                let cos_theta: f32 = random_direction.dot(colision.normal).max(1e-4);
+                let gi_ray = Ray::new(
+                colision.point + self.bias() * colision.normal, // Offset to avoid self-intersection
+                random_direction,
+                ).at_time(ray.time).with_pdf(gi_density * cos_theta / std::f32::consts::PI);
+
+                let gi_color = utils::rgba_to_color(self._recursive_raytrace(&gi_ray, &None, bounce_limit, gi_depth - 1, gi_throughput, false, rays, rng));
+
+                ilumination_sources.push(LightResidual {
+                    source_id: None,
+                    color: gi_color,
+                    intensity: 1.0_f32 / (cos_theta * survival * gi_samples as f32),
+                    direction: random_direction,
+                    normal: colision.normal,
+                });
+            }
+        }
+
+        // Next event estimation, emissive objects are also sampled directly from every vertex that casts GI rays
+        if self.gi_nee && gi_density > 0.0 {
+            ilumination_sources.extend(self._sample_emitters(&colision, gi_density, ray.time, rays, rng));
         }
 
 
         // In a shadow, return black
         if ilumination_sources.is_empty() {
 
-            return MatVec::new(vec![0.0, 0.0, 0.0, 1.0]);
+            return MatVec::from_array([0.0, 0.0, 0.0, 1.0]) + emission;
 
         }
 
-        // Handle shininess and transparency
-        let shininess = self.shapes[shape_id].shininess().unwrap_or(vec![0.0, 0.0, 0.0]);
-        let transparency = self.shapes[shape_id].transparency().unwrap_or(vec![0.0, 0.0, 0.0]);
+        let default_material = PhongMaterial::default();
+        let material: &dyn Material = shape.material().unwrap_or(&default_material);
+
+        // Handle shininess
+        let shininess = material.shininess().unwrap_or(vec![0.0, 0.0, 0.0]);
+        let transparency = material.transparency().unwrap_or(vec![0.0, 0.0, 0.0]);
+
+        // Reflectance of the surface at this viewing angle, transparent surfaces reflect by it as well
+        let view: MatVec<3> = -1.0f32 * ray.direction.normalize();
+        let fresnel: f32 = utils::schlick(view.dot(colision.normal).abs(), shape.ior());
 
-        let mut reflection_color: Color = MatVec::new(vec![0.0, 0.0, 0.0]);
-        let mut refraction_color: Color = MatVec::new(vec![0.0, 0.0, 0.0]);
+        let mut reflection_color: Color = MatVec::zero();
+        let mut refraction_color: Color = MatVec::zero();
 
         if bounce_limit > 1 {
             // Handle reflections
-            if shininess.iter().any(|&s| s > 0.0) {
-                let reflection_ray = Ray::generate_reflection_ray(&colision.clone(), ray);
-                reflection_color = utils::rgba_to_color(self._recursive_raytrace(&reflection_ray, &Some(colision.clone()), bounce_limit - 1, gi_depth));
+            if shininess.iter().chain(transparency.iter()).any(|&s| s > 0.0) {
+                // Tilting the normal by an angle tilts the reflection by twice that, which widens the cone of the ray
+                let reflection_ray = Ray::generate_reflection_ray(&colision.clone(), ray).with_spread(ray.spread + 2.0 * shape.roughness());
+                reflection_color = utils::rgba_to_color(self._recursive_raytrace(&reflection_ray, &Some(colision.clone()), bounce_limit - 1, gi_depth, throughput, false, rays, rng));
 
             }
 
-            // Handle refractions
-            // TODO: Make sure that tranceparency is suppoed to be double applies on the in and out
-            // if transparency.iter().any(|&t| t > 0.0) {
-            //     let mut refraction_ray = Ray::generate_refraction_ray(&colision, ray, &self.shapes[shape_id]);
-            //     // utils::in_place_propagate(&mut refraction_ray, self.shapes[shape_id]);
-
-            //     // refraction_ray = self.shapes[shape_id].propagate(&refraction_ray);
-            //     // let pass_through_intersection = self.find_minimum_intersection_with_point(&refraction_ray, &Some(colision.clone()));
+            // Handle refractions, the ray passes through the whole shape, so transparency is applied once for both surfaces
+            if transparency.iter().any(|&t| t > 0.0) {
+                let (refraction_ray, leaving) = Ray::generate_refraction_ray(&colision, ray, shape, self.bias());
+                let refraction_ray = refraction_ray.with_spread(ray.spread);
+                refraction_color = utils::rgba_to_color(self._recursive_raytrace(&refraction_ray, &Some(leaving), bounce_limit - 1, gi_depth, throughput, false, rays, rng));
+            }
+        }
 
-            //     refraction_color = utils::rgba_to_color(self._recursive_raytrace(&refraction_ray, &Some(colision.clone()), bounce_limit - 1, gi_depth));
+        let color: Color = if primary && self.ao_samples > 0 {
+            self.compute_ao(&colision, ray.time, rays, rng) * color
+        } else {
+            color
+        };
 
-            // }
-        }
+        material.shade(&color, &reflection_color, &refraction_color, &ilumination_sources, &view, fresnel) + emission
 
-        // Apply Lambertian shading
+    }
 
-        for i in 0..3 {
-            let color_val: f32 = shininess[i] * reflection_color[i]
-                                    + (1_f32 - shininess[i]) * transparency[i] * refraction_color[i]
-                                    + (1_f32 - shininess[i]) * (1_f32 - transparency[i]) * color[i];
-            color.set(i, color_val);
+    /// Color of a ray that escapes the scene without hitting anything.
+    /// Samples the environment map by the direction of the ray when there is one, otherwise uses the
+    /// background color, which secondary rays only see when `background_secondary` is set.
+    /// Without either, the ray is transparent black.
+    /// The environment is sampled at the mip level whose texels are about as wide as the cone of the ray,
+    /// so rough reflections see it blurred.
+    fn _miss_color(&self, ray: &Ray, primary: bool) -> RGBA {
+
+        if let Some(ref environment) = self.environment {
+            // A texel of the equirectangular map spans 2 pi / width radians along the equator
+            let texel_angle: f32 = 2.0 * std::f32::consts::PI * environment.texel_size()[0];
+            let level: f32 = (ray.spread / texel_angle).max(1.0).log2();
+            return utils::color_to_rgba(environment.sample_level(utils::equirectangular_uv(&ray.direction), level), 1.0);
         }
 
-        return utils::lambert(&color, &ilumination_sources);
+        match self.background {
+            Some(background) if primary || self.background_secondary => utils::color_to_rgba(background, 1.0),
+            _ => MatVec::zero(),
+        }
 
     }
 
-    /// Returns all light sources that illuminate an intersection
-    fn _find_light_sources(&self, primary_intersection: &Intersection) -> Vec<LightResidual> {
+    /// Returns all light sources that illuminate an intersection.
+    /// Area lights are sampled `light_samples` times, and the visibility and intensity
+    /// are averaged over all of the samples, which produces soft shadows.
+    fn _find_light_sources(&self, primary_intersection: &Intersection, time: f32, rays: &mut u32, rng: &mut StdRng) -> Vec<LightResidual> {
 
         let mut light_sources: Vec<LightResidual> = Vec::new();
 
-        for (_i, light_source) in self.light_sources.iter().enumerate() {
+        for (i, light_source) in self.light_sources.iter().enumerate() {
+
+            let num_samples: u32 = if light_source.is_area_light() { self.light_samples.max(1) } else { 1 };
+
+            let mut visible_samples: u32 = 0;
+            let mut total_intensity: f32 = 0.0;
+            let mut total_direction: MatVec<3> = MatVec::zero();
+
+            for _sample in 0..num_samples {
+
+                let light_ray: Ray = Ray::generate_light_ray(primary_intersection, light_source, rng).at_time(time);
+                *rays += 1;
 
-            let mut current_residual: LightResidual = LightResidual::new();
-            let light_ray: Ray = Ray::generate_light_ray(&primary_intersection, light_source);
+                let intersection = self.find_minimum_intersection_with_point(&light_ray, &Some(primary_intersection.clone()));
 
-            let intersection = self.find_minimum_intersection_with_point(&light_ray, &Some(primary_intersection.clone()));
+                // Geometry behind the light does not occlude it
+                let occluded: bool = match intersection {
+                    None => false,
+                    Some(ref occluder) => occluder.distance < light_source.distance(&light_ray),
+                };
 
-            if intersection.is_none() {
+                if !occluded {
 
+                    visible_samples += 1;
+                    total_intensity += light_source.intensity(&light_ray);
+                    total_direction = total_direction + light_ray.direction;
+
+                }
+
+            }
+
+            if visible_samples > 0 {
+
+                let mut current_residual: LightResidual = LightResidual::new();
+                current_residual.source_id = Some(i);
                 current_residual.color = light_source.light_color();
-                current_residual.intensity = light_source.intensity(&light_ray);
-                current_residual.direction = light_ray.direction.clone();
+                current_residual.intensity = total_intensity / num_samples as f32;
+                current_residual.direction = total_direction.normalize();
                 current_residual.normal = primary_intersection.normal.clone();
                 light_sources.push(current_residual);
 
@@ -302,9 +794,75 @@ impl Scene {
 
     }
 
+    /// Light from emissive objects at an intersection, from one direction sampled towards each object that
+    /// can be sampled, see `SceneObject::sample_direction_from`. This is next event estimation, which finds
+    /// small emitters that the GI rays rarely hit. The GI rays still see the emitters, so each sample is
+    /// weighted against them by multiple importance sampling, with `gi_density` GI rays leaving the
+    /// intersection on average, see `_emission_weight` for the other side.
+    fn _sample_emitters(&self, intersection: &Intersection, gi_density: f32, time: f32, rays: &mut u32, rng: &mut StdRng) -> Vec<LightResidual> {
+
+        let origin: MatVec<3> = intersection.point + self.bias() * intersection.normal; // Off the surface, like every secondary ray
+        let mut emitters: Vec<LightResidual> = Vec::new();
+
+        for (id, shape) in self.shapes.iter() {
+
+            // A surface does not light itself, as the GI rays leaving it do not hit it
+            if intersection.shape_id == Some(*id) {
+                continue;
+            }
+            let emission: Color = match shape.emission() {
+                Some(emission) => emission,
+                None => continue,
+            };
+            let direction: MatVec<3> = match shape.sample_direction_from(&origin, rng) {
+                Some(direction) => direction,
+                None => continue,
+            };
+            let cos_theta: f32 = direction.dot(intersection.normal);
+            let light_pdf: f32 = shape.direction_pdf_from(&origin, &direction);
+            if cos_theta <= 0.0 || light_pdf <= 0.0 {
+                continue;
+            }
+
+            let light_ray: Ray = Ray::new(origin, direction).at_time(time);
+            *rays += 1;
+            let visible: bool = match self.find_minimum_intersection(&light_ray) {
+                Some(hit) => hit.shape_id == Some(*id),
+                None => false,
+            };
+
+            if visible {
+                // The estimate is brdf * emission * cos / pdf, with the lambertian brdf of albedo / pi,
+                // and `lambert` supplies the albedo and the cosine
+                let gi_pdf: f32 = gi_density * cos_theta.max(1e-4) / std::f32::consts::PI;
+                let weight: f32 = utils::power_heuristic(light_pdf, gi_pdf);
+                emitters.push(LightResidual {
+                    source_id: None,
+                    color: emission,
+                    intensity: weight / (std::f32::consts::PI * light_pdf),
+                    direction,
+                    normal: intersection.normal,
+                });
+            }
+
+        }
+
+        emitters
+
+    }
+
+    /// Weight of the emission seen by a ray. GI rays that hit an emitter which `_sample_emitters` also samples
+    /// are weighted against it by the power heuristic, so that the two estimates of the same light add up to it once.
+    /// Every other ray sees the whole emission.
+    fn _emission_weight(&self, ray: &Ray, shape: &dyn SceneObject) -> f32 {
+        if !self.gi_nee || ray.pdf <= 0.0 {
+            return 1.0;
+        }
+        utils::power_heuristic(ray.pdf, shape.direction_pdf_from(&ray.origin, &ray.direction))
+    }
 
-    fn generate_random_direction_in_hemisphere(&self, normal: &MatVec<3>) -> MatVec<3> {
-        let mut rng = rand::thread_rng();
+    /// Samples a direction in the hemisphere around `normal`, with probability density `cos(theta) / pi`.
+    fn generate_random_direction_in_hemisphere(&self, normal: &MatVec<3>, rng: &mut StdRng) -> MatVec<3> {
     
         // Generate random spherical coordinates using cosine-weighted sampling
         let r1: f32 = rng.gen();
@@ -316,22 +874,19 @@ impl Scene {
         let y = theta.sin() * sqrt_r2;
         let z = (1.0 - r2).sqrt();
     
-        let local_dir = MatVec::new(vec![x, y, z]);
+        let local_dir: MatVec<3> = MatVec::from_array([x, y, z]);
     
         // Transform local direction to world space
         let uz = normal.normalize();
         let ux = if normal[0].abs() > 0.1 {
-            MatVec::new(vec![-normal[1], normal[0], 0.0]).normalize()
+            MatVec::from_array([-normal[1], normal[0], 0.0]).normalize()
         } else {
-            MatVec::new(vec![0.0, -normal[2], normal[1]]).normalize()
+            MatVec::from_array([0.0, -normal[2], normal[1]]).normalize()
         };
         let uy = uz.cross(&ux);
     
-        MatVec::new(vec![
-            ux.dot(local_dir),
-            uy.dot(local_dir),
-            uz.dot(local_dir),
-        ])
+        // Combine the basis vectors weighted by the local coordinates
+        local_dir[0] * ux + local_dir[1] * uy + local_dir[2] * uz
     }
     
     
@@ -3,106 +3,100 @@ use crate::raytracer::scene::SceneObject;
 use crate::raytracer::ray::Ray;
 use crate::raytracer::utils;
 use crate::raytracer::texture::Texture2d;
+use std::sync::Arc;
+use rand::Rng;
+use rand::rngs::StdRng;
+use crate::raytracer::transform::Transform;
+use crate::raytracer::material::{self, Material};
+#[cfg(feature = "serde")]
+use crate::raytracer::shared::SharedTables;
+
+/// The world space box around the corners of a box given in object space.
+fn world_bounds(min: MatVec<3>, max: MatVec<3>, transform: &Option<Transform>) -> (MatVec<3>, MatVec<3>) {
+    match transform {
+        None => (min, max),
+        Some(ref transform) => {
+            let corners = (0..8).map(|corner: usize| {
+                let point: MatVec<3> = MatVec::from_array(std::array::from_fn(|axis| if corner >> axis & 1 == 0 { min[axis] } else { max[axis] }));
+                transform.matrix.transform_point(&point)
+            });
+            let empty: (MatVec<3>, MatVec<3>) = (MatVec::splat(f32::INFINITY), MatVec::splat(f32::NEG_INFINITY));
+            corners.fold(empty, |(min, max), corner| (min.component_min(&corner), max.component_max(&corner)))
+        },
+    }
+}
 
 /// Represents a sphere in 3D space.
+/// A moving sphere is centered at `center + velocity * time` for a ray at the given time.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Sphere {
     pub center: MatVec<3>,
+    pub velocity: MatVec<3>,
     pub radius: f32,
     pub color: Color,
-    pub texture: Option<Texture2d>,
-    pub shininess: Option<Vec<f32>>,
-    pub transparency: Option<Vec<f32>>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::raytracer::shared::option"))]
+    pub texture: Option<Arc<Texture2d>>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::raytracer::shared::option"))]
+    pub normal_map: Option<Arc<Texture2d>>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::raytracer::shared::scaled"))]
+    pub bump_map: Option<(Arc<Texture2d>, f32)>,
     pub roughness: f32,
-    // pub material: Material,
+    pub transform: Option<Transform>,
+    pub material: Option<Box<dyn Material>>,
+    pub ior: f32,
+    pub emission: Option<Color>,
 }
 
 impl Sphere {
 
     pub fn new(center: MatVec<3>, radius: f32, context: &InputState) -> Sphere {
 
-        println!("Making sphere with center: {:?}, radius: {:?}, color: {:?}", center, radius, context.color);
-
-        // Function assumes that the texture path in the context is valid and exists
-        let texture: Option<Texture2d> = match context.texture.as_str() {
-            "none" => None,
-            _ => Some(Texture2d::new(&context.texture)),
-        };
-
-        let shininess: Option<Vec<f32>> = match context.shininess.is_empty() {
-            true => None,
-            false => {
-                if context.shininess.len() == 3 {
-                    Some(context.shininess.clone())
-                } else if context.shininess.len() == 1 {
-                    Some(vec![context.shininess[0]; 3])
-                } else {
-                    None
-                }
-            }
-        };
-
-        let transparency: Option<Vec<f32>> = match context.transparency.is_empty() {
-            true => None,
-            false => {
-            if context.transparency.len() == 3 {
-                Some(context.transparency.clone())
-            } else if context.transparency.len() == 1 {
-                Some(vec![context.transparency[0]; 3])
-            } else {
-                None
-            }
-            }
-        };
-
-
+        let material: Box<dyn Material> = material::from_context(context);
 
         Sphere {
             center,
+            velocity: context.velocity,
             radius,
             color: context.color.clone(),
-            texture,
-            shininess,
-            transparency,
+            texture: context.texture.clone(),
+            normal_map: context.normal_map.clone(),
+            bump_map: context.bump_map.clone(),
             roughness: context.roughness,
-            // material,
+            transform: context.current_transform(),
+            material: Some(material),
+            ior: context.index_of_refraction,
+            emission: context.emission,
         }
 
     }
 
-}
-
-impl SceneObject for Sphere {
-
-    fn propagate(&self, incident: &Ray) -> Ray {
-        // Move the origin slightly outside the sphere along the ray direction
-        let offset = 0.001; // Small offset to move the origin outside the sphere
-        let direction_to_center = (self.center.clone() - incident.origin.clone()).normalize();
-        let new_origin = incident.origin.clone() + (self.radius + offset) * direction_to_center;
-        Ray {
-            origin: new_origin,
-            direction: incident.direction.clone(),
-        }
-    }
-
-    fn intersect(&self, ray: &Ray) -> IntersectionPayload {
+    /// Intersects a ray given in the object space of the sphere.
+    fn intersect_object_space(&self, ray: &Ray) -> IntersectionPayload {
 
         // println!("ray: {:?}", ray);
         // println!("center: {:?}", self.center);
         // println!("radius: {:?}", self.radius);
 
-        let inside: bool = (self.center.clone() - ray.origin.clone()).magnitude() < self.radius;
+        // Work with a unit direction so that every term below, and the returned distance,
+        // is a true distance regardless of the length of the incoming direction
+        let direction: MatVec<3> = ray.direction.normalize();
+
+        let center: MatVec<3> = self.center + ray.time * self.velocity;
+
+        let inside: bool = (center - ray.origin).magnitude() < self.radius;
 
         // println!("inside: {}", inside);
 
-        let tc: f32 = ((self.center.clone() - ray.origin.clone()).dot(ray.direction.clone())) / ray.direction.clone().magnitude();
+        let tc: f32 = (center - ray.origin).dot(direction);
 
         if !inside && tc < 0.0 {
             return None;
         }
 
-        let d: f32 = (ray.origin.clone() + (tc * ray.direction.clone()) - self.center.clone()).magnitude();
-        
-        let d2: f32 = utils::round_precision(f32::powi(d, 2), 0.005);
+        // Squared distance of the center from the line of the ray, exact so that hit points lie on the surface
+        let closest: MatVec<3> = ray.origin + (tc * direction) - center;
+        let d2: f32 = closest.dot(closest);
 
         if !inside && f32::powi(self.radius, 2) <= d2 {
             return None;
@@ -112,7 +106,7 @@ impl SceneObject for Sphere {
         //     return None;
         // }
 
-        let t_offset: f32 = f32::sqrt(f32::powi(self.radius, 2) - d2) / ray.direction.clone().magnitude();
+        let t_offset: f32 = f32::sqrt(f32::powi(self.radius, 2) - d2);
 
         let t: f32 = if inside {
             tc + t_offset
@@ -120,10 +114,18 @@ impl SceneObject for Sphere {
             tc - t_offset
         };
 
-        let intersection_point: MatVec<3> = ray.origin.clone() + t * ray.direction.clone();
-        let mut normal: MatVec<3> = (intersection_point.clone() - self.center.clone()).normalize();
+        let intersection_point: MatVec<3> = ray.origin.clone() + t * direction;
+        let normal: MatVec<3> = (intersection_point - center).normalize();
+
+        let normal: MatVec<3> = match self.normal_map {
+            None => normal,
+            Some(ref normal_map) => self.apply_normal_map(normal_map, &(intersection_point - center), &normal),
+        };
 
-        normal = normal.perturb(0.5_f32, self.roughness).normalize();
+        let normal: MatVec<3> = match self.bump_map {
+            None => normal,
+            Some((ref bump_map, scale)) => self.apply_bump_map(bump_map, scale, &(intersection_point - center), &normal),
+        };
 
         Some(Intersection {
             shape_id: None,
@@ -131,58 +133,303 @@ impl SceneObject for Sphere {
             normal: normal,
             distance: t,
             residual: false,
+            primitive: None,
         })
 
     }
 
-    fn color_at(&self, point: &MatVec<3>) -> Color {
+    /// The tangent frame at a point, given relative to the center, with the given normal.
+    /// The tangent points along increasing `u` and the bitangent towards the north pole, ie up in the image.
+    /// There is no tangent frame at the poles.
+    fn tangent_frame(&self, local_point: &MatVec<3>, normal: &MatVec<3>) -> Option<(MatVec<3>, MatVec<3>)> {
+        let tangent: MatVec<3> = MatVec::from_array([local_point[2], 0.0, -local_point[0]]);
+        if tangent.approx_zero(1e-6) {
+            return None;
+        }
+        let tangent: MatVec<3> = tangent.normalize();
+        Some((tangent, normal.cross(&tangent)))
+    }
+
+    /// Bends the normal at a point, given relative to the center, by the tangent space normal stored in the map,
+    /// following the usual convention that a flat map is `(0.5, 0.5, 1.0)`.
+    fn apply_normal_map(&self, normal_map: &Texture2d, local_point: &MatVec<3>, normal: &MatVec<3>) -> MatVec<3> {
+
+        let (tangent, bitangent) = match self.tangent_frame(local_point, normal) {
+            None => return *normal,
+            Some(frame) => frame,
+        };
+
+        let uv_coord: MatVec<2> = utils::spherical_world_to_uv(local_point, &MatVec::zero());
+        let sample: MatVec<3> = normal_map.sample_data(uv_coord);
+        let [x, y, z] = std::array::from_fn(|i| 2.0 * sample[i] - 1.0);
+
+        (x * tangent + y * bitangent + z * *normal).normalize()
+
+    }
+
+    /// Tilts the normal at a point, given relative to the center, away from the slope of the height stored in the map.
+    /// The height is the luminance of the map, and `scale` is the height of white in UV units.
+    fn apply_bump_map(&self, bump_map: &Texture2d, scale: f32, local_point: &MatVec<3>, normal: &MatVec<3>) -> MatVec<3> {
+
+        let (tangent, bitangent) = match self.tangent_frame(local_point, normal) {
+            None => return *normal,
+            Some(frame) => frame,
+        };
+
+        let uv_coord: MatVec<2> = utils::spherical_world_to_uv(local_point, &MatVec::zero());
+        let step: MatVec<2> = bump_map.texel_size();
+        let height = |du: f32, dv: f32| utils::luminance(&bump_map.sample_data(uv_coord + MatVec::from_array([du, dv])));
+
+        // Central differences; `v` increases towards the south pole, against the bitangent
+        let slope_u: f32 = (height(step[0], 0.0) - height(-step[0], 0.0)) / (2.0 * step[0]);
+        let slope_v: f32 = (height(0.0, -step[1]) - height(0.0, step[1])) / (2.0 * step[1]);
+
+        (*normal - scale * (slope_u * tangent + slope_v * bitangent)).normalize()
+
+    }
+
+    /// Axis and cosine of the half angle of the cone the sphere covers as seen from `origin`.
+    /// Only resting spheres without a transform are known to be round in world space, and a point
+    /// inside the sphere sees it all around, so neither has a cone.
+    fn cone_from(&self, origin: &MatVec<3>) -> Option<(MatVec<3>, f32)> {
+        if self.transform.is_some() || !self.velocity.approx_zero(0.0) {
+            return None;
+        }
+        let to_center: MatVec<3> = self.center - *origin;
+        let distance: f32 = to_center.magnitude();
+        if distance <= self.radius {
+            return None;
+        }
+        let sin_max: f32 = self.radius / distance;
+        Some(((1.0 / distance) * to_center, (1.0 - sin_max * sin_max).max(0.0).sqrt()))
+    }
+
+    /// The chord of a ray given in the object space of the sphere, see `SceneObject::intersect_interval`.
+    fn interval_object_space(&self, ray: &Ray) -> Option<(Intersection, Intersection)> {
+
+        let direction: MatVec<3> = ray.direction.normalize();
+
+        let center: MatVec<3> = self.center + ray.time * self.velocity;
+
+        let tc: f32 = (center - ray.origin).dot(direction);
+        let d2: f32 = (ray.origin + tc * direction - center).dot(ray.origin + tc * direction - center);
+
+        if d2 >= self.radius * self.radius {
+            return None;
+        }
+
+        let t_offset: f32 = f32::sqrt(self.radius * self.radius - d2);
+
+        if tc + t_offset < 0.0001 {
+            return None;
+        }
+
+        let hit = |t: f32| {
+            let point: MatVec<3> = ray.origin + t * direction;
+            Intersection {
+                shape_id: None,
+                point,
+                normal: (point - center).normalize(),
+                distance: t,
+                residual: false,
+                primitive: None,
+            }
+        };
+
+        Some((hit(tc - t_offset), hit(tc + t_offset)))
+
+    }
+
+}
+
+impl SceneObject for Sphere {
+
+    fn clone_box(&self) -> Box<dyn SceneObject> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "serde")]
+    fn serialized(&self) -> SerializedShape {
+        SerializedShape::SPHERE(self.clone())
+    }
+
+    #[cfg(feature = "serde")]
+    fn add_shared(&self, tables: &mut SharedTables) {
+        for texture in self.texture.iter().chain(self.normal_map.iter()).chain(self.bump_map.iter().map(|(bump_map, _)| bump_map)) {
+            tables.add(texture);
+        }
+    }
+
+    fn bounds(&self) -> Option<(MatVec<3>, MatVec<3>)> {
+        // A moving sphere sweeps over the whole shutter interval, which the sphere does not know
+        if !self.velocity.approx_zero(0.0) {
+            return None;
+        }
+        let extent: MatVec<3> = MatVec::splat(self.radius);
+        Some(world_bounds(self.center - extent, self.center + extent, &self.transform))
+    }
+
+    fn propagate(&self, incident: &Ray) -> Ray {
+        // Move the origin slightly outside the sphere along the ray direction
+        let offset = 0.001; // Small offset to move the origin outside the sphere
+        let direction_to_center = (self.center.clone() - incident.origin.clone()).normalize();
+        let new_origin = incident.origin.clone() + (self.radius + offset) * direction_to_center;
+        Ray::new(new_origin, incident.direction.clone()).at_time(incident.time)
+    }
+
+    fn intersect(&self, ray: &Ray) -> IntersectionPayload {
+
+        match self.transform {
+            None => self.intersect_object_space(ray),
+            Some(ref transform) => {
+                let intersection = self.intersect_object_space(&transform.ray_to_object(ray))?;
+                Some(transform.intersection_to_world(ray, intersection))
+            },
+        }
+
+    }
+
+    fn color_at(&self, hit: &Intersection) -> Color {
 
         match self.texture {
             None => self.color.clone(),
             Some(ref texture) => {
-                let uv_coord: MatVec<2> = utils::spherical_world_to_uv(point, &self.center, self.radius);
+                let local_point: MatVec<3> = match self.transform {
+                    None => hit.point,
+                    Some(ref transform) => transform.point_to_object(&hit.point),
+                };
+                let uv_coord: MatVec<2> = utils::spherical_world_to_uv(&local_point, &self.center);
                 texture.sample(uv_coord)
             },
         }
     }
 
-    fn shininess(&self) -> Option<Vec<f32>> {
-        self.shininess.clone()
+    fn material(&self) -> Option<&dyn Material> {
+        self.material.as_deref()
+    }
+
+    fn ior(&self) -> f32 {
+        self.ior
+    }
+
+    fn emission(&self) -> Option<Color> {
+        self.emission
+    }
+
+    fn sample_direction_from(&self, origin: &MatVec<3>, rng: &mut StdRng) -> Option<MatVec<3>> {
+        // Uniform over the cone of directions that hit the sphere
+        let (axis, cos_max) = self.cone_from(origin)?;
+        let cos_theta: f32 = 1.0 - rng.gen::<f32>() * (1.0 - cos_max);
+        let sin_theta: f32 = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi: f32 = 2.0 * std::f32::consts::PI * rng.gen::<f32>();
+        let right: MatVec<3> = utils::camera_right(&axis, &MatVec::from_array([0.0, 1.0, 0.0]));
+        let up: MatVec<3> = right.cross(&axis);
+        Some((sin_theta * phi.cos()) * right + (sin_theta * phi.sin()) * up + cos_theta * axis)
+    }
+
+    fn direction_pdf_from(&self, origin: &MatVec<3>, direction: &MatVec<3>) -> f32 {
+        match self.cone_from(origin) {
+            Some((axis, cos_max)) if direction.normalize().dot(axis) >= cos_max => {
+                1.0 / (2.0 * std::f32::consts::PI * (1.0 - cos_max).max(1e-7))
+            },
+            _ => 0.0,
+        }
+    }
+
+    fn perturb_normal(&self, normal: &MatVec<3>, rng: &mut StdRng) -> MatVec<3> {
+        normal.perturb(0.5_f32, self.roughness, rng).normalize()
+    }
+
+    fn roughness(&self) -> f32 {
+        0.5 * self.roughness
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<String> = Vec::new();
+        if !(self.radius > 0.0 && self.radius.is_finite()) {
+            errors.push(format!("sphere has a radius of {}, which is not positive", self.radius));
+        }
+        if !self.center.iter().all(|c| c.is_finite()) {
+            errors.push(format!("sphere has a center of {:?}, which is not finite", self.center.into_array()));
+        }
+        errors
+    }
+
+    fn is_solid(&self) -> bool {
+        true
     }
 
-    fn transparency(&self) -> Option<Vec<f32>> {
-        self.transparency.clone()
+    fn intersect_interval(&self, ray: &Ray) -> Vec<(Intersection, Intersection)> {
+
+        let interval = match self.transform {
+            None => self.interval_object_space(ray),
+            Some(ref transform) => self.interval_object_space(&transform.ray_to_object(ray))
+                .map(|(entry, exit)| (transform.intersection_to_world(ray, entry), transform.intersection_to_world(ray, exit))),
+        };
+
+        interval.into_iter().collect()
+
     }
 
 }
 
 /// Represents a plane in 3D space.
+/// A checkered plane alternates between two colors in square cells of a given size, laid out in world space.
 #[allow(non_snake_case)]
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Plane {
     pub normal: MatVec<3>,
     pub D: f32,
     pub color: Color,
+    pub checker: Option<(Color, Color, f32)>, // The two colors and the cell size
+    pub emission: Option<Color>,
 }
 
 impl Plane {
 
     pub fn new(coeffs: MatVec<4>, context: &InputState) -> Plane {
-        println!("Making plane with coeffs: {:?}, color: {:?}", coeffs, context.color);
         Plane {
-            normal: MatVec::new(vec![*coeffs.get(0), *coeffs.get(1), *coeffs.get(2)]).normalize(),
+            normal: MatVec::from_array([*coeffs.get(0), *coeffs.get(1), *coeffs.get(2)]).normalize(),
             D: *coeffs.get(3),
             color: context.color.clone(),
+            checker: None,
+            emission: context.emission,
+        }
+    }
+
+    pub fn checker(coeffs: MatVec<4>, color_a: Color, color_b: Color, size: f32, context: &InputState) -> Plane {
+        Plane {
+            checker: Some((color_a, color_b, size)),
+            ..Plane::new(coeffs, context)
         }
     }
 
+    /// Two orthonormal directions that span the plane, the axes of the checker cells.
+    /// The first is the world axis least aligned with the normal, projected onto the plane.
+    fn basis(&self) -> (MatVec<3>, MatVec<3>) {
+        let axis: MatVec<3> = if self.normal[0].abs() < 0.9 {
+            MatVec::from_array([1.0, 0.0, 0.0])
+        } else {
+            MatVec::from_array([0.0, 1.0, 0.0])
+        };
+        let u: MatVec<3> = axis.reject_from(&self.normal).normalize();
+        (u, self.normal.cross(&u))
+    }
+
 }
 
 impl SceneObject for Plane {
 
-    fn propagate(&self, incident: &Ray) -> Ray {
-        todo!("not implemented yet");
+    fn clone_box(&self) -> Box<dyn SceneObject> {
+        Box::new(self.clone())
     }
-    
+
+    #[cfg(feature = "serde")]
+    fn serialized(&self) -> SerializedShape {
+        SerializedShape::PLANE(self.clone())
+    }
+
     fn intersect(&self, ray: &Ray) -> IntersectionPayload {
 
         let denom: f32 = self.normal.clone().dot(ray.direction.clone());
@@ -207,114 +454,1123 @@ impl SceneObject for Plane {
             normal,
             distance: t,
             residual: false,
+            primitive: None,
         })
 
     }
 
-    fn color_at(&self, _point: &MatVec<3>) -> Color {
+    fn color_at(&self, hit: &Intersection) -> Color {
+
+        match self.checker {
+            None => self.color,
+            Some((color_a, color_b, size)) => {
+                let (u, v) = self.basis();
+                let cell: f32 = (hit.point.dot(u) / size).floor() + (hit.point.dot(v) / size).floor();
+                if cell.rem_euclid(2.0) < 1.0 { color_a } else { color_b }
+            },
+        }
+
+    }
 
-        self.color.clone()
+    fn validate(&self) -> Vec<String> {
+        // A zero normal is normalized into NaNs
+        if !self.normal.iter().all(|c| c.is_finite()) {
+            return vec![String::from("plane has a zero normal")];
+        }
+        match self.checker {
+            Some((_, _, size)) if size <= 0.0 => vec![format!("checker plane has a cell size of {}, it must be positive", size)],
+            _ => Vec::new(),
+        }
+    }
 
+    fn emission(&self) -> Option<Color> {
+        self.emission
     }
 
 }
 
-/// Represents a triangle in 3D space.
-pub struct Triangle {
-    pub verticies: [MatVec<3>; 3],
+/// Represents a disk in 3D space, the part of a plane within `radius` of `center`.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Disk {
+    pub center: MatVec<3>,
+    pub normal: MatVec<3>,
+    pub radius: f32,
     pub color: Color,
-    pub texture: Option<Texture2d>,
-    pub texcoords: Option<Vec<MatVec<2>>>,
-    roughness: f32,
+    pub transform: Option<Transform>,
+    pub material: Option<Box<dyn Material>>,
+    pub ior: f32,
+    pub emission: Option<Color>,
 }
 
-impl Triangle {
-    pub fn new(indices: Vec<i32>, context: &InputState) -> Triangle {
-        assert_eq!(indices.len(), 3, "Triangle must have exactly 3 vertices");
-        println!("Making triangle with vertices: {:?}, color: {:?}", indices, context.color);
+impl Disk {
 
-        let verticies: Vec<MatVec<3>> = indices.iter().map(|&i| {
-            if i < 0 {
-                context.verticies[(context.verticies.len() as i32 + i) as usize].clone()
-            } else {
-                context.verticies[(i - 1) as usize].clone()
-            }
-        }).collect();
+    pub fn new(center: MatVec<3>, normal: MatVec<3>, radius: f32, context: &InputState) -> Disk {
 
-        let texcoords: Option<Vec<MatVec<2>>> = if context.texture != "none" && !context.texcoords.is_empty() {
-            Some(indices.iter().map(|&i| {
-            if i < 0 {
-                context.texcoords[(context.texcoords.len() as i32 + i) as usize].clone()
-            } else {
-                context.texcoords[(i - 1) as usize].clone()
-            }
-            }).collect())
+        let material: Box<dyn Material> = material::from_context(context);
+
+        Disk {
+            center,
+            normal: normal.normalize(),
+            radius,
+            color: context.color,
+            transform: context.current_transform(),
+            material: Some(material),
+            ior: context.index_of_refraction,
+            emission: context.emission,
+        }
+
+    }
+
+    /// Intersects a ray given in the object space of the disk.
+    fn intersect_object_space(&self, ray: &Ray) -> IntersectionPayload {
+
+        let direction: MatVec<3> = ray.direction.normalize();
+
+        let denom: f32 = self.normal.dot(direction);
+
+        if denom.abs() < 0.0001 {
+            return None;
+        }
+
+        // Intersect the supporting plane, then reject hits outside the radius
+        let t: f32 = self.normal.dot(self.center - ray.origin) / denom;
+
+        if t < 0.0001 {
+            return None;
+        }
+
+        let intersection_point: MatVec<3> = ray.origin + t * direction;
+
+        if (intersection_point - self.center).magnitude() > self.radius {
+            return None;
+        }
+
+        // The disk is two sided, so the normal always faces the incoming ray
+        let normal: MatVec<3> = if denom > 0.0 {
+            -1.0f32 * self.normal
         } else {
-            None
+            self.normal
         };
 
-        let texture: Option<Texture2d> = match context.texture.as_str() {
-            "none" => None,
-            _ => {
-                Some(Texture2d::new(&context.texture))
+        Some(Intersection {
+            shape_id: None,
+            point: intersection_point,
+            normal,
+            distance: t,
+            residual: false,
+            primitive: None,
+        })
+
+    }
+
+}
+
+impl SceneObject for Disk {
+
+    fn clone_box(&self) -> Box<dyn SceneObject> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "serde")]
+    fn serialized(&self) -> SerializedShape {
+        SerializedShape::DISK(self.clone())
+    }
+
+    fn bounds(&self) -> Option<(MatVec<3>, MatVec<3>)> {
+        let extent: MatVec<3> = MatVec::splat(self.radius);
+        Some(world_bounds(self.center - extent, self.center + extent, &self.transform))
+    }
+
+    fn intersect(&self, ray: &Ray) -> IntersectionPayload {
+
+        match self.transform {
+            None => self.intersect_object_space(ray),
+            Some(ref transform) => {
+                let intersection = self.intersect_object_space(&transform.ray_to_object(ray))?;
+                Some(transform.intersection_to_world(ray, intersection))
             },
-        };
+        }
 
-        Triangle {
-            verticies: [verticies[0].clone(), verticies[1].clone(), verticies[2].clone()],
-            color: context.color.clone(),
-            texture,
-            texcoords,
-            roughness: 0.0_f32,
+    }
+
+    fn color_at(&self, _hit: &Intersection) -> Color {
+
+        self.color
+
+    }
+
+    fn material(&self) -> Option<&dyn Material> {
+        self.material.as_deref()
+    }
+
+    fn ior(&self) -> f32 {
+        self.ior
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<String> = Vec::new();
+        if !(self.radius > 0.0 && self.radius.is_finite()) {
+            errors.push(format!("disk has a radius of {}, which is not positive", self.radius));
+        }
+        if !self.normal.iter().all(|c| c.is_finite()) {
+            errors.push(String::from("disk has a zero normal"));
         }
+        errors
     }
 
-    // fn uv_at(&self, point: &MatVec<3>) -> MatVec<2> {
-    //     let texcoords = self.texcoords.as_ref().unwrap();
-    //     let v0 = self.vertices[1].clone() - self.vertices[0].clone();
-    //     let v1 = self.vertices[2].clone() - self.vertices[0].clone();
-    //     let v2 = point.clone() - self.vertices[0].clone();
-    
-    //     // Compute dot products for the barycentric coordinates
-    //     let d00 = v0.dot(v0.clone());
-    //     let d01 = v0.dot(v1.clone());
-    //     let d11 = v1.dot(v1.clone());
-    //     let d20 = v2.dot(v0.clone());
-    //     let d21 = v2.dot(v1.clone());
-    
-    //     // Compute the denominator of the barycentric coordinates
-    //     let denom = d00 * d11 - d01 * d01;
-    
-    //     if denom.abs() < 1e-6 {
-    //         panic!("Triangle vertices are degenerate or too close together!");
-    //     }
-    
-    //     // Barycentric coordinates
-    //     let v = (d11 * d20 - d01 * d21) / denom;
-    //     let w = (d00 * d21 - d01 * d20) / denom;
-    //     let u = 1.0 - v - w;
-    
-    //     // Interpolate the UV coordinates using barycentric weights
-    //     texcoords[0].clone() * u + texcoords[1].clone() * v + texcoords[2].clone() * w
-    // }
-    
+    fn emission(&self) -> Option<Color> {
+        self.emission
+    }
 
 }
 
-impl SceneObject for Triangle {
+/// Represents a torus in 3D space, the surface swept by a circle of radius `minor`
+/// whose center travels around a circle of radius `major` about `axis`.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Torus {
+    pub center: MatVec<3>,
+    pub axis: MatVec<3>,
+    pub major: f32,
+    pub minor: f32,
+    pub color: Color,
+    pub transform: Option<Transform>,
+    pub material: Option<Box<dyn Material>>,
+    pub ior: f32,
+    pub emission: Option<Color>,
+}
 
+impl Torus {
+
+    pub fn new(center: MatVec<3>, axis: MatVec<3>, major: f32, minor: f32, context: &InputState) -> Torus {
+
+        let material: Box<dyn Material> = material::from_context(context);
+
+        Torus {
+            center,
+            axis: axis.normalize(),
+            major,
+            minor,
+            color: context.color,
+            transform: context.current_transform(),
+            material: Some(material),
+            ior: context.index_of_refraction,
+            emission: context.emission,
+        }
 
-    fn propagate(&self, incident: &Ray) -> Ray {
-        todo!("not implemented yet");
     }
 
-    fn intersect(&self, ray: &Ray) -> IntersectionPayload {
-            
-            let edge1: MatVec<3> = self.verticies[1].clone() - self.verticies[0].clone();
-            let edge2: MatVec<3> = self.verticies[2].clone() - self.verticies[0].clone();
-    
-            let h: MatVec<3> = ray.direction.clone().cross(&edge2);
-            let a: f32 = edge1.clone().dot(h.clone());
+    /// Intersects a ray given in the object space of the torus.
+    /// A point `p` relative to the center lies on the torus when
+    /// `(|p|^2 + R^2 - r^2)^2 = 4 R^2 (|p|^2 - (p . axis)^2)`,
+    /// substituting `p = o + t d` gives a quartic in `t`.
+    fn intersect_object_space(&self, ray: &Ray) -> IntersectionPayload {
+
+        let direction: MatVec<3> = ray.direction.normalize();
+        let origin: MatVec<3> = ray.origin - self.center;
+
+        // Reject rays that miss the bounding sphere, and otherwise start the quartic from where the ray
+        // enters it, so the coefficients stay small even when the torus is far from the ray origin
+        let bound: f32 = self.major + self.minor;
+        let tc: f32 = -origin.dot(direction);
+        let d2: f32 = origin.dot(origin) - tc * tc;
+
+        if d2 > bound * bound {
+            return None;
+        }
+
+        let t_start: f32 = f32::max(0.0, tc - f32::sqrt(bound * bound - d2));
+
+        if tc + f32::sqrt(bound * bound - d2) < 0.0 {
+            return None;
+        }
+
+        let o: MatVec<3> = origin + t_start * direction;
+
+        let (ox, oy, oz) = (o[0] as f64, o[1] as f64, o[2] as f64);
+        let (dx, dy, dz) = (direction[0] as f64, direction[1] as f64, direction[2] as f64);
+        let (ax, ay, az) = (self.axis[0] as f64, self.axis[1] as f64, self.axis[2] as f64);
+        let major2: f64 = (self.major as f64).powi(2);
+        let minor2: f64 = (self.minor as f64).powi(2);
+
+        let oo: f64 = ox * ox + oy * oy + oz * oz;
+        let od: f64 = ox * dx + oy * dy + oz * dz;
+        let dd: f64 = dx * dx + dy * dy + dz * dz;
+        let oa: f64 = ox * ax + oy * ay + oz * az;
+        let da: f64 = dx * ax + dy * ay + dz * az;
+        let k: f64 = oo + major2 - minor2;
+
+        let roots: Vec<f64> = utils::solve_quartic(
+            dd * dd,
+            4.0 * dd * od,
+            2.0 * dd * k + 4.0 * od * od - 4.0 * major2 * (dd - da * da),
+            4.0 * od * k - 8.0 * major2 * (od - oa * da),
+            k * k - 4.0 * major2 * (oo - oa * oa),
+            0.0,
+            2.0 * bound as f64,
+        );
+
+        let t: f32 = t_start + roots.into_iter().map(|t| t as f32).find(|&t| t_start + t > 0.0001)?;
+
+        let intersection_point: MatVec<3> = ray.origin + t * direction;
+
+        // The normal points away from the closest point on the circle through the middle of the tube
+        let p: MatVec<3> = intersection_point - self.center;
+        let radial: MatVec<3> = p - p.dot(self.axis) * self.axis;
+        let ring_point: MatVec<3> = self.major * radial.normalize();
+        let normal: MatVec<3> = (p - ring_point).normalize();
+
+        Some(Intersection {
+            shape_id: None,
+            point: intersection_point,
+            normal,
+            distance: t,
+            residual: false,
+            primitive: None,
+        })
+
+    }
+
+}
+
+impl SceneObject for Torus {
+
+    fn clone_box(&self) -> Box<dyn SceneObject> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "serde")]
+    fn serialized(&self) -> SerializedShape {
+        SerializedShape::TORUS(self.clone())
+    }
+
+    fn bounds(&self) -> Option<(MatVec<3>, MatVec<3>)> {
+        let extent: MatVec<3> = MatVec::splat(self.major + self.minor);
+        Some(world_bounds(self.center - extent, self.center + extent, &self.transform))
+    }
+
+    fn intersect(&self, ray: &Ray) -> IntersectionPayload {
+
+        match self.transform {
+            None => self.intersect_object_space(ray),
+            Some(ref transform) => {
+                let intersection = self.intersect_object_space(&transform.ray_to_object(ray))?;
+                Some(transform.intersection_to_world(ray, intersection))
+            },
+        }
+
+    }
+
+    fn color_at(&self, _hit: &Intersection) -> Color {
+
+        self.color
+
+    }
+
+    fn material(&self) -> Option<&dyn Material> {
+        self.material.as_deref()
+    }
+
+    fn ior(&self) -> f32 {
+        self.ior
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<String> = Vec::new();
+        if !(self.major > 0.0 && self.minor > 0.0 && self.major.is_finite() && self.minor.is_finite()) {
+            errors.push(format!("torus has radii of {} and {}, which are not both positive", self.major, self.minor));
+        }
+        if !self.axis.iter().all(|c| c.is_finite()) {
+            errors.push(String::from("torus has a zero axis"));
+        }
+        errors
+    }
+
+    fn emission(&self) -> Option<Color> {
+        self.emission
+    }
+
+}
+
+/// Represents a finite cone in 3D space, with its tip at `apex`, opening along `axis` by `angle` radians
+/// from the axis, and cut off `height` along the axis, where it is closed by a flat base unless it is open.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Cone {
+    pub apex: MatVec<3>,
+    pub axis: MatVec<3>,
+    pub angle: f32,
+    pub height: f32,
+    pub capped: bool,
+    pub color: Color,
+    pub transform: Option<Transform>,
+    pub material: Option<Box<dyn Material>>,
+    pub ior: f32,
+    pub emission: Option<Color>,
+}
+
+impl Cone {
+
+    /// The angle between the axis and the side of the cone is given in degrees.
+    pub fn new(apex: MatVec<3>, axis: MatVec<3>, angle: f32, height: f32, capped: bool, context: &InputState) -> Cone {
+
+        let material: Box<dyn Material> = material::from_context(context);
+
+        Cone {
+            apex,
+            axis: axis.normalize(),
+            angle: angle.to_radians(),
+            height,
+            capped,
+            color: context.color,
+            transform: context.current_transform(),
+            material: Some(material),
+            ior: context.index_of_refraction,
+            emission: context.emission,
+        }
+
+    }
+
+    /// Intersects a ray given in the object space of the cone.
+    /// A point `p` relative to the apex lies on the infinite double cone when `(p . axis)^2 = cos^2(angle) |p|^2`,
+    /// substituting `p = o + t d` gives a quadratic in `t`, whose roots are kept on the half
+    /// of the cone between the apex and the base.
+    fn intersect_object_space(&self, ray: &Ray) -> IntersectionPayload {
+
+        let direction: MatVec<3> = ray.direction.normalize();
+        let origin: MatVec<3> = ray.origin - self.apex;
+
+        let cos2: f32 = self.angle.cos().powi(2);
+        let da: f32 = direction.dot(self.axis);
+        let oa: f32 = origin.dot(self.axis);
+
+        let a: f32 = da * da - cos2;
+        let b: f32 = 2.0 * (da * oa - cos2 * direction.dot(origin));
+        let c: f32 = oa * oa - cos2 * origin.dot(origin);
+
+        // A ray parallel to the side of the cone crosses it only once, and a ray along the side does not at all
+        let roots: Vec<f32> = if a.abs() < 1e-6 {
+            if b.abs() < 1e-6 { Vec::new() } else { vec![-c / b] }
+        } else {
+            let discriminant: f32 = b * b - 4.0 * a * c;
+            if discriminant < 0.0 {
+                Vec::new()
+            } else {
+                let root: f32 = discriminant.sqrt();
+                vec![(-b - root) / (2.0 * a), (-b + root) / (2.0 * a)]
+            }
+        };
+
+        let side = roots.into_iter()
+            .filter(|&t| t > 0.0001)
+            .map(|t| (t, origin + t * direction))
+            .filter(|(_, p)| (0.0..=self.height).contains(&p.dot(self.axis)))
+            .map(|(t, p)| {
+                // Outward normal, the negated gradient of the implicit form. It vanishes at the apex,
+                // where a ray through the tip sees the cone face away from the base
+                let normal: MatVec<3> = (cos2 * p - p.dot(self.axis) * self.axis).try_normalize().unwrap_or(-1.0f32 * self.axis);
+                (t, normal)
+            })
+            .fold(None, |closest: Option<(f32, MatVec<3>)>, hit| match closest {
+                Some(closest) if closest.0 <= hit.0 => Some(closest),
+                _ => Some(hit),
+            });
+
+        let base = if self.capped && da.abs() > 1e-6 {
+            let t: f32 = (self.height - oa) / da;
+            let p: MatVec<3> = origin + t * direction;
+            let base_radius: f32 = self.height * self.angle.tan();
+            if t > 0.0001 && (p - self.height * self.axis).magnitude() <= base_radius { Some((t, self.axis)) } else { None }
+        } else {
+            None
+        };
+
+        let (t, normal) = match (side, base) {
+            (Some(side), Some(base)) => if side.0 <= base.0 { side } else { base },
+            (side, base) => side.or(base)?,
+        };
+
+        // An open cone can be seen from the inside, where it faces the ray
+        let normal: MatVec<3> = if !self.capped && normal.dot(direction) > 0.0 { -1.0f32 * normal } else { normal };
+
+        Some(Intersection {
+            shape_id: None,
+            point: ray.origin + t * direction,
+            normal,
+            distance: t,
+            residual: false,
+            primitive: None,
+        })
+
+    }
+
+}
+
+impl SceneObject for Cone {
+
+    fn clone_box(&self) -> Box<dyn SceneObject> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "serde")]
+    fn serialized(&self) -> SerializedShape {
+        SerializedShape::CONE(self.clone())
+    }
+
+    fn bounds(&self) -> Option<(MatVec<3>, MatVec<3>)> {
+        // The box around the apex and the rim of the base, which reaches out from the base center
+        // by the radius times the sine of the angle between each coordinate axis and the cone axis
+        let base_center: MatVec<3> = self.apex + self.height * self.axis;
+        let base_radius: f32 = self.height * self.angle.tan();
+        let extent: MatVec<3> = MatVec::from_array(std::array::from_fn(|i| base_radius * (1.0 - self.axis[i] * self.axis[i]).max(0.0).sqrt()));
+        let min: MatVec<3> = self.apex.component_min(&(base_center - extent));
+        let max: MatVec<3> = self.apex.component_max(&(base_center + extent));
+        Some(world_bounds(min, max, &self.transform))
+    }
+
+    fn intersect(&self, ray: &Ray) -> IntersectionPayload {
+
+        match self.transform {
+            None => self.intersect_object_space(ray),
+            Some(ref transform) => {
+                let intersection = self.intersect_object_space(&transform.ray_to_object(ray))?;
+                Some(transform.intersection_to_world(ray, intersection))
+            },
+        }
+
+    }
+
+    fn color_at(&self, _hit: &Intersection) -> Color {
+
+        self.color
+
+    }
+
+    fn material(&self) -> Option<&dyn Material> {
+        self.material.as_deref()
+    }
+
+    fn ior(&self) -> f32 {
+        self.ior
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<String> = Vec::new();
+        if !(self.angle > 0.0 && self.angle < std::f32::consts::FRAC_PI_2) {
+            errors.push(format!("cone has an angle of {} degrees, which is not between 0 and 90", self.angle.to_degrees()));
+        }
+        if !(self.height > 0.0 && self.height.is_finite()) {
+            errors.push(format!("cone has a height of {}, which is not positive", self.height));
+        }
+        if !self.axis.iter().all(|c| c.is_finite()) {
+            errors.push(String::from("cone has a zero axis"));
+        }
+        errors
+    }
+
+    fn emission(&self) -> Option<Color> {
+        self.emission
+    }
+
+}
+
+/// Represents a quadric surface in 3D space, the points where
+/// `Ax^2 + By^2 + Cz^2 + Dxy + Exz + Fyz + Gx + Hy + Iz + J = 0`.
+/// This covers ellipsoids, cylinders, cones, paraboloids and hyperboloids, most of which are unbounded.
+/// Normals point towards the side where the left hand side is positive.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Quadric {
+    pub coefficients: [f32; 10],
+    pub color: Color,
+    pub transform: Option<Transform>,
+    pub material: Option<Box<dyn Material>>,
+    pub ior: f32,
+    pub emission: Option<Color>,
+}
+
+impl Quadric {
+
+    pub fn new(coefficients: [f32; 10], context: &InputState) -> Quadric {
+
+        let material: Box<dyn Material> = material::from_context(context);
+
+        Quadric {
+            coefficients,
+            color: context.color,
+            transform: context.current_transform(),
+            material: Some(material),
+            ior: context.index_of_refraction,
+            emission: context.emission,
+        }
+
+    }
+
+    /// Gradient of the implicit function at a point, which is normal to the surface there.
+    fn gradient(&self, point: &MatVec<3>) -> MatVec<3> {
+
+        let [a, b, c, d, e, f, g, h, i, _] = self.coefficients;
+        let (x, y, z) = (point[0], point[1], point[2]);
+
+        MatVec::from_array([
+            2.0 * a * x + d * y + e * z + g,
+            2.0 * b * y + d * x + f * z + h,
+            2.0 * c * z + e * x + f * y + i,
+        ])
+
+    }
+
+    /// Intersects a ray given in the object space of the quadric.
+    /// Substituting `p = o + t d` into the implicit equation gives a quadratic in `t`.
+    fn intersect_object_space(&self, ray: &Ray) -> IntersectionPayload {
+
+        let direction: MatVec<3> = ray.direction.normalize();
+
+        let [a, b, c, d, e, f, g, h, i, j] = self.coefficients;
+        let (ox, oy, oz) = (ray.origin[0], ray.origin[1], ray.origin[2]);
+        let (dx, dy, dz) = (direction[0], direction[1], direction[2]);
+
+        let qa: f32 = a * dx * dx + b * dy * dy + c * dz * dz + d * dx * dy + e * dx * dz + f * dy * dz;
+        let qb: f32 = 2.0 * (a * ox * dx + b * oy * dy + c * oz * dz)
+                        + d * (ox * dy + oy * dx) + e * (ox * dz + oz * dx) + f * (oy * dz + oz * dy)
+                        + g * dx + h * dy + i * dz;
+        let qc: f32 = a * ox * ox + b * oy * oy + c * oz * oz + d * ox * oy + e * ox * oz + f * oy * oz
+                        + g * ox + h * oy + i * oz + j;
+
+        let t: f32 = if qa.abs() < 1e-6 {
+            // The ray is parallel to an asymptotic direction, so it crosses the surface at most once
+            if qb.abs() < 1e-6 {
+                return None;
+            }
+            -qc / qb
+        } else {
+            let discriminant: f32 = qb * qb - 4.0 * qa * qc;
+            if discriminant < 0.0 {
+                return None;
+            }
+            // Numerically stable form, avoids cancellation when one root is much smaller than the other
+            let q: f32 = -0.5 * (qb + qb.signum() * discriminant.sqrt());
+            let (t0, t1) = (q / qa, if q != 0.0 { qc / q } else { q / qa });
+            let (t0, t1) = if t0 < t1 { (t0, t1) } else { (t1, t0) };
+            if t0 > 0.0001 { t0 } else { t1 }
+        };
+
+        if t < 0.0001 {
+            return None;
+        }
+
+        let intersection_point: MatVec<3> = ray.origin + t * direction;
+        let normal: MatVec<3> = self.gradient(&intersection_point).normalize();
+
+        Some(Intersection {
+            shape_id: None,
+            point: intersection_point,
+            normal,
+            distance: t,
+            residual: false,
+            primitive: None,
+        })
+
+    }
+
+}
+
+impl SceneObject for Quadric {
+
+    fn clone_box(&self) -> Box<dyn SceneObject> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "serde")]
+    fn serialized(&self) -> SerializedShape {
+        SerializedShape::QUADRIC(self.clone())
+    }
+
+    fn intersect(&self, ray: &Ray) -> IntersectionPayload {
+
+        match self.transform {
+            None => self.intersect_object_space(ray),
+            Some(ref transform) => {
+                let intersection = self.intersect_object_space(&transform.ray_to_object(ray))?;
+                Some(transform.intersection_to_world(ray, intersection))
+            },
+        }
+
+    }
+
+    fn color_at(&self, _hit: &Intersection) -> Color {
+
+        self.color
+
+    }
+
+    fn material(&self) -> Option<&dyn Material> {
+        self.material.as_deref()
+    }
+
+    fn ior(&self) -> f32 {
+        self.ior
+    }
+
+    fn validate(&self) -> Vec<String> {
+        if self.coefficients.iter().all(|&c| c == 0.0) {
+            return vec![String::from("quadric has no nonzero coefficients")];
+        }
+        Vec::new()
+    }
+
+    fn emission(&self) -> Option<Color> {
+        self.emission
+    }
+
+}
+
+/// Represents an axis aligned box in 3D space, spanning from `min` to `max`.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Aabb {
+    pub min: MatVec<3>,
+    pub max: MatVec<3>,
+    pub color: Color,
+    pub transform: Option<Transform>,
+    pub material: Option<Box<dyn Material>>,
+    pub ior: f32,
+    pub emission: Option<Color>,
+}
+
+impl Aabb {
+
+    pub fn new(corner: MatVec<3>, opposite: MatVec<3>, context: &InputState) -> Aabb {
+
+        let material: Box<dyn Material> = material::from_context(context);
+
+        Aabb {
+            min: MatVec::from_array(std::array::from_fn(|i| f32::min(corner[i], opposite[i]))),
+            max: MatVec::from_array(std::array::from_fn(|i| f32::max(corner[i], opposite[i]))),
+            color: context.color,
+            transform: context.current_transform(),
+            material: Some(material),
+            ior: context.index_of_refraction,
+            emission: context.emission,
+        }
+
+    }
+
+    /// The span of a ray given in the object space of the box, using the slab method.
+    fn interval_object_space(&self, ray: &Ray) -> Option<(Intersection, Intersection)> {
+
+        let direction: MatVec<3> = ray.direction.normalize();
+
+        let mut t_near: f32 = f32::NEG_INFINITY;
+        let mut t_far: f32 = f32::INFINITY;
+        let mut near_axis: usize = 0;
+        let mut far_axis: usize = 0;
+
+        for axis in 0..3 {
+
+            if direction[axis].abs() < 1e-8 {
+                // Parallel to the slab, so the ray is either always or never within it
+                if ray.origin[axis] < self.min[axis] || ray.origin[axis] > self.max[axis] {
+                    return None;
+                }
+                continue;
+            }
+
+            let t0: f32 = (self.min[axis] - ray.origin[axis]) / direction[axis];
+            let t1: f32 = (self.max[axis] - ray.origin[axis]) / direction[axis];
+            let (t0, t1) = if t0 < t1 { (t0, t1) } else { (t1, t0) };
+
+            if t0 > t_near {
+                t_near = t0;
+                near_axis = axis;
+            }
+            if t1 < t_far {
+                t_far = t1;
+                far_axis = axis;
+            }
+
+        }
+
+        if t_near > t_far || t_far < 0.0001 {
+            return None;
+        }
+
+        // The normal of a face points along its axis, away from the box
+        let hit = |t: f32, axis: usize, sign: f32| {
+            Intersection {
+                shape_id: None,
+                point: ray.origin + t * direction,
+                normal: MatVec::from_array(std::array::from_fn(|i| if i == axis { sign } else { 0.0 })),
+                distance: t,
+                residual: false,
+                primitive: None,
+            }
+        };
+
+        Some((
+            hit(t_near, near_axis, -direction[near_axis].signum()),
+            hit(t_far, far_axis, direction[far_axis].signum()),
+        ))
+
+    }
+
+}
+
+impl SceneObject for Aabb {
+
+    fn clone_box(&self) -> Box<dyn SceneObject> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "serde")]
+    fn serialized(&self) -> SerializedShape {
+        SerializedShape::AABB(self.clone())
+    }
+
+    fn bounds(&self) -> Option<(MatVec<3>, MatVec<3>)> {
+        Some(world_bounds(self.min, self.max, &self.transform))
+    }
+
+    fn intersect(&self, ray: &Ray) -> IntersectionPayload {
+
+        // The nearest face in front of the ray, the exit when the ray starts inside the box
+        let (entry, exit) = self.intersect_interval(ray).pop()?;
+
+        if entry.distance > 0.0001 {
+            Some(entry)
+        } else {
+            Some(exit)
+        }
+
+    }
+
+    fn color_at(&self, _hit: &Intersection) -> Color {
+
+        self.color
+
+    }
+
+    fn material(&self) -> Option<&dyn Material> {
+        self.material.as_deref()
+    }
+
+    fn ior(&self) -> f32 {
+        self.ior
+    }
+
+    fn emission(&self) -> Option<Color> {
+        self.emission
+    }
+
+    fn is_solid(&self) -> bool {
+        true
+    }
+
+    fn intersect_interval(&self, ray: &Ray) -> Vec<(Intersection, Intersection)> {
+
+        let interval = match self.transform {
+            None => self.interval_object_space(ray),
+            Some(ref transform) => self.interval_object_space(&transform.ray_to_object(ray))
+                .map(|(entry, exit)| (transform.intersection_to_world(ray, entry), transform.intersection_to_world(ray, exit))),
+        };
+
+        interval.into_iter().collect()
+
+    }
+
+}
+
+/// Boolean operations that combine the volumes of the two children of a `Csg`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(rename_all = "lowercase"))]
+pub enum CsgOperation {
+    UNION,
+    INTERSECTION,
+    DIFFERENCE, // The first child with the second one carved out of it
+}
+
+impl CsgOperation {
+
+    /// Whether a point is inside the combined volume, given whether it is inside each child.
+    fn contains(&self, in_left: bool, in_right: bool) -> bool {
+        match self {
+            CsgOperation::UNION => in_left || in_right,
+            CsgOperation::INTERSECTION => in_left && in_right,
+            CsgOperation::DIFFERENCE => in_left && !in_right,
+        }
+    }
+
+}
+
+/// Constructive solid geometry, the volume obtained by combining two closed objects with a boolean operation.
+/// The result is colored and shaded like its first child, including the parts carved by the second one.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Csg {
+    pub left: Box<dyn SceneObject>,
+    pub right: Box<dyn SceneObject>,
+    pub operation: CsgOperation,
+}
+
+impl Csg {
+
+    pub fn new(left: Box<dyn SceneObject>, right: Box<dyn SceneObject>, operation: CsgOperation) -> Csg {
+
+        Csg {
+            left,
+            right,
+            operation,
+        }
+
+    }
+
+}
+
+impl SceneObject for Csg {
+
+    fn clone_box(&self) -> Box<dyn SceneObject> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "serde")]
+    fn serialized(&self) -> SerializedShape {
+        SerializedShape::CSG(self.clone())
+    }
+
+    #[cfg(feature = "serde")]
+    fn add_shared(&self, tables: &mut SharedTables) {
+        self.left.add_shared(tables);
+        self.right.add_shared(tables);
+    }
+
+    fn bounds(&self) -> Option<(MatVec<3>, MatVec<3>)> {
+        // Every operation stays within the union of its operands
+        let (left_min, left_max) = self.left.bounds()?;
+        let (right_min, right_max) = self.right.bounds()?;
+        Some((left_min.component_min(&right_min), left_max.component_max(&right_max)))
+    }
+
+    fn intersect(&self, ray: &Ray) -> IntersectionPayload {
+
+        self.intersect_interval(ray)
+            .into_iter()
+            .flat_map(|(entry, exit)| [entry, exit])
+            .find(|hit| hit.distance > 0.0001)
+
+    }
+
+    fn color_at(&self, hit: &Intersection) -> Color {
+
+        self.left.color_at(hit)
+
+    }
+
+    fn material(&self) -> Option<&dyn Material> {
+        self.left.material()
+    }
+
+    fn ior(&self) -> f32 {
+        self.left.ior()
+    }
+
+    fn perturb_normal(&self, normal: &MatVec<3>, rng: &mut StdRng) -> MatVec<3> {
+        self.left.perturb_normal(normal, rng)
+    }
+
+    fn roughness(&self) -> f32 {
+        self.left.roughness()
+    }
+
+    fn emission(&self) -> Option<Color> {
+        self.left.emission()
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<String> = Vec::new();
+        // Without a volume, an operand is taken to be empty, which silently loses part or all of the CSG
+        for (side, operand) in [("left", &self.left), ("right", &self.right)] {
+            if !operand.is_solid() {
+                errors.push(format!("{} operand of CSG does not enclose a volume, only spheres, boxes and CSGs can be combined", side));
+            }
+        }
+        let left = self.left.validate().into_iter().map(|error| format!("left operand of CSG: {}", error));
+        let right = self.right.validate().into_iter().map(|error| format!("right operand of CSG: {}", error));
+        errors.extend(left.chain(right));
+        errors
+    }
+
+    fn is_solid(&self) -> bool {
+        true
+    }
+
+    /// Walks the boundaries of both children along the ray, tracking whether the ray is inside each of them,
+    /// and keeps the boundaries where being inside the combined volume changes.
+    fn intersect_interval(&self, ray: &Ray) -> Vec<(Intersection, Intersection)> {
+
+        // (hit, whether it belongs to the left child, whether it is an entry)
+        let mut events: Vec<(Intersection, bool, bool)> = Vec::new();
+
+        for (child, is_left) in [(&self.left, true), (&self.right, false)] {
+            for (entry, exit) in child.intersect_interval(ray) {
+                events.push((entry, is_left, true));
+                events.push((exit, is_left, false));
+            }
+        }
+
+        events.sort_by(|a, b| a.0.distance.total_cmp(&b.0.distance));
+
+        let mut in_left: bool = false;
+        let mut in_right: bool = false;
+        let mut entry: Option<Intersection> = None;
+        let mut intervals: Vec<(Intersection, Intersection)> = Vec::new();
+
+        for (mut hit, is_left, is_entry) in events {
+
+            let was_inside: bool = self.operation.contains(in_left, in_right);
+
+            if is_left {
+                in_left = is_entry;
+            } else {
+                in_right = is_entry;
+            }
+
+            let inside: bool = self.operation.contains(in_left, in_right);
+
+            if inside == was_inside {
+                continue;
+            }
+
+            // Carved out surfaces face into the second child
+            if self.operation == CsgOperation::DIFFERENCE && !is_left {
+                hit.normal = -1.0f32 * hit.normal;
+            }
+
+            if inside {
+                entry = Some(hit);
+            } else if let Some(entry) = entry.take() {
+                intervals.push((entry, hit));
+            }
+
+        }
+
+        intervals
+
+    }
+
+}
+
+/// Represents a triangle in 3D space.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Triangle {
+    pub verticies: [MatVec<3>; 3],
+    pub color: Color,
+    #[cfg_attr(feature = "serde", serde(with = "crate::raytracer::shared::option"))]
+    pub texture: Option<Arc<Texture2d>>,
+    pub texcoords: Option<Vec<MatVec<2>>>,
+    pub vertcolors: Option<[Color; 3]>,
+    roughness: f32,
+    pub transform: Option<Transform>,
+    pub emission: Option<Color>,
+    backface_cull: bool,
+    alpha_cutoff: Option<f32>, // Hits where the texture alpha is below this are ignored, for cutouts
+    alpha_blend: bool, // Whether the texture alpha gives the coverage of the surface, see `SceneObject::coverage`
+    errors: Vec<String>, // Problems found while building the triangle, see `validate`
+}
+
+impl Triangle {
+    /// Builds a triangle from 1-based indices into the verticies given so far, negative indices count back from the last one.
+    /// Bad indices do not panic, they leave the triangle degenerate and are reported by `validate`.
+    pub fn new(indices: Vec<i32>, context: &InputState) -> Triangle {
+
+        let mut errors: Vec<String> = Vec::new();
+        if indices.len() != 3 {
+            errors.push(format!("triangle has {} vertex indices, expected 3", indices.len()));
+        }
+
+        // The element of `values` that every index refers to, or `None` if any of them is missing
+        let resolve = |values_len: usize| -> Option<[usize; 3]> {
+            let resolved: Vec<usize> = indices.iter().take(3).filter_map(|&i| Triangle::resolve_index(i, values_len)).collect();
+            resolved.try_into().ok()
+        };
+
+        let verticies: [MatVec<3>; 3] = match resolve(context.verticies.len()) {
+            Some(resolved) => resolved.map(|i| context.verticies[i]),
+            None => {
+                errors.push(format!("triangle references verticies {:?}, but {} are defined", indices, context.verticies.len()));
+                [MatVec::zero(); 3]
+            },
+        };
+
+        let texture: Option<Arc<Texture2d>> = context.texture.clone();
+
+        let texcoords: Option<Vec<MatVec<2>>> = if texture.is_some() && !context.texcoords.is_empty() {
+            match resolve(context.texcoords.len()) {
+                Some(resolved) => Some(resolved.iter().map(|&i| context.texcoords[i]).collect()),
+                None => {
+                    errors.push(format!("triangle references texcoords {:?}, but {} are defined", indices, context.texcoords.len()));
+                    None
+                },
+            }
+        } else {
+            None
+        };
+
+        let vertcolors: Option<[Color; 3]> = if !context.vertcolors.is_empty() {
+            match resolve(context.vertcolors.len()) {
+                Some(resolved) => Some(resolved.map(|i| context.vertcolors[i])),
+                None => {
+                    errors.push(format!("triangle references vertcolors {:?}, but {} are defined", indices, context.vertcolors.len()));
+                    None
+                },
+            }
+        } else {
+            None
+        };
+
+        Triangle {
+            verticies,
+            color: context.color.clone(),
+            texture,
+            texcoords,
+            vertcolors,
+            roughness: 0.0_f32,
+            transform: context.current_transform(),
+            emission: context.emission,
+            backface_cull: context.backface_cull,
+            alpha_cutoff: context.alpha_cutoff,
+            alpha_blend: context.alpha_blend,
+            errors,
+        }
+    }
+
+    /// Whether the texture is transparent enough at the barycentric coordinates `u`, `v` for the triangle to be cut out.
+    /// Only textured triangles with texcoords and an alpha cutoff can be cut out.
+    fn is_cut_out(&self, u: f32, v: f32) -> bool {
+        match (self.alpha_cutoff, &self.texture, &self.texcoords) {
+            (Some(cutoff), Some(texture), Some(texcoords)) => {
+                let uv_coord: MatVec<2> = (1.0 - u - v) * texcoords[0] + u * texcoords[1] + v * texcoords[2];
+                texture.sample_rgba(uv_coord)[3] < cutoff
+            },
+            _ => false,
+        }
+    }
+
+    /// Maps a 1-based index, or a negative one counting back from the end, to a position in a list of `len` values.
+    fn resolve_index(index: i32, len: usize) -> Option<usize> {
+        let position: i64 = if index < 0 { len as i64 + index as i64 } else { index as i64 - 1 };
+        if (0..len as i64).contains(&position) { Some(position as usize) } else { None }
+    }
+
+    /// Intersects a ray given in the object space of the triangle.
+    fn intersect_object_space(&self, ray: &Ray) -> IntersectionPayload {
+            
+            let edge1: MatVec<3> = self.verticies[1].clone() - self.verticies[0].clone();
+            let edge2: MatVec<3> = self.verticies[2].clone() - self.verticies[0].clone();
+    
+            let h: MatVec<3> = ray.direction.clone().cross(&edge2);
+            let a: f32 = edge1.clone().dot(h.clone());
     
             if a.abs() < 0.0001 {
                 return None;
@@ -340,14 +1596,21 @@ impl SceneObject for Triangle {
             if t < 0.0001 {
                 return None;
             }
+
+            if self.is_cut_out(u, v) {
+                return None;
+            }
     
             let intersection_point: MatVec<3> = ray.origin.clone() + t * ray.direction.clone();
-            let mut normal: MatVec<3> = edge1.clone().cross(&edge2).normalize();
+            // A degenerate triangle has no normal, and no surface to hit
+            let mut normal: MatVec<3> = edge1.clone().cross(&edge2).try_normalize()?;
             if normal.dot(ray.direction.clone()) > 0.0 {
+                // The ray hit the back side, given by the winding order of the verticies
+                if self.backface_cull {
+                    return None;
+                }
                 normal = -1.0f32 * normal;
             }
-
-            normal = normal.perturb(0.01, self.roughness).normalize();
     
             Some(Intersection {
                 shape_id: None,
@@ -355,24 +1618,424 @@ impl SceneObject for Triangle {
                 normal,
                 distance: t,
                 residual: false,
+                primitive: None,
             })
             
     }
 
-    fn color_at(&self, point: &MatVec<3>) -> Color {
+}
+
+impl SceneObject for Triangle {
+
+    fn clone_box(&self) -> Box<dyn SceneObject> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "serde")]
+    fn serialized(&self) -> SerializedShape {
+        SerializedShape::TRIANGLE(self.clone())
+    }
+
+    #[cfg(feature = "serde")]
+    fn add_shared(&self, tables: &mut SharedTables) {
+        if let Some(ref texture) = self.texture {
+            tables.add(texture);
+        }
+    }
+
+    fn bounds(&self) -> Option<(MatVec<3>, MatVec<3>)> {
+        let min: MatVec<3> = self.verticies[0].component_min(&self.verticies[1]).component_min(&self.verticies[2]);
+        let max: MatVec<3> = self.verticies[0].component_max(&self.verticies[1]).component_max(&self.verticies[2]);
+        Some(world_bounds(min, max, &self.transform))
+    }
+
+    fn intersect(&self, ray: &Ray) -> IntersectionPayload {
+
+        match self.transform {
+            None => self.intersect_object_space(ray),
+            Some(ref transform) => {
+                let intersection = self.intersect_object_space(&transform.ray_to_object(ray))?;
+                Some(transform.intersection_to_world(ray, intersection))
+            },
+        }
+
+    }
+
+    fn color_at(&self, hit: &Intersection) -> Color {
         match self.texture {
 
-            None => self.color.clone(),
+            None => match self.vertcolors {
+                None => self.color,
+                Some(colors) => {
+                    let local_point: MatVec<3> = match self.transform {
+                        None => hit.point,
+                        Some(ref transform) => transform.point_to_object(&hit.point),
+                    };
+                    // A degenerate triangle has no weights to blend the vertex colors with
+                    match utils::barycentric_weights(&local_point, &self.verticies) {
+                        None => self.color,
+                        Some(weights) => weights[0] * colors[0] + weights[1] * colors[1] + weights[2] * colors[2],
+                    }
+                },
+            },
 
             Some(ref texture) => {
-                // todo!("Texture mapping for triangles doesnt work yet");
-                // let uv_coord: MatVec<2> = self.uv_at(point);
-                let uv_coord: MatVec<2> = utils::barycentric_uv(point, self.verticies.to_vec(), self.texcoords.as_ref().unwrap().clone());
-                texture.sample(uv_coord)
+                let local_point: MatVec<3> = match self.transform {
+                    None => hit.point,
+                    Some(ref transform) => transform.point_to_object(&hit.point),
+                };
+                // Without texcoords, or on a degenerate triangle, there is nothing to sample
+                let uv_coord: Option<MatVec<2>> = self.texcoords.as_ref()
+                    .and_then(|texcoords| utils::barycentric_uv(&local_point, self.verticies.to_vec(), texcoords.clone()));
+                match uv_coord {
+                    None => self.color,
+                    Some(uv_coord) => texture.sample(uv_coord),
+                }
             },
             
         }
 
     }
 
-}
\ No newline at end of file
+    /// The alpha of the texture under the point with `alpha_blend`, triangles without it, or without
+    /// a texture to sample, are opaque.
+    fn coverage(&self, hit: &Intersection) -> f32 {
+        match (self.alpha_blend, &self.texture, &self.texcoords) {
+            (true, Some(texture), Some(texcoords)) => {
+                let local_point: MatVec<3> = match self.transform {
+                    None => hit.point,
+                    Some(ref transform) => transform.point_to_object(&hit.point),
+                };
+                match utils::barycentric_uv(&local_point, self.verticies.to_vec(), texcoords.clone()) {
+                    None => 1.0,
+                    Some(uv_coord) => texture.sample_rgba(uv_coord)[3],
+                }
+            },
+            _ => 1.0,
+        }
+    }
+
+    fn perturb_normal(&self, normal: &MatVec<3>, rng: &mut StdRng) -> MatVec<3> {
+        normal.perturb(0.01, self.roughness, rng).normalize()
+    }
+
+    fn nearest_edge(&self, hit: &Intersection) -> Option<MatVec<3>> {
+        let local_point: MatVec<3> = match self.transform {
+            None => hit.point,
+            Some(ref transform) => transform.point_to_object(&hit.point),
+        };
+        let edge_point: MatVec<3> = (0..3)
+            .map(|i| utils::closest_point_on_segment(&local_point, &self.verticies[i], &self.verticies[(i + 1) % 3]))
+            .min_by(|a, b| (*a - local_point).magnitude().total_cmp(&(*b - local_point).magnitude()))?;
+        Some(match self.transform {
+            None => edge_point,
+            Some(ref transform) => transform.matrix.transform_point(&edge_point),
+        })
+    }
+
+    fn roughness(&self) -> f32 {
+        0.01 * self.roughness
+    }
+
+    fn validate(&self) -> Vec<String> {
+        self.errors.clone()
+    }
+
+    fn emission(&self) -> Option<Color> {
+        self.emission
+    }
+
+}
+
+/// A group of triangles that is stored once, and placed in the scene any number of times by `Instance`s.
+/// The triangles keep their own transforms, which map them into the local space of the mesh.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Mesh {
+    pub name: String, // Name the mesh was defined with, which also keys it when serialized, see `SharedTables`
+    pub triangles: Vec<Triangle>,
+    bounds: (MatVec<3>, MatVec<3>), // Corners of the box around the triangles, in the local space of the mesh
+}
+
+impl Mesh {
+
+    pub fn new(name: String, triangles: Vec<Triangle>) -> Mesh {
+
+        let corners = triangles.iter().flat_map(|triangle| triangle.verticies.iter().map(move |vertex| match triangle.transform {
+            None => *vertex,
+            Some(ref transform) => transform.matrix.transform_point(vertex),
+        }));
+        let empty: (MatVec<3>, MatVec<3>) = (MatVec::splat(f32::INFINITY), MatVec::splat(f32::NEG_INFINITY));
+        let bounds = corners.fold(empty, |(min, max), corner| (min.component_min(&corner), max.component_max(&corner)));
+
+        Mesh {
+            name,
+            triangles,
+            bounds,
+        }
+
+    }
+
+    /// Whether a ray in the local space of the mesh passes through its bounding box, using the slab method.
+    fn hits_bounds(&self, ray: &Ray) -> bool {
+
+        if self.triangles.is_empty() {
+            return false;
+        }
+
+        let (ref min, ref max) = self.bounds;
+        let mut t_near: f32 = f32::NEG_INFINITY;
+        let mut t_far: f32 = f32::INFINITY;
+        for axis in 0..3 {
+            let inverse: f32 = 1.0 / ray.direction[axis];
+            let t0: f32 = (min[axis] - ray.origin[axis]) * inverse;
+            let t1: f32 = (max[axis] - ray.origin[axis]) * inverse;
+            t_near = t_near.max(t0.min(t1));
+            t_far = t_far.min(t0.max(t1));
+        }
+
+        t_near <= t_far && t_far >= 0.0
+
+    }
+
+    /// The closest hit of a ray in the local space of the mesh with any of its triangles.
+    /// The hit records the index of the triangle as its `primitive`, so shading looks it up directly.
+    fn intersect(&self, ray: &Ray) -> IntersectionPayload {
+
+        if !self.hits_bounds(ray) {
+            return None;
+        }
+
+        self.triangles.iter()
+            .enumerate()
+            .filter_map(|(index, triangle)| triangle.intersect(ray).map(|hit| Intersection { primitive: Some(index), ..hit }))
+            .min_by(|a, b| a.distance.total_cmp(&b.distance))
+
+    }
+
+}
+
+/// A placement of a shared `Mesh` in the scene, with its own transform from the local space of the mesh to the world.
+/// Instances only hold a reference to the mesh, so placing a mesh many times does not copy its triangles.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Instance {
+    #[cfg_attr(feature = "serde", serde(with = "crate::raytracer::shared"))]
+    pub mesh: Arc<Mesh>,
+    pub transform: Option<Transform>,
+    pub emission: Option<Color>,
+}
+
+impl Instance {
+
+    pub fn new(mesh: Arc<Mesh>, context: &InputState) -> Instance {
+        Instance {
+            mesh,
+            transform: context.current_transform(),
+            emission: context.emission,
+        }
+    }
+
+    /// The triangle of the mesh a hit on the instance is on, and the hit moved into the local space of the mesh,
+    /// where the triangle lives. `None` for hits that do not record a triangle.
+    fn local_hit(&self, hit: &Intersection) -> Option<(&Triangle, Intersection)> {
+
+        let triangle: &Triangle = self.mesh.triangles.get(hit.primitive?)?;
+        let point: MatVec<3> = match self.transform {
+            None => hit.point,
+            Some(ref transform) => transform.point_to_object(&hit.point),
+        };
+
+        Some((triangle, Intersection { point, ..hit.clone() }))
+
+    }
+
+}
+
+impl SceneObject for Instance {
+
+    fn clone_box(&self) -> Box<dyn SceneObject> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "serde")]
+    fn serialized(&self) -> SerializedShape {
+        SerializedShape::INSTANCE(self.clone())
+    }
+
+    /// The textures of the triangles come first, as the mesh refers to them.
+    #[cfg(feature = "serde")]
+    fn add_shared(&self, tables: &mut SharedTables) {
+        for triangle in &self.mesh.triangles {
+            triangle.add_shared(tables);
+        }
+        tables.add(&self.mesh);
+    }
+
+    fn bounds(&self) -> Option<(MatVec<3>, MatVec<3>)> {
+        if self.mesh.triangles.is_empty() {
+            return None;
+        }
+        let (min, max) = self.mesh.bounds;
+        Some(world_bounds(min, max, &self.transform))
+    }
+
+    fn intersect(&self, ray: &Ray) -> IntersectionPayload {
+
+        match self.transform {
+            None => self.mesh.intersect(ray),
+            Some(ref transform) => {
+                let intersection = self.mesh.intersect(&transform.ray_to_object(ray))?;
+                Some(transform.intersection_to_world(ray, intersection))
+            },
+        }
+
+    }
+
+    /// The color of the triangle the hit is on, see `Mesh::intersect`.
+    fn color_at(&self, hit: &Intersection) -> Color {
+
+        match self.local_hit(hit) {
+            Some((triangle, local_hit)) => triangle.color_at(&local_hit),
+            None => MatVec::one(),
+        }
+
+    }
+
+    /// The coverage of the triangle the hit is on, see `color_at`.
+    fn coverage(&self, hit: &Intersection) -> f32 {
+
+        self.local_hit(hit).map_or(1.0, |(triangle, local_hit)| triangle.coverage(&local_hit))
+
+    }
+
+    /// The nearest edge of the triangle the hit is on, see `color_at`.
+    fn nearest_edge(&self, hit: &Intersection) -> Option<MatVec<3>> {
+
+        let (triangle, local_hit) = self.local_hit(hit)?;
+        let edge_point: MatVec<3> = triangle.nearest_edge(&local_hit)?;
+
+        Some(match self.transform {
+            None => edge_point,
+            Some(ref transform) => transform.matrix.transform_point(&edge_point),
+        })
+
+    }
+
+    fn validate(&self) -> Vec<String> {
+        self.mesh.triangles.iter()
+            .enumerate()
+            .flat_map(|(index, triangle)| triangle.validate().into_iter().map(move |error| format!("triangle {} of instanced mesh {}: {}", index, self.mesh.name, error)))
+            .collect()
+    }
+
+    fn emission(&self) -> Option<Color> {
+        self.emission
+    }
+
+}
+
+/// Every kind of shape, tagged with its name, which is how serde writes and reads a `Box<dyn SceneObject>`.
+/// Shapes are copied in and out of it, see `SceneObject::serialized`.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SerializedShape {
+    SPHERE(Sphere),
+    PLANE(Plane),
+    DISK(Disk),
+    TORUS(Torus),
+    CONE(Cone),
+    QUADRIC(Quadric),
+    AABB(Aabb),
+    CSG(Csg),
+    TRIANGLE(Triangle),
+    INSTANCE(Instance),
+}
+
+#[cfg(feature = "serde")]
+impl SerializedShape {
+
+    pub fn into_boxed(self) -> Box<dyn SceneObject> {
+        match self {
+            SerializedShape::SPHERE(inner) => Box::new(inner),
+            SerializedShape::PLANE(inner) => Box::new(inner),
+            SerializedShape::DISK(inner) => Box::new(inner),
+            SerializedShape::TORUS(inner) => Box::new(inner),
+            SerializedShape::CONE(inner) => Box::new(inner),
+            SerializedShape::QUADRIC(inner) => Box::new(inner),
+            SerializedShape::AABB(inner) => Box::new(inner),
+            SerializedShape::CSG(inner) => Box::new(inner),
+            SerializedShape::TRIANGLE(inner) => Box::new(inner),
+            SerializedShape::INSTANCE(inner) => Box::new(inner),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-4, "expected {}, got {}", expected, actual);
+    }
+
+    #[test]
+    fn unnormalized_ray_from_inside_a_sphere() {
+        let context = InputState::new();
+        let sphere = Sphere::new(MatVec::from_array([0.5, 0.0, 0.0]), 2.0, &context);
+        let ray = Ray::new(MatVec::zero(), MatVec::from_array([0.0, 3.0, 0.0]));
+        let hit = sphere.intersect(&ray).unwrap();
+        // The exit point is where 0.5^2 + t^2 = 2^2 along the unit direction
+        let expected: f32 = 3.75f32.sqrt();
+        assert_close(hit.distance, expected);
+        assert_close(hit.point[1], expected);
+        assert_close((hit.point - sphere.center).magnitude(), 2.0);
+    }
+
+    #[test]
+    fn transformed_hit_distance_is_euclidean() {
+        let context = InputState::new();
+        let ray = Ray::new(MatVec::zero(), MatVec::from_array([0.0, 0.0, -4.0]));
+        let sphere = Sphere::new(MatVec::from_array([0.0, 0.0, -5.0]), 1.0, &context);
+        let translated = Sphere {
+            center: MatVec::zero(),
+            transform: Some(Transform::new().translate(&MatVec::from_array([0.0, 0.0, -5.0]))),
+            ..sphere.clone()
+        };
+        assert_close(sphere.intersect(&ray).unwrap().distance, 4.0);
+        assert_close(translated.intersect(&ray).unwrap().distance, 4.0);
+    }
+
+    #[test]
+    fn instance_hit_records_the_triangle() {
+        let mut context = InputState::new();
+        context.verticies = vec![
+            MatVec::from_array([-1.0, -1.0, 0.0]),
+            MatVec::from_array([1.0, -1.0, 0.0]),
+            MatVec::from_array([1.0, 1.0, 0.0]),
+            MatVec::from_array([-1.0, 1.0, 0.0]),
+        ];
+        let mesh = Arc::new(Mesh::new(String::from("quad"), vec![Triangle::new(vec![1, 2, 3], &context), Triangle::new(vec![1, 3, 4], &context)]));
+        context.transform = Transform::new().translate(&MatVec::from_array([0.0, 0.0, -5.0]));
+        let instance = Instance::new(mesh, &context);
+        let ray = Ray::new(MatVec::zero(), MatVec::from_array([-0.1, 0.1, -1.0]));
+        let hit = instance.intersect(&ray).unwrap();
+        assert_eq!(hit.primitive, Some(1));
+        let edge: MatVec<3> = instance.nearest_edge(&hit).unwrap();
+        assert_close(edge[2], -5.0);
+    }
+
+    #[test]
+    fn csg_operands_must_enclose_a_volume() {
+        let context = InputState::new();
+        let sphere = || Box::new(Sphere::new(MatVec::zero(), 1.0, &context)) as Box<dyn SceneObject>;
+        let torus = Box::new(Torus::new(MatVec::zero(), MatVec::from_array([0.0, 1.0, 0.0]), 1.0, 0.25, &context));
+        assert!(Csg::new(sphere(), sphere(), CsgOperation::DIFFERENCE).validate().is_empty());
+        let errors: Vec<String> = Csg::new(sphere(), torus, CsgOperation::DIFFERENCE).validate();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("right operand"), "{}", errors[0]);
+    }
+
+}
@@ -0,0 +1,221 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error as _;
+use serde::ser::{Error as _, SerializeMap, SerializeStruct};
+use crate::raytracer::scene::Scene;
+use crate::raytracer::shapes::Mesh;
+use crate::raytracer::texture::Texture2d;
+
+/// The textures and meshes shared between the shapes of a scene. Serde writes each of them once, in a table keyed
+/// by its name, and the shapes write the key in its place, see `serialize_scene`. Reading the scene back gives
+/// every shape that used the same texture or mesh the same `Arc` again, rather than a copy of its own.
+#[derive(Default)]
+pub struct SharedTables {
+    textures: Vec<(String, Arc<Texture2d>)>,
+    meshes: Vec<(String, Arc<Mesh>)>,
+}
+
+/// Something stored once in the `SharedTables`, under a key made from its name.
+pub trait Shared: Sized {
+    /// Name of the entry, the file of an image texture or the name of a mesh. Entries with the same name are told
+    /// apart by a suffix, eg a file used with two different wrap modes.
+    fn name(&self) -> String;
+    fn table(tables: &SharedTables) -> &Vec<(String, Arc<Self>)>;
+    fn table_mut(tables: &mut SharedTables) -> &mut Vec<(String, Arc<Self>)>;
+}
+
+thread_local! {
+    /// The tables of the scene being written or read on this thread, empty otherwise.
+    static TABLES: RefCell<SharedTables> = RefCell::new(SharedTables::default());
+}
+
+impl SharedTables {
+
+    /// Adds an entry under a key of its own, unless the same `Arc` is already in the table.
+    pub fn add<T: Shared>(&mut self, value: &Arc<T>) {
+
+        let table: &mut Vec<(String, Arc<T>)> = T::table_mut(self);
+        if table.iter().any(|(_, entry)| Arc::ptr_eq(entry, value)) {
+            return;
+        }
+
+        let name: String = value.name();
+        let mut key: String = name.clone();
+        let mut count: usize = 1;
+        while table.iter().any(|(entry_key, _)| *entry_key == key) {
+            count += 1;
+            key = format!("{}#{}", name, count);
+        }
+        table.push((key, value.clone()));
+
+    }
+
+}
+
+impl Shared for Mesh {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+    fn table(tables: &SharedTables) -> &Vec<(String, Arc<Mesh>)> {
+        &tables.meshes
+    }
+    fn table_mut(tables: &mut SharedTables) -> &mut Vec<(String, Arc<Mesh>)> {
+        &mut tables.meshes
+    }
+}
+
+impl Shared for Texture2d {
+    fn name(&self) -> String {
+        self.source_name()
+    }
+    fn table(tables: &SharedTables) -> &Vec<(String, Arc<Texture2d>)> {
+        &tables.textures
+    }
+    fn table_mut(tables: &mut SharedTables) -> &mut Vec<(String, Arc<Texture2d>)> {
+        &mut tables.textures
+    }
+}
+
+/// Writes the scene after the tables of everything its shapes share, which are collected by `Scene::shared_tables`.
+/// The tables come first, so that they are read before the shapes that refer to them.
+pub fn serialize_scene<S: Serializer>(scene: &Scene, serializer: S) -> Result<S::Ok, S::Error> {
+
+    TABLES.with(|tables| *tables.borrow_mut() = scene.shared_tables());
+
+    let result = (|| {
+        let mut state = serializer.serialize_struct("Scene", 3)?;
+        state.serialize_field("textures", &Table::<Texture2d>(PhantomData))?;
+        state.serialize_field("meshes", &Table::<Mesh>(PhantomData))?;
+        state.serialize_field("scene", scene)?;
+        state.end()
+    })();
+
+    TABLES.with(|tables| tables.take());
+    result
+
+}
+
+/// Reads a scene written by `serialize_scene`. Fails if the scene comes before the tables, or refers to a key
+/// missing from them.
+pub fn deserialize_scene<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Scene, D::Error> {
+
+    #[derive(Deserialize)]
+    struct SerializedScene {
+        #[serde(rename = "textures")]
+        _textures: Table<Texture2d>,
+        #[serde(rename = "meshes")]
+        _meshes: Table<Mesh>,
+        scene: Scene,
+    }
+
+    TABLES.with(|tables| tables.take());
+    let result = SerializedScene::deserialize(deserializer);
+    TABLES.with(|tables| tables.take());
+    result.map(|serialized| serialized.scene)
+
+}
+
+/// One of the tables of the scene being written or read. Reading it fills the table, rather than returning it.
+struct Table<T>(PhantomData<T>);
+
+impl<T: Shared + Serialize> Serialize for Table<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        TABLES.with(|tables| {
+            let tables = tables.borrow();
+            let mut map = serializer.serialize_map(Some(T::table(&tables).len()))?;
+            for (key, value) in T::table(&tables) {
+                map.serialize_entry(key, value.as_ref())?;
+            }
+            map.end()
+        })
+    }
+}
+
+impl<'de, T: Shared + Deserialize<'de>> Deserialize<'de> for Table<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Table<T>, D::Error> {
+        let entries: HashMap<String, T> = HashMap::deserialize(deserializer)?;
+        TABLES.with(|tables| {
+            let mut tables = tables.borrow_mut();
+            T::table_mut(&mut tables).extend(entries.into_iter().map(|(key, value)| (key, Arc::new(value))));
+        });
+        Ok(Table(PhantomData))
+    }
+}
+
+/// Writes the key of a shared entry in its place, see `SharedTables`. For use with `#[serde(with = "...")]`.
+pub fn serialize<T: Shared, S: Serializer>(value: &Arc<T>, serializer: S) -> Result<S::Ok, S::Error> {
+    TABLES.with(|tables| {
+        let tables = tables.borrow();
+        match T::table(&tables).iter().find(|(_, entry)| Arc::ptr_eq(entry, value)) {
+            Some((key, _)) => serializer.serialize_str(key),
+            None => Err(S::Error::custom("shared texture or mesh is missing from the tables of the scene")),
+        }
+    })
+}
+
+/// Reads the key of a shared entry, and returns the entry, see `serialize`.
+pub fn deserialize<'de, T: Shared, D: Deserializer<'de>>(deserializer: D) -> Result<Arc<T>, D::Error> {
+    let key: String = String::deserialize(deserializer)?;
+    TABLES.with(|tables| {
+        let tables = tables.borrow();
+        match T::table(&tables).iter().find(|(entry_key, _)| *entry_key == key) {
+            Some((_, entry)) => Ok(entry.clone()),
+            None => Err(D::Error::custom(format!("unknown shared texture or mesh {}", key))),
+        }
+    })
+}
+
+/// Like the module itself, for optional entries.
+pub mod option {
+    use super::*;
+
+    pub fn serialize<T: Shared, S: Serializer>(value: &Option<Arc<T>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => serializer.serialize_some(&Key(value)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, T: Shared, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Arc<T>>, D::Error> {
+        let key: Option<KeyOf<T>> = Option::deserialize(deserializer)?;
+        Ok(key.map(|key| key.0))
+    }
+}
+
+/// Like the module itself, for entries that come with a scale, eg bump maps.
+pub mod scaled {
+    use super::*;
+
+    pub fn serialize<T: Shared, S: Serializer>(value: &Option<(Arc<T>, f32)>, serializer: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some((value, scale)) => serializer.serialize_some(&(Key(value), scale)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, T: Shared, D: Deserializer<'de>>(deserializer: D) -> Result<Option<(Arc<T>, f32)>, D::Error> {
+        let key: Option<(KeyOf<T>, f32)> = Option::deserialize(deserializer)?;
+        Ok(key.map(|(key, scale)| (key.0, scale)))
+    }
+}
+
+/// A shared entry written as its key, for entries nested in other values.
+struct Key<'a, T>(&'a Arc<T>);
+
+impl<T: Shared> Serialize for Key<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize(self.0, serializer)
+    }
+}
+
+/// A shared entry read from its key, see `Key`.
+struct KeyOf<T>(Arc<T>);
+
+impl<'de, T: Shared> Deserialize<'de> for KeyOf<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<KeyOf<T>, D::Error> {
+        deserialize(deserializer).map(KeyOf)
+    }
+}
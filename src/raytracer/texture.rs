@@ -1,44 +1,427 @@
-use image::{Pixel, RgbaImage};
-use crate::raytracer::{MatVec, Color};
+use image::{ColorType, ImageError, Pixel, Rgba32FImage, RgbaImage};
+use image::error::{DecodingError, ImageFormatHint};
+use crate::raytracer::{MatVec, Color, RGBA};
+use crate::raytracer::utils;
+use std::sync::Arc;
 
+/// How UV coordinates outside of `[0, 1]` are mapped back onto the texture.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(rename_all = "lowercase"))]
+pub enum WrapMode {
+
+    REPEAT,
+    CLAMP,
+    MIRROR,
+
+}
+
+impl WrapMode {
+
+    /// Maps a single texture coordinate into `[0, 1]`.
+    pub fn apply(&self, t: f32) -> f32 {
+        match self {
+            WrapMode::REPEAT => t - t.floor(),
+            WrapMode::CLAMP => t.clamp(0.0, 1.0),
+            WrapMode::MIRROR => {
+                let t = t.rem_euclid(2.0);
+                if t > 1.0 { 2.0 - t } else { t }
+            },
+        }
+    }
+
+}
+
+/// A 2D transform of texture coordinates: scaled, then rotated about the origin, then offset.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UvTransform {
+    pub scale: MatVec<2>,
+    pub rotation: f32, // In radians
+    pub offset: MatVec<2>,
+}
+
+impl UvTransform {
+
+    pub fn identity() -> UvTransform {
+        UvTransform {
+            scale: MatVec::from_array([1.0, 1.0]),
+            rotation: 0.0,
+            offset: MatVec::from_array([0.0, 0.0]),
+        }
+    }
+
+    pub fn apply(&self, uv_coord: MatVec<2>) -> MatVec<2> {
+        uv_coord.hadamard(&self.scale).rotate(self.rotation) + self.offset
+    }
+
+}
+
+/// The source of the colors of a texture, either a decoded image or a procedural pattern.
+/// Float images come from HDR formats, and hold linear, unclamped colors.
+enum TextureSource {
+    Image(RgbaImage),
+    FloatImage(Rgba32FImage),
+    Checker { scale: MatVec<2>, color_a: Color, color_b: Color },
+    Gradient { color_a: Color, color_b: Color },
+}
+
+/// A texture file that could not be opened or decoded.
+#[derive(Debug)]
+pub struct TextureError {
+    pub path: String,
+    pub error: image::ImageError,
+}
+
+impl std::fmt::Display for TextureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "texture '{}' failed to load: {}", self.path, self.error)
+    }
+}
+
+/// The source is shared, so the same decoded image can be used with different wrap modes.
+/// A texture is never changed once it is made, so it can be sampled from any number of threads at once.
+#[derive(Clone)]
 pub struct Texture2d {
     width: u32,
     height: u32,
-    image: RgbaImage,
+    source: Arc<TextureSource>,
+    wrap_mode: WrapMode,
+    uv_transform: UvTransform,
+    mipmaps: Option<Arc<Vec<Rgba32FImage>>>, // Linear colors, each level half the size of the one before, see `with_mipmaps`
+    #[cfg_attr(not(feature = "serde"), allow(dead_code))]
+    path: Option<String>, // File the image was decoded from, so a serialized texture can refer to it
 }
 
 impl Texture2d {
-    pub fn new(file: &String) -> Texture2d {
-        println!("Loading texture from file: {}", file);
-        let image = image::open(file).unwrap().to_rgba8();
-        let (width, height) = image.dimensions();
-        Texture2d {
+    /// Decodes an image file. PFM files, and float formats that `image` decodes such as Radiance HDR and
+    /// OpenEXR, keep their float colors, everything else is stored with 8 bits per channel.
+    pub fn new(file: &String) -> Result<Texture2d, TextureError> {
+        let to_error = |error: ImageError| TextureError { path: file.clone(), error };
+
+        let source: TextureSource = if file.to_lowercase().ends_with(".pfm") {
+            TextureSource::FloatImage(read_pfm(file).map_err(to_error)?)
+        } else {
+            let image = image::open(file).map_err(to_error)?;
+            match image.color() {
+                ColorType::Rgb32F | ColorType::Rgba32F => TextureSource::FloatImage(image.to_rgba32f()),
+                _ => TextureSource::Image(image.to_rgba8()),
+            }
+        };
+
+        let (width, height) = match source {
+            TextureSource::Image(ref image) => image.dimensions(),
+            TextureSource::FloatImage(ref image) => image.dimensions(),
+            _ => (0, 0),
+        };
+        Ok(Texture2d {
             width,
             height,
-            image,
+            source: Arc::new(source),
+            wrap_mode: WrapMode::CLAMP,
+            uv_transform: UvTransform::identity(),
+            mipmaps: None,
+            path: Some(file.clone()),
+        })
+    }
+
+    /// Procedural checkerboard with `scale[0]` by `scale[1]` cells across the UV square.
+    pub fn checker(scale: MatVec<2>, color_a: Color, color_b: Color) -> Texture2d {
+        Texture2d {
+            width: 0,
+            height: 0,
+            source: Arc::new(TextureSource::Checker { scale, color_a, color_b }),
+            wrap_mode: WrapMode::CLAMP,
+            uv_transform: UvTransform::identity(),
+            mipmaps: None,
+            path: None,
+        }
+    }
+
+    /// Procedural gradient going from `color_a` at `u = 0` to `color_b` at `u = 1`.
+    pub fn gradient(color_a: Color, color_b: Color) -> Texture2d {
+        Texture2d {
+            width: 0,
+            height: 0,
+            source: Arc::new(TextureSource::Gradient { color_a, color_b }),
+            wrap_mode: WrapMode::CLAMP,
+            uv_transform: UvTransform::identity(),
+            mipmaps: None,
+            path: None,
+        }
+    }
+
+    /// Returns a copy of the texture, sharing the same source, with a different wrap mode.
+    pub fn with_wrap_mode(&self, wrap_mode: WrapMode) -> Texture2d {
+        Texture2d {
+            wrap_mode,
+            ..self.clone()
+        }
+    }
+
+    /// Returns a copy of the texture, sharing the same source, with a different UV transform.
+    pub fn with_uv_transform(&self, uv_transform: UvTransform) -> Texture2d {
+        Texture2d {
+            uv_transform,
+            ..self.clone()
+        }
+    }
+
+    /// Returns a copy of the texture with a chain of mip levels for `sample_level`, the first one being the
+    /// image itself and every further one half the size of the one before, down to a single texel.
+    /// Procedural textures have no texels to average, and are returned without levels.
+    pub fn with_mipmaps(&self) -> Texture2d {
+        let base: Rgba32FImage = match *self.source {
+            TextureSource::Image(ref image) => Rgba32FImage::from_fn(self.width, self.height, |x, y| {
+                let channels = image.get_pixel(x, y).0;
+                let linear: [f32; 3] = std::array::from_fn(|i| utils::srgb_to_linear(channels[i] as f32 / 255.0));
+                image::Rgba([linear[0], linear[1], linear[2], channels[3] as f32 / 255.0])
+            }),
+            TextureSource::FloatImage(ref image) => image.clone(),
+            _ => return self.clone(),
+        };
+
+        let mut levels: Vec<Rgba32FImage> = vec![base];
+        loop {
+            let previous: &Rgba32FImage = levels.last().unwrap();
+            if previous.width() <= 1 && previous.height() <= 1 {
+                break;
+            }
+            // Every texel averages the 2x2 block under it, odd sizes repeat their last row or column
+            let level = Rgba32FImage::from_fn((previous.width() / 2).max(1), (previous.height() / 2).max(1), |x, y| {
+                let mut sum = [0.0f32; 4];
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let texel = previous.get_pixel((2 * x + dx).min(previous.width() - 1), (2 * y + dy).min(previous.height() - 1)).0;
+                    for i in 0..4 {
+                        sum[i] += 0.25 * texel[i];
+                    }
+                }
+                image::Rgba(sum)
+            });
+            levels.push(level);
+        }
+
+        Texture2d {
+            mipmaps: Some(Arc::new(levels)),
+            ..self.clone()
         }
     }
 
+    /// The size of one texel in UV coordinates, the smallest step that can change a sample.
+    /// Procedural textures have no texels, and use a small fixed step instead.
+    pub fn texel_size(&self) -> MatVec<2> {
+        match *self.source {
+            TextureSource::Image(_) | TextureSource::FloatImage(_) => MatVec::from_array([1.0 / self.width as f32, 1.0 / self.height as f32]),
+            _ => MatVec::from_array([1e-3, 1e-3]),
+        }
+    }
+
+    /// Samples the texture at the given UV coordinates, after transforming them and wrapping them into `[0, 1]`.
+    /// The stored image is assumed to be sRGB encoded, and the returned color is linear.
+    /// Float images and procedural colors are already linear, and are returned as is.
     pub fn sample(&self, uv_coord: MatVec<2>) -> Color {
-        let x = (uv_coord[0] * (self.width - 1) as f32) as u32;
-        let y = (uv_coord[1] * (self.height - 1) as f32) as u32;
-        let pixel = self.image.get_pixel(x, y);
+        utils::rgba_to_color(self._sample(uv_coord, true))
+    }
+
+    /// Samples the texture like `sample`, and also returns the alpha of the image, which is always linear.
+    /// Procedural textures are fully opaque.
+    pub fn sample_rgba(&self, uv_coord: MatVec<2>) -> RGBA {
+        self._sample(uv_coord, true)
+    }
+
+    /// Samples the texture like `sample`, but returns the stored values scaled to `[0, 1]` without
+    /// decoding sRGB, for images that hold data rather than colors, such as normal maps.
+    /// Float images return their stored values unscaled.
+    pub fn sample_data(&self, uv_coord: MatVec<2>) -> MatVec<3> {
+        utils::rgba_to_color(self._sample(uv_coord, false))
+    }
+
+    /// Samples the texture blurred to the given mip level, a fractional level blends the two nearest ones,
+    /// and each of them is interpolated bilinearly. Levels past the last one sample the last one.
+    /// Like `sample`, the color is linear. Textures without levels are sampled by `sample`.
+    pub fn sample_level(&self, uv_coord: MatVec<2>, level: f32) -> Color {
+        let levels: &Vec<Rgba32FImage> = match self.mipmaps {
+            Some(ref levels) => levels,
+            None => return self.sample(uv_coord),
+        };
+
+        let uv_coord: MatVec<2> = self.uv_transform.apply(uv_coord);
+        let level: f32 = level.clamp(0.0, (levels.len() - 1) as f32);
+        let lower: usize = level.floor() as usize;
+        let upper: usize = (lower + 1).min(levels.len() - 1);
+
+        let color: RGBA = self._bilinear(&levels[lower], uv_coord).lerp(&self._bilinear(&levels[upper], uv_coord), level - lower as f32);
+        utils::rgba_to_color(color)
+    }
+
+    /// Interpolates between the four texels around the UV coordinates, whose centers sit at half texel offsets.
+    /// Neighbours past the edge of the image are found through the wrap mode.
+    fn _bilinear(&self, image: &Rgba32FImage, uv_coord: MatVec<2>) -> RGBA {
+        let size: [u32; 2] = [image.width(), image.height()];
+        // Texel indices of the two neighbours along an axis, and the weight of the second one
+        let axis = |i: usize| {
+            let t: f32 = self.wrap_mode.apply(uv_coord[i]) * size[i] as f32 - 0.5;
+            let index = |t: f32| (self.wrap_mode.apply((t + 0.5) / size[i] as f32) * size[i] as f32).min(size[i] as f32 - 1.0) as u32;
+            (index(t.floor()), index(t.floor() + 1.0), t - t.floor())
+        };
+        let (x0, x1, fx) = axis(0);
+        let (y0, y1, fy) = axis(1);
+        let texel = |x: u32, y: u32| MatVec::from_array(image.get_pixel(x, y).0);
+        texel(x0, y0).lerp(&texel(x1, y0), fx).lerp(&texel(x0, y1).lerp(&texel(x1, y1), fx), fy)
+    }
+
+    fn _sample(&self, uv_coord: MatVec<2>, srgb: bool) -> RGBA {
+        let uv_coord: MatVec<2> = self.uv_transform.apply(uv_coord);
+        let uv_coord: MatVec<2> = MatVec::from_array([self.wrap_mode.apply(uv_coord[0]), self.wrap_mode.apply(uv_coord[1])]);
+
+        let x = (uv_coord[0] * (self.width.max(1) - 1) as f32) as u32;
+        let y = (uv_coord[1] * (self.height.max(1) - 1) as f32) as u32;
+
+        let image = match *self.source {
+            TextureSource::Image(ref image) => image,
+            TextureSource::FloatImage(ref image) => return MatVec::from_array(image.get_pixel(x, y).0),
+            TextureSource::Checker { ref scale, color_a, color_b } => {
+                let cell = (uv_coord[0] * scale[0]).floor() + (uv_coord[1] * scale[1]).floor();
+                let color: Color = if cell.rem_euclid(2.0) < 1.0 { color_a } else { color_b };
+                return utils::color_to_rgba(color, 1.0);
+            },
+            TextureSource::Gradient { ref color_a, ref color_b } => {
+                return utils::color_to_rgba(color_a.lerp(color_b, uv_coord[0]), 1.0);
+            },
+        };
+
+        let pixel = image.get_pixel(x, y);
         let channels = pixel.channels();
         
         // Convert sRGB to linear RGB; un gamma-correct
         let srgb_to_rgb = |c: u8| {
             let c = c as f32 / 255.0;
-            if c <= 0.04045 {
-                c / 12.92
-            } else {
-                ((c + 0.055) / 1.055).powf(2.4)
-            }
+            if srgb { utils::srgb_to_linear(c) } else { c }
         };
 
         let r = srgb_to_rgb(channels[0]);
         let g = srgb_to_rgb(channels[1]);
         let b = srgb_to_rgb(channels[2]);
+        let a = channels[3] as f32 / 255.0;
+
+        MatVec::from_array([r, g, b, a])
+    }
+}
+
+/// Where the colors of a serialized texture come from. Images are written as the file they were decoded from,
+/// not as their texels, and the file is decoded again when the texture is read back.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SerializedSource {
+    FILE(String),
+    CHECKER { scale: MatVec<2>, color_a: Color, color_b: Color },
+    GRADIENT { color_a: Color, color_b: Color },
+}
+
+/// How a `Texture2d` is written by serde, its source and the settings applied on top of it.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SerializedTexture {
+    source: SerializedSource,
+    wrap_mode: WrapMode,
+    uv_transform: UvTransform,
+    mipmaps: bool,
+}
+
+#[cfg(feature = "serde")]
+impl Texture2d {
+    /// Name of the texture in the table of a serialized scene, the file of an image or the kind of a procedural texture.
+    pub fn source_name(&self) -> String {
+        match *self.source {
+            TextureSource::Image(_) | TextureSource::FloatImage(_) => self.path.clone().unwrap_or(String::from("image")),
+            TextureSource::Checker { .. } => String::from("checker"),
+            TextureSource::Gradient { .. } => String::from("gradient"),
+        }
+    }
+}
 
-        MatVec::new(vec![r, g, b])
+#[cfg(feature = "serde")]
+impl serde::Serialize for Texture2d {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let source: SerializedSource = match *self.source {
+            TextureSource::Image(_) | TextureSource::FloatImage(_) => match self.path {
+                Some(ref path) => SerializedSource::FILE(path.clone()),
+                None => return Err(serde::ser::Error::custom("texture image has no file to refer to")),
+            },
+            TextureSource::Checker { scale, color_a, color_b } => SerializedSource::CHECKER { scale, color_a, color_b },
+            TextureSource::Gradient { color_a, color_b } => SerializedSource::GRADIENT { color_a, color_b },
+        };
+        SerializedTexture {
+            source,
+            wrap_mode: self.wrap_mode,
+            uv_transform: self.uv_transform,
+            mipmaps: self.mipmaps.is_some(),
+        }.serialize(serializer)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Texture2d {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Texture2d, D::Error> {
+        let serialized = SerializedTexture::deserialize(deserializer)?;
+        let texture: Texture2d = match serialized.source {
+            SerializedSource::FILE(path) => Texture2d::new(&path).map_err(serde::de::Error::custom)?,
+            SerializedSource::CHECKER { scale, color_a, color_b } => Texture2d::checker(scale, color_a, color_b),
+            SerializedSource::GRADIENT { color_a, color_b } => Texture2d::gradient(color_a, color_b),
+        };
+        let texture: Texture2d = texture.with_wrap_mode(serialized.wrap_mode).with_uv_transform(serialized.uv_transform);
+        Ok(if serialized.mipmaps { texture.with_mipmaps() } else { texture })
+    }
+}
+
+/// Reads a Portable FloatMap, either the color `PF` or the greyscale `Pf` variant, into linear colors.
+/// The header is the variant, the size and a scale, whose sign gives the byte order of the floats,
+/// negative for little endian. The magnitude of the scale is not applied. Rows are stored bottom to top.
+fn read_pfm(path: &str) -> Result<Rgba32FImage, ImageError> {
+    let bytes: Vec<u8> = std::fs::read(path).map_err(ImageError::IoError)?;
+    let malformed = |message: &str| ImageError::Decoding(DecodingError::new(ImageFormatHint::Name(String::from("PFM")), message.to_string()));
+
+    // Four whitespace separated tokens, followed by a single whitespace byte before the data
+    let mut tokens: Vec<String> = Vec::new();
+    let mut position: usize = 0;
+    while tokens.len() < 4 {
+        while position < bytes.len() && bytes[position].is_ascii_whitespace() {
+            position += 1;
+        }
+        let start: usize = position;
+        while position < bytes.len() && !bytes[position].is_ascii_whitespace() {
+            position += 1;
+        }
+        if start == position {
+            return Err(malformed("truncated header"));
+        }
+        tokens.push(String::from_utf8_lossy(&bytes[start..position]).into_owned());
+    }
+    position += 1;
+
+    let channels: usize = match tokens[0].as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(malformed("not a PFM file")),
+    };
+    let width: u32 = tokens[1].parse().map_err(|_| malformed("invalid width"))?;
+    let height: u32 = tokens[2].parse().map_err(|_| malformed("invalid height"))?;
+    let scale: f32 = tokens[3].parse().map_err(|_| malformed("invalid scale"))?;
+
+    let size: usize = width as usize * height as usize * channels * 4;
+    let data: &[u8] = bytes.get(position..position + size).ok_or_else(|| malformed("truncated data"))?;
+    let values: Vec<f32> = data.chunks_exact(4)
+        .map(|chunk| {
+            let chunk: [u8; 4] = [chunk[0], chunk[1], chunk[2], chunk[3]];
+            if scale < 0.0 { f32::from_le_bytes(chunk) } else { f32::from_be_bytes(chunk) }
+        })
+        .collect();
+
+    Ok(Rgba32FImage::from_fn(width, height, |x, y| {
+        let i: usize = ((height - 1 - y) as usize * width as usize + x as usize) * channels;
+        match channels {
+            3 => image::Rgba([values[i], values[i + 1], values[i + 2], 1.0]),
+            _ => image::Rgba([values[i], values[i], values[i], 1.0]),
+        }
+    }))
+}
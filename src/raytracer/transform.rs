@@ -0,0 +1,130 @@
+use crate::raytracer::{Intersection, MatVec, Matrix};
+use crate::raytracer::ray::Ray;
+
+/// Row major 4x4 matrix, used to represent affine transforms in homogeneous coordinates.
+pub type Matrix4 = Matrix<4, 4>;
+
+impl Matrix<4, 4> {
+
+    pub fn translation(offset: &MatVec<3>) -> Matrix4 {
+        Matrix4::new([[1.0, 0.0, 0.0, offset[0]],
+                      [0.0, 1.0, 0.0, offset[1]],
+                      [0.0, 0.0, 1.0, offset[2]],
+                      [0.0, 0.0, 0.0, 1.0]])
+    }
+
+    pub fn scaling(factors: &MatVec<3>) -> Matrix4 {
+        Matrix4::new([[factors[0], 0.0, 0.0, 0.0],
+                      [0.0, factors[1], 0.0, 0.0],
+                      [0.0, 0.0, factors[2], 0.0],
+                      [0.0, 0.0, 0.0, 1.0]])
+    }
+
+    /// Rotation of `angle` radians around `axis`, using Rodrigues' rotation formula.
+    pub fn rotation(axis: &MatVec<3>, angle: f32) -> Matrix4 {
+        let axis: MatVec<3> = axis.normalize();
+        let (x, y, z) = (axis[0], axis[1], axis[2]);
+        let (s, c) = angle.sin_cos();
+        let t: f32 = 1.0 - c;
+        Matrix4::new([[t * x * x + c,     t * x * y - s * z, t * x * z + s * y, 0.0],
+                      [t * x * y + s * z, t * y * y + c,     t * y * z - s * x, 0.0],
+                      [t * x * z - s * y, t * y * z + s * x, t * z * z + c,     0.0],
+                      [0.0,               0.0,               0.0,               1.0]])
+    }
+
+    /// Transforms a point, ie a homogeneous vector with `w = 1`.
+    pub fn transform_point(&self, point: &MatVec<3>) -> MatVec<3> {
+        self._transform(point, 1.0)
+    }
+
+    /// Transforms a direction, ie a homogeneous vector with `w = 0`, so translation is ignored.
+    pub fn transform_direction(&self, direction: &MatVec<3>) -> MatVec<3> {
+        self._transform(direction, 0.0)
+    }
+
+    fn _transform(&self, v: &MatVec<3>, w: f32) -> MatVec<3> {
+        let out: MatVec<4> = self.mul(&MatVec::from_array([v[0], v[1], v[2], w]));
+        MatVec::from_array([out[0], out[1], out[2]])
+    }
+
+}
+
+/// An affine object to world transform, stored together with its inverse.
+/// The inverse is accumulated alongside the matrix as each primitive transform is applied,
+/// so it never has to be computed from a general matrix inversion.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Transform {
+    pub matrix: Matrix4,
+    pub inverse: Matrix4,
+    identity: bool,
+}
+
+impl Transform {
+
+    pub fn new() -> Transform {
+        Transform {
+            matrix: Matrix4::identity(),
+            inverse: Matrix4::identity(),
+            identity: true,
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.identity
+    }
+
+    /// Appends a transform given as a matrix and its inverse.
+    /// The new transform is applied to objects before the existing ones.
+    fn _compose(&self, matrix: Matrix4, inverse: Matrix4) -> Transform {
+        Transform {
+            matrix: self.matrix.mul_matrix(&matrix),
+            inverse: inverse.mul_matrix(&self.inverse),
+            identity: false,
+        }
+    }
+
+    pub fn translate(&self, offset: &MatVec<3>) -> Transform {
+        self._compose(Matrix4::translation(offset), Matrix4::translation(&(-1.0f32 * *offset)))
+    }
+
+    /// Rotates by `angle` degrees around `axis`.
+    pub fn rotate(&self, axis: &MatVec<3>, angle: f32) -> Transform {
+        let radians: f32 = angle.to_radians();
+        self._compose(Matrix4::rotation(axis, radians), Matrix4::rotation(axis, -radians))
+    }
+
+    pub fn scale(&self, factors: &MatVec<3>) -> Transform {
+        let inverse_factors = MatVec::from_array([1.0 / factors[0], 1.0 / factors[1], 1.0 / factors[2]]);
+        self._compose(Matrix4::scaling(factors), Matrix4::scaling(&inverse_factors))
+    }
+
+    /// Maps a world space point into object space.
+    pub fn point_to_object(&self, point: &MatVec<3>) -> MatVec<3> {
+        self.inverse.transform_point(point)
+    }
+
+    /// Maps a world space ray into object space.
+    /// The direction is not renormalized, so parametric distances are preserved.
+    pub fn ray_to_object(&self, ray: &Ray) -> Ray {
+        Ray::new(self.inverse.transform_point(&ray.origin),
+                 self.inverse.transform_direction(&ray.direction)).at_time(ray.time)
+    }
+
+    /// Maps an object space intersection back into world space.
+    /// Normals are transformed by the inverse-transpose so they stay perpendicular to the surface,
+    /// and the distance is recomputed as the Euclidean distance along the world space ray, like untransformed
+    /// shapes report it, whatever the length of the ray direction.
+    pub fn intersection_to_world(&self, ray: &Ray, intersection: Intersection) -> Intersection {
+        let point: MatVec<3> = self.matrix.transform_point(&intersection.point);
+        let normal: MatVec<3> = self.inverse.transpose().transform_direction(&intersection.normal).normalize();
+        Intersection {
+            point,
+            normal,
+            // Signed, so interval entries behind the ray origin stay behind it
+            distance: (point - ray.origin).dot(ray.direction) / ray.direction.magnitude(),
+            ..intersection
+        }
+    }
+
+}
@@ -1,12 +1,12 @@
 use crate::raytracer::MatVec;
 use super::{Light, LightResidual, RGBA, Color};
 use rand_distr::{Distribution, Normal};
-use rand::thread_rng;
+use rand::Rng;
 
 
 // pub fn compute_total_light(ilumination_sources: Vec<LightResidual>) -> Light {
 //     todo!("compute_total_light, Not yet implemented");
-//     // let mut total_light: Light = MatVec::new(vec![0.0, 0.0, 0.0]);
+//     // let mut total_light: Light = MatVec::from_array([0.0, 0.0, 0.0]);
 //     // for light in ilumination_sources {
 //     //     total_light.color = total_light.color + light.color;
 //     //     total_light.intensity += light.intensity;
@@ -18,36 +18,47 @@ use rand::thread_rng;
 /// Uses the color of the object at the pixel that is being rendered, and all of the
 /// light sources in the scene, that do not have any objects between them and the
 /// intersection point.
-pub fn lambert(base_color: &Light, ilumination_sources: &Vec<LightResidual>) -> RGBA {
+pub fn lambert(base_color: &Light, ilumination_sources: &[LightResidual]) -> RGBA {
 
-    let mut total: Color = MatVec::new(vec![0.0, 0.0, 0.0]);
+    let mut total: Color = MatVec::zero();
 
     for source in ilumination_sources {
 
         let lambert: f32 = f32::max(source.normal.dot(source.direction.clone()), 0.0);
-        let mut temp: MatVec<3> = source.intensity * lambert * source.color.clone();
+        let temp: MatVec<3> = (source.intensity * lambert * source.color).hadamard(base_color);
 
-        for i in 0..3 {
-
-            temp.set(i, temp.get(i).clone() * base_color.get(i).clone());
-
-        }
-
-        total = total + temp.clone();
+        total = total + temp;
 
     }
 
     color_to_rgba(total, 1.0f32)
 }
 
-/// Rounds a value to the nearest multiple of a given precision.
-pub fn round_precision(value: f32, precision: f32) -> f32 {
-    let rounded_value = (value / precision).round() * precision;
-    if (value - rounded_value).abs() < precision {
-        rounded_value
-    } else {
-        value
+/// Computes the color of a pixel based on the Blinn-Phong model, ie the lambertian diffuse
+/// term plus a specular highlight where the half-vector between the light and the viewer
+/// aligns with the surface normal.
+/// `view` is the unit direction from the intersection point towards the viewer.
+/// Only residuals that come from actual light sources (those with a `source_id`) produce a highlight.
+pub fn phong(base_color: &Light, ilumination_sources: &[LightResidual], view: &MatVec<3>, specular_exponent: f32, specular_color: &Color) -> RGBA {
+
+    let diffuse: RGBA = lambert(base_color, ilumination_sources);
+    let mut total: Color = rgba_to_color(diffuse);
+
+    for source in ilumination_sources.iter().filter(|source| source.source_id.is_some()) {
+
+        if source.normal.dot(source.direction) <= 0.0 {
+            continue;
+        }
+
+        let half_vector: MatVec<3> = (source.direction + *view).normalize();
+        let specular: f32 = f32::max(source.normal.dot(half_vector), 0.0).powf(specular_exponent);
+        let temp: MatVec<3> = (source.intensity * specular * source.color).hadamard(specular_color);
+
+        total = total + temp;
+
     }
+
+    color_to_rgba(total, *diffuse.get(3))
 }
 
 // #[inline(always)]
@@ -62,15 +73,48 @@ pub fn gamma_correct(value: f32) -> f32 {
     if value <= 0.0031308 { 12.92 * value } else { 1.055 * value.powf(1.0 / 2.4) - 0.055 }
 }
 
+/// Decodes an sRGB encoded color component back to linear, the inverse of `gamma_correct`
+#[inline(always)]
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 { value / 12.92 } else { ((value + 0.055) / 1.055).powf(2.4) }
+}
+
+/// Schlick's approximation of the Fresnel reflectance of a dielectric surface,
+/// for light arriving at an angle with cosine `cos_theta` from the surface normal, from air.
+pub fn schlick(cos_theta: f32, ior: f32) -> f32 {
+    let r0: f32 = ((1.0 - ior) / (1.0 + ior)).powi(2);
+    r0 + (1.0 - r0) * (1.0 - cos_theta.clamp(0.0, 1.0)).powi(5)
+}
+
+/// Multiple importance sampling weight of a sample drawn with density `pdf`, against a second strategy
+/// that could have drawn it with density `other_pdf`, by the power heuristic with an exponent of 2.
+pub fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    if other_pdf <= 0.0 {
+        return 1.0;
+    }
+    let (a, b): (f32, f32) = (pdf * pdf, other_pdf * other_pdf);
+    a / (a + b)
+}
+
 /// Converts a linear color to sRGB color space
 #[allow(non_snake_case)]
 pub fn sRGB(color: &RGBA) -> MatVec<4> {
-    let mut to_return: Vec<f32> = Vec::new();
-    for i in 0..3 {
-        to_return.push(gamma_correct(color.get(i).clone()));
+    // Pass through the 4th element
+    MatVec::from_array(std::array::from_fn(|i| if i < 3 { gamma_correct(color[i]) } else { color[3] }))
+}
+
+/// Divides the color of a premultiplied color by its alpha, fully transparent colors are returned unchanged.
+pub fn unpremultiply(color: &RGBA) -> RGBA {
+    if color[3] > 0.0 {
+        color_to_rgba((1.0 / color[3]) * rgba_to_color(*color), color[3])
+    } else {
+        *color
     }
-    to_return.push(color.get(3).clone()); // Pass through the 4th element
-    MatVec::new(to_return)
+}
+
+/// Multiplies the color of a straight color by its alpha.
+pub fn premultiply(color: &RGBA) -> RGBA {
+    color_to_rgba(color[3] * rgba_to_color(*color), color[3])
 }
 
 /// This function just passes through the color and sets the alpha value
@@ -78,48 +122,115 @@ pub fn sRGB(color: &RGBA) -> MatVec<4> {
 /// This is essentially only used to convert Color (`MatVec<3>`) to RGBA (`MatVec<4>`)
 #[inline(always)]
 pub fn color_to_rgba(color: Color, alpha: f32) -> RGBA {
-    MatVec::<4>::new(vec![*color.get(0),
+    MatVec::<4>::from_array([*color.get(0),
                                   *color.get(1),
                                   *color.get(2), 
                                   alpha])
 }
 
+/// Unit direction to the right of a camera looking along `forward`, with `up` above it.
+/// Looking straight along `up` leaves no right, and any direction perpendicular to `forward` is used instead.
+pub fn camera_right(forward: &MatVec<3>, up: &MatVec<3>) -> MatVec<3> {
+    match forward.cross(up).try_normalize() {
+        Some(right) => right,
+        None => match forward.cross(&MatVec::from_array([1.0, 0.0, 0.0])).try_normalize() {
+            Some(right) => right,
+            None => forward.cross(&MatVec::from_array([0.0, 0.0, 1.0])).normalize(),
+        },
+    }
+}
+
+/// Relative luminance of a linear color, with the Rec. 709 weights.
+pub fn luminance(color: &Color) -> f32 {
+    0.2126 * color[0] + 0.7152 * color[1] + 0.0722 * color[2]
+}
+
+/// Maps `t` in `[0, 1]` to a heat ramp going from black through blue, red and yellow to white.
+pub fn heat_ramp(t: f32) -> Color {
+    let stops: [Color; 5] = [
+        MatVec::zero(),
+        MatVec::from_array([0.0, 0.0, 1.0]),
+        MatVec::from_array([1.0, 0.0, 0.0]),
+        MatVec::from_array([1.0, 1.0, 0.0]),
+        MatVec::one(),
+    ];
+    let position: f32 = t.clamp(0.0, 1.0) * (stops.len() - 1) as f32;
+    let i: usize = (position as usize).min(stops.len() - 2);
+    stops[i].lerp(&stops[i + 1], position - i as f32)
+}
+
 /// Applies an exponential exposure to a color, and passes through the alpha channel.
 pub fn appy_exposure(color: &RGBA, exposure: f32) -> RGBA {
 
-    let mut to_return: Vec<f32> = Vec::new();
+    let mut exposed: Color = rgba_to_color(*color);
 
     for i in 0..3 {
 
-        to_return.push(1.0 - ( -color.get(i).clone() * exposure).exp());
+        exposed.set(i, 1.0 - (-exposed[i] * exposure).exp());
 
     }
 
-    to_return.push(color.get(3).clone()); // Pass through the alpha channel
-    MatVec::new(to_return)
+    color_to_rgba(exposed.clamp(0.0, 1.0), color[3]) // Pass through the alpha channel
+
+}
+
+/// Applies the Reinhard tone mapping operator `c / (1 + c)` to a color,
+/// and passes through the alpha channel.
+pub fn apply_reinhard(color: &RGBA) -> RGBA {
+
+    // Pass through the alpha channel
+    MatVec::from_array(std::array::from_fn(|i| if i < 3 { color[i] / (1.0 + color[i]) } else { color[3] }))
+
+}
+
+/// Applies the ACES filmic tone mapping curve (Narkowicz's fit) to a color,
+/// and passes through the alpha channel.
+pub fn apply_aces(color: &RGBA) -> RGBA {
+
+    let (a, b, c, d, e) = (2.51_f32, 0.03_f32, 2.43_f32, 0.59_f32, 0.14_f32);
+    let curve = |x: f32| ((x * (a * x + b)) / (x * (c * x + d) + e)).clamp(0.0, 1.0);
+
+    // Pass through the alpha channel
+    MatVec::from_array(std::array::from_fn(|i| if i < 3 { curve(color[i]) } else { color[3] }))
 
 }
 
 /// Computes the UV texture coordinates of a point on a sphere.
-/// Given a point in 3D space (that belongs on the given sphere), and the center and radius of the sphere,
+/// Given a point in 3D space (that belongs on the given sphere), and the center of the sphere,
 /// computes the UV coordinates of the point in normalized texture coordinates.
 /// The point is first converted to a longitude and latitude, and then the UV coordinates are computed.
-pub fn spherical_world_to_uv(point: &MatVec<3>, center: &MatVec<3>, radius: f32) -> MatVec<2> {
+pub fn spherical_world_to_uv(point: &MatVec<3>, center: &MatVec<3>) -> MatVec<2> {
     let translated_point = point.clone() - center.clone();
     let phi: f32 = f32::atan2(*translated_point.get(2), *translated_point.get(0));
-    let theta: f32 = f32::acos(*translated_point.get(1) / radius);
+    // Polar angle from the north pole, which does not depend on the radius
+    let theta: f32 = translated_point.angle_between(&MatVec::from_array([0.0, 1.0, 0.0]));
 
     let u: f32 = 1.0 - (phi + std::f32::consts::PI) / (2.0 * std::f32::consts::PI);
     let v: f32 = theta / std::f32::consts::PI;
 
-    MatVec::new(vec![u, v])
+    MatVec::from_array([u, v])
 }
 
+/// Computes the UV coordinates of a direction in an equirectangular (latitude-longitude) image.
+/// Uses the same angles as the panoramic projection for the default camera, so an environment map
+/// rendered with a panoramic camera at the origin reproduces the original image.
+pub fn equirectangular_uv(direction: &MatVec<3>) -> MatVec<2> {
+    let direction: MatVec<3> = direction.normalize();
+    let theta: f32 = f32::atan2(direction[0], -direction[2]);
+    let phi: f32 = direction[1].clamp(-1.0, 1.0).asin();
 
-/// Cmputes the UV texture coordinates of a point in a triangle.
-/// Given a point in 3D space (that belongs on the given triangle), and the texcoords of the triangle,
-/// computes the UV coordinates of the point in normalized texture coordinates.
-pub fn barycentric_uv(point: &MatVec<3>, verticies: Vec<MatVec<3>>, texcoords: Vec<MatVec<2>>) -> MatVec<2> {
+    let u: f32 = (theta + std::f32::consts::PI) / (2.0 * std::f32::consts::PI);
+    let v: f32 = (std::f32::consts::PI / 2.0 - phi) / std::f32::consts::PI;
+
+    MatVec::from_array([u, v])
+}
+
+/// Computes the barycentric weights of a point in a triangle, ie the weights of the three vertices
+/// that reproduce the point, for interpolating per vertex attributes.
+/// The weights are computed from the 3D point itself rather than from screen space,
+/// so the interpolation is already perspective-correct.
+/// Returns `None` for degenerate triangles, whose barycentric coordinates are undefined.
+pub fn barycentric_weights(point: &MatVec<3>, verticies: &[MatVec<3>]) -> Option<MatVec<3>> {
     let v0: MatVec<3> = verticies[1].clone() - verticies[0].clone();
     let v1: MatVec<3>  = verticies[2].clone() - verticies[0].clone();
     let v2: MatVec<3>  = point.clone() - verticies[0].clone();
@@ -130,22 +241,143 @@ pub fn barycentric_uv(point: &MatVec<3>, verticies: Vec<MatVec<3>>, texcoords: V
     let d20 = v2.dot(v0.clone());
     let d21 = v2.dot(v1.clone());
     
+    // The denominator is |v0|^2 |v1|^2 sin^2 of the angle between the edges, so compare it
+    // relative to the edge lengths, thin triangles lose all precision long before it reaches 0
     let denom = d00 * d11 - d01 * d01;
+    if denom <= f32::EPSILON * d00 * d11 {
+        return None;
+    }
+
     let v = (d11 * d20 - d01 * d21) / denom;
     let w = (d00 * d21 - d01 * d20) / denom;
     let u = 1.0 - v - w;
+
+    Some(MatVec::from_array([u, v, w]))
+}
+
+/// Point on the segment from `a` to `b` that is closest to `point`.
+pub fn closest_point_on_segment(point: &MatVec<3>, a: &MatVec<3>, b: &MatVec<3>) -> MatVec<3> {
+    let edge: MatVec<3> = *b - *a;
+    let length2: f32 = edge.dot(edge);
+    if length2 <= 0.0 {
+        return *a;
+    }
+    *a + ((*point - *a).dot(edge) / length2).clamp(0.0, 1.0) * edge
+}
+
+/// Cmputes the UV texture coordinates of a point in a triangle.
+/// Given a point in 3D space (that belongs on the given triangle), and the texcoords of the triangle,
+/// computes the UV coordinates of the point in normalized texture coordinates.
+/// Returns `None` for degenerate triangles, see `barycentric_weights`.
+pub fn barycentric_uv(point: &MatVec<3>, verticies: Vec<MatVec<3>>, texcoords: Vec<MatVec<2>>) -> Option<MatVec<2>> {
+    let weights: MatVec<3> = barycentric_weights(point, &verticies)?;
     
-    let uv = texcoords[0].clone() * u + texcoords[1].clone() * v + texcoords[2].clone() * w;
-    MatVec::new(vec![uv.get(0).clone(), uv.get(1).clone()])
+    let uv = texcoords[0] * weights[0] + texcoords[1] * weights[1] + texcoords[2] * weights[2];
+    Some(MatVec::from_array([uv[0], uv[1]]))
 }
 
 
-pub fn gaussian_sample(std_dev: f32) -> f32 {
+/// Maps a point in the unit square to the unit disk using Shirley's concentric mapping,
+/// which preserves the uniformity of the samples without the clustering of a polar mapping.
+pub fn concentric_disk_sample(u: f32, v: f32) -> MatVec<2> {
+    let a: f32 = 2.0 * u - 1.0;
+    let b: f32 = 2.0 * v - 1.0;
+
+    if a == 0.0 && b == 0.0 {
+        return MatVec::from_array([0.0, 0.0]);
+    }
+
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, std::f32::consts::FRAC_PI_4 * (b / a))
+    } else {
+        (b, std::f32::consts::FRAC_PI_2 - std::f32::consts::FRAC_PI_4 * (a / b))
+    };
+
+    MatVec::from_array([r * theta.cos(), r * theta.sin()])
+}
+
+pub fn gaussian_sample<R: Rng + ?Sized>(std_dev: f32, rng: &mut R) -> f32 {
     let normal = Normal::new(0.0, std_dev).unwrap();
-    let mut rng = thread_rng();
-    normal.sample(&mut rng)
+    normal.sample(rng)
 }
 
 pub fn rgba_to_color(rgba: RGBA) -> Color {
-    MatVec::new(vec![*rgba.get(0), *rgba.get(1), *rgba.get(2)])
-}
\ No newline at end of file
+    MatVec::from_array([*rgba.get(0), *rgba.get(1), *rgba.get(2)])
+}
+
+/// Finds the real roots of the quartic `a t^4 + b t^3 + c t^2 + d t + e` within `[min, max]`, in ascending order.
+/// The roots of the derivative split the range into intervals on which the quartic is monotonic,
+/// so each interval holds at most one root, which is then found by bisection.
+/// This is slower than the closed form but does not lose roots to cancellation when two of them nearly coincide.
+pub fn solve_quartic(a: f64, b: f64, c: f64, d: f64, e: f64, min: f64, max: f64) -> Vec<f64> {
+    polynomial_roots(&[a, b, c, d, e], min, max)
+}
+
+/// Real roots of the polynomial with the given coefficients (highest degree first) within `[min, max]`, in ascending order.
+fn polynomial_roots(coeffs: &[f64], min: f64, max: f64) -> Vec<f64> {
+
+    // Drop vanishing leading coefficients so the degree is correct
+    let coeffs: &[f64] = match coeffs.iter().position(|c| c.abs() > 1e-12) {
+        Some(first) => &coeffs[first..],
+        None => return Vec::new(),
+    };
+
+    let degree: usize = coeffs.len() - 1;
+
+    if degree == 0 {
+        return Vec::new();
+    }
+
+    if degree == 1 {
+        let root: f64 = -coeffs[1] / coeffs[0];
+        return if (min..=max).contains(&root) { vec![root] } else { Vec::new() };
+    }
+
+    let derivative: Vec<f64> = coeffs[..degree].iter()
+        .enumerate()
+        .map(|(i, c)| c * (degree - i) as f64)
+        .collect();
+
+    let mut bounds: Vec<f64> = vec![min];
+    bounds.extend(polynomial_roots(&derivative, min, max));
+    bounds.push(max);
+
+    let eval = |t: f64| coeffs.iter().fold(0.0, |acc, c| acc * t + c);
+
+    let mut roots: Vec<f64> = Vec::new();
+
+    for window in bounds.windows(2) {
+
+        let (mut lo, mut hi) = (window[0], window[1]);
+        let (f_lo, f_hi) = (eval(lo), eval(hi));
+
+        if f_lo == 0.0 {
+            roots.push(lo);
+            continue;
+        }
+
+        if f_lo.signum() == f_hi.signum() {
+            continue;
+        }
+
+        for _ in 0..64 {
+            let mid: f64 = 0.5 * (lo + hi);
+            if eval(mid).signum() == f_lo.signum() {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+
+        roots.push(0.5 * (lo + hi));
+
+    }
+
+    if eval(max) == 0.0 {
+        roots.push(max);
+    }
+
+    roots.dedup();
+    roots
+
+}
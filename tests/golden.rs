@@ -0,0 +1,41 @@
+use raytracer::raytracer::raytracer::RayTracer;
+
+/// Largest difference allowed in any channel of any pixel, for differences in float rounding between platforms.
+const TOLERANCE: u8 = 2;
+
+/// Renders a scene under `tests/golden` and compares it to the reference image next to it, which is the image
+/// the scene file names in its header. A change that alters an image on purpose updates the reference by
+/// rendering the scene with the binary from within `tests/golden`.
+fn assert_matches_reference(name: &str) {
+    let directory: String = format!("{}/tests/golden", env!("CARGO_MANIFEST_DIR"));
+    let (mut raytracer, _) = RayTracer::from_file(&format!("{}/{}.txt", directory, name));
+    let reference = image::open(format!("{}/{}.png", directory, name)).unwrap().to_rgba8();
+    let (image, _) = raytracer.render_to_buffer();
+
+    assert_eq!(image.dimensions(), reference.dimensions(), "{}: size differs from the reference", name);
+    for (x, y, pixel) in image.enumerate_pixels() {
+        let expected = reference.get_pixel(x, y);
+        let within: bool = pixel.0.iter().zip(expected.0.iter()).all(|(a, b)| a.abs_diff(*b) <= TOLERANCE);
+        assert!(within, "{}: pixel ({}, {}) is {:?}, the reference has {:?}", name, x, y, pixel.0, expected.0);
+    }
+}
+
+#[test]
+fn sphere() {
+    assert_matches_reference("sphere");
+}
+
+#[test]
+fn lit_plane() {
+    assert_matches_reference("plane");
+}
+
+#[test]
+fn textured_triangle() {
+    assert_matches_reference("triangle");
+}
+
+#[test]
+fn reflective_pair() {
+    assert_matches_reference("reflective");
+}
@@ -0,0 +1,107 @@
+use raytracer::raytracer::raytracer::RayTracer;
+
+/// Parses a scene under `tests/scenes` through the library, the way an embedding program would.
+fn load(name: &str) -> RayTracer {
+    let path: String = format!("{}/tests/scenes/{}.txt", env!("CARGO_MANIFEST_DIR"), name);
+    RayTracer::from_file(&path).0
+}
+
+#[test]
+fn library_path_culls_shapes_behind_the_camera() {
+    let mut raytracer = load("culled");
+    let (_, stats) = raytracer.render_to_buffer();
+    // Every camera ray is only tested against the sphere in view
+    assert_eq!(stats.intersection_tests, stats.camera_rays);
+}
+
+#[test]
+fn nearly_touching_spheres_do_not_leak_light() {
+    let mut raytracer = load("touching");
+    let (image, _) = raytracer.render_to_buffer();
+    // Shadow rays from under the tiny sphere used to start past it, with a bias larger than the sphere
+    assert_eq!(image.get_pixel(12, 14).0, [0, 0, 0, 255]);
+    assert!(image.get_pixel(12, 3).0[0] > 200);
+}
+
+#[test]
+fn glass_reflects_more_at_grazing_angles() {
+    let mut raytracer = load("fresnel");
+    let (image, _) = raytracer.render_to_buffer();
+    let grazing: u8 = image.get_pixel(1, 8).0[0];
+    let normal: u8 = image.get_pixel(1, 31).0[0];
+    // Glass of ior 1.5 reflects 4% head on, which is 56 in sRGB
+    assert!((50..=62).contains(&normal), "near-normal reflectance {}", normal);
+    assert!(grazing > 2 * normal, "grazing reflectance {} is not above near-normal {}", grazing, normal);
+}
+
+#[test]
+fn bulb_lights_objects_on_either_side() {
+    let mut raytracer = load("bulb");
+    let (image, _) = raytracer.render_to_buffer();
+    // The sides facing the bulb are lit, rather than shadowed by the sphere behind it
+    assert!(image.get_pixel(12, 8).0[0] > 100);
+    assert!(image.get_pixel(20, 8).0[0] > 100);
+}
+
+#[test]
+fn checker_is_not_skewed_across_the_diagonal_of_a_quad() {
+    let mut raytracer = load("quad");
+    let (image, _) = raytracer.render_to_buffer();
+    // Every column changes cell at the middle of the floor, whichever of the two triangles it is in
+    for x in 0..32 {
+        let far = image.get_pixel(x, 8).0;
+        let near = image.get_pixel(x, 31).0;
+        assert_ne!(far, near, "column {} does not change cell", x);
+        for y in 8..32 {
+            let expected = if y < 15 { far } else { near };
+            assert_eq!(image.get_pixel(x, y).0, expected, "pixel ({}, {})", x, y);
+        }
+    }
+}
+
+#[test]
+fn wide_image_is_not_transposed() {
+    let mut raytracer = load("wide");
+    let (image, _) = raytracer.render_to_buffer();
+    assert_eq!(image.dimensions(), (40, 10));
+    // The sphere is at the right end of the middle row, and the left end is empty
+    assert!(image.get_pixel(37, 5).0[0] > 200);
+    assert_eq!(image.get_pixel(2, 5).0[3], 0);
+}
+
+#[test]
+fn panorama_is_continuous_across_the_seam() {
+    let mut raytracer = load("panorama");
+    let (image, _) = raytracer.render_to_buffer();
+    let red = |x: u32, y: u32| image.get_pixel(x, y).0[0] as i32;
+    // The sphere behind the camera is split between the two ends, and shades smoothly from one end into the other
+    for y in 12..20 {
+        let seam: i32 = (red(0, y) - red(63, y)).abs();
+        let neighbours: i32 = (red(63, y) - red(62, y)).abs().max((red(1, y) - red(0, y)).abs());
+        assert!(red(0, y) > 100 && red(63, y) > 100, "row {} misses the sphere", y);
+        assert!(seam <= neighbours + 2, "row {} jumps by {} across the seam, and by {} beside it", y, seam, neighbours);
+    }
+}
+
+#[test]
+fn coincident_planes_resolve_to_the_first_one() {
+    let mut raytracer = load("coincident");
+    let (image, _) = raytracer.render_to_buffer();
+    // Without the tolerance, the green plane is nearer by rounding for about three rays in four
+    for (x, y, pixel) in image.enumerate_pixels() {
+        let [red, green, _, alpha] = pixel.0;
+        assert!(alpha == 0 || (red > 0 && green == 0), "pixel ({}, {}) is {:?}", x, y, pixel.0);
+    }
+}
+
+#[test]
+fn degenerate_triangles_leave_no_marks() {
+    let mut raytracer = load("degenerate");
+    let (image, _) = raytracer.render_to_buffer();
+    // Triangles with no area have no normal, and used to shade the pixels along them with NaNs
+    let wall = image.get_pixel(0, 0).0;
+    assert!(wall[0] > 0);
+    for (x, y, pixel) in image.enumerate_pixels() {
+        assert_eq!(pixel.0, wall, "pixel ({}, {})", x, y);
+    }
+}
@@ -0,0 +1,28 @@
+#![cfg(feature = "serde")]
+
+use raytracer::raytracer::raytracer::RayTracer;
+
+fn load(name: &str) -> RayTracer {
+    let path: String = format!("{}/tests/scenes/{}.txt", env!("CARGO_MANIFEST_DIR"), name);
+    RayTracer::from_file(&path).0
+}
+
+fn to_json(raytracer: &RayTracer) -> String {
+    let mut buffer: Vec<u8> = Vec::new();
+    raytracer.serialize_scene(&mut serde_json::Serializer::new(&mut buffer)).unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+#[test]
+fn shared_meshes_and_textures_are_written_once() {
+    let mut raytracer = load("instances");
+    let json: String = to_json(&raytracer);
+    assert_eq!(json.matches("\"verticies\"").count(), 3, "the mesh is written once, not per instance");
+    assert_eq!(json.matches("\"source\":{\"checker\"").count(), 1, "the texture is written once, not per triangle");
+    assert_eq!(json.matches("\"mesh\":\"pyramid\"").count(), 3);
+
+    let expected = raytracer.render_to_buffer().0.clone();
+    raytracer.deserialize_scene(&mut serde_json::Deserializer::from_str(&json)).unwrap();
+    assert_eq!(to_json(&raytracer), json);
+    assert!(*raytracer.render_to_buffer().0 == expected);
+}